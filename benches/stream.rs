@@ -0,0 +1,109 @@
+use bytes::{Bytes, BytesMut};
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use futures::{Stream, StreamExt};
+use k3::{
+    CommandResponse, CompressionAlgorithm, FrameCodec, FrameConfig, KvError, ProstStream, Value,
+    read_frame,
+};
+use std::sync::Arc;
+use tokio::{
+    io::{AsyncRead, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+const FRAMES: usize = 10_000;
+const VALUE_SIZE: usize = 64;
+
+// FRAMES 个不压缩的小帧，每个帧里是一个 VALUE_SIZE 字节的 binary
+fn frames() -> Bytes {
+    let config = FrameConfig {
+        compression: CompressionAlgorithm::None,
+        ..Default::default()
+    };
+    let resp: CommandResponse = Value::from(Bytes::from(vec![42u8; VALUE_SIZE])).into();
+    let mut buf = BytesMut::new();
+    for _ in 0..FRAMES {
+        resp.encode_frame_with(&mut buf, &config).unwrap();
+    }
+    buf.freeze()
+}
+
+// 对端一次把所有帧写进 TCP 连接
+async fn connect(listener: &TcpListener, data: Bytes) -> TcpStream {
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+    });
+    listener.accept().await.unwrap().0
+}
+
+// 改之前的 ProstStream 的读路径：每个帧新建一个 read_frame 的 future，先读帧头再读 payload，
+// 整个帧读进 rbuf 之后再解码。原来的 poll_next 每次 poll 都重新建 future，
+// 读到一半返回 Pending 时会丢掉已经读到的数据，这里让 future 跨 poll 保留，读的方式不变
+fn legacy_stream<S>(stream: S) -> impl Stream<Item = Result<CommandResponse, KvError>>
+where
+    S: AsyncRead + Unpin + Send,
+{
+    let config = FrameConfig::default();
+    futures::stream::unfold(
+        (stream, BytesMut::new()),
+        move |(mut stream, mut rbuf)| async move {
+            let fut = Box::pin(read_frame(&mut stream, &mut rbuf, config.max_frame));
+            let res = match fut.await {
+                Ok(()) => CommandResponse::decode_frame_with(&mut rbuf, &config),
+                Err(e) => Err(e),
+            };
+            Some((res, (stream, rbuf)))
+        },
+    )
+}
+
+fn read_path(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let listener = Arc::new(rt.block_on(TcpListener::bind("127.0.0.1:0")).unwrap());
+    let data = frames();
+    let mut group = c.benchmark_group("read");
+    group.throughput(Throughput::Bytes(data.len() as _));
+
+    // 改之前的 ProstStream::poll_next，用来对比
+    group.bench_function("legacy_prost_stream", |b| {
+        b.to_async(&rt).iter(|| async {
+            let stream = connect(&listener, data.clone()).await;
+            let mut stream = Box::pin(legacy_stream(stream));
+            for _ in 0..FRAMES {
+                let resp = stream.next().await.unwrap().unwrap();
+                assert_eq!(resp.values.len(), 1);
+            }
+        })
+    });
+
+    // 每个帧先读帧头再读 payload，至少两次读 socket
+    group.bench_function("read_frame", |b| {
+        b.to_async(&rt).iter(|| async {
+            let mut stream = connect(&listener, data.clone()).await;
+            let mut buf = BytesMut::new();
+            for _ in 0..FRAMES {
+                read_frame(&mut stream, &mut buf, usize::MAX).await.unwrap();
+                let resp = CommandResponse::decode_frame(&mut buf.split()).unwrap();
+                assert_eq!(resp.values.len(), 1);
+            }
+        })
+    });
+
+    // 一次读出尽量多的数据，里面的每个帧都不用再读 socket
+    group.bench_function("prost_stream", |b| {
+        b.to_async(&rt).iter(|| async {
+            let stream = connect(&listener, data.clone()).await;
+            let mut stream = ProstStream::<_, CommandResponse, CommandResponse>::new(stream);
+            for _ in 0..FRAMES {
+                let resp = stream.next().await.unwrap().unwrap();
+                assert_eq!(resp.values.len(), 1);
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, read_path);
+criterion_main!(benches);
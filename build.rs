@@ -5,7 +5,12 @@ fn main() {
     // 定制生成 .rs 文件 => 给 struct 自动 derive traits
     let mut config = prost_build::Config::new();
     // config.type_attribute(".", "#[derive(serde::Serialize, serde::Deserialize)]");
-    config.type_attribute(".", "#[derive(PartialOrd)]");
+    // 只给需要排序的 message 加 PartialOrd，proto 里的 enum 自己已经 derive 过了
+    for path in [".abi.Kvpair", ".abi.Value"] {
+        config.type_attribute(path, "#[derive(PartialOrd)]");
+    }
+    // 解码时 binary 直接引用收到的 buffer，不再拷贝一份
+    config.bytes([".abi.Value"]);
     config
         .compile_protos(&["src/cmd/abi.proto"], &["src"])
         .unwrap();
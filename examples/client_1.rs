@@ -9,7 +9,7 @@ async fn main() -> Result<()> {
     let stream = TcpStream::connect(addr).await?;
     let framed = Framed::new(stream, LinesCodec::new());
     let (mut tx, mut rx) = framed.split::<String>();
-    let _ = tx.send("hello".into()).await?;
+    tx.send("hello".into()).await?;
     let resp = rx.next().await.unwrap();
     println!("resp: {:?}", resp);
     Ok(())
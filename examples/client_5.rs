@@ -1,7 +1,5 @@
 use anyhow::Result;
-use async_prost::AsyncProstStream;
-use futures::{SinkExt, StreamExt};
-use k3::{ClientStream, CommandRequest, CommandResponse};
+use k3::{ClientStream, CommandRequest};
 use tokio::net::TcpStream;
 
 // client stream => customized stream exec
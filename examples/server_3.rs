@@ -1,7 +1,7 @@
 use anyhow::Result;
 use async_prost::AsyncProstStream;
 use futures::{SinkExt, StreamExt};
-use k3::{CommandRequest, CommandResponse, MemTable, Service};
+use k3::{CommandRequest, CommandResponse, MemTable, RequestContext, Service};
 use tokio::net::TcpListener;
 
 // prost dummy server => prost server => add a service
@@ -20,9 +20,11 @@ async fn main() -> Result<()> {
             while let Some(Ok(cmd)) = stream.next().await {
                 println!("cmd: {:?}", cmd);
                 // impl service
-                let resp = svc_cl.process_request(cmd);
-                println!("resp: {:?}", resp);
-                stream.send(resp).await.unwrap();
+                let mut resps = svc_cl.process_request(cmd, &RequestContext::default());
+                while let Some(resp) = resps.next().await {
+                    println!("resp: {:?}", resp);
+                    stream.send((*resp).clone()).await.unwrap();
+                }
             }
         });
     }
@@ -1,7 +1,7 @@
 use anyhow::Result;
 use async_prost::AsyncProstStream;
 use futures::{SinkExt, StreamExt};
-use k3::{CommandRequest, CommandResponse, MemTable, service::ServiceInner};
+use k3::{CommandRequest, CommandResponse, MemTable, RequestContext, service::ServiceInner};
 use tokio::net::TcpListener;
 
 // service => service_builder + chain operation + hooks
@@ -11,7 +11,10 @@ async fn main() -> Result<()> {
     let listener = TcpListener::bind(addr).await?;
     let store = MemTable::new();
     let svc_builder = ServiceInner::new(store)
-        .add_req_hook(|cmd_req: &CommandRequest| println!("hook 1 - request: {:?}", cmd_req))
+        .add_req_hook(|cmd_req: &CommandRequest, _: &RequestContext| {
+            println!("hook 1 - request: {:?}", cmd_req);
+            Ok(())
+        })
         .add_resp_hook(|resp: &mut CommandResponse| println!("hook 2 - resp: {:?}", resp));
     let service = svc_builder.build();
     loop {
@@ -23,9 +26,11 @@ async fn main() -> Result<()> {
             while let Some(Ok(cmd)) = stream.next().await {
                 println!("cmd: {:?}", cmd);
                 // impl service
-                let resp = svc_cl.process_request(cmd);
-                println!("resp: {:?}", resp);
-                stream.send(resp).await.unwrap();
+                let mut resps = svc_cl.process_request(cmd, &RequestContext::default());
+                while let Some(resp) = resps.next().await {
+                    println!("resp: {:?}", resp);
+                    stream.send((*resp).clone()).await.unwrap();
+                }
             }
         });
     }
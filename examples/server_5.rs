@@ -1,5 +1,7 @@
 use anyhow::Result;
-use k3::{CommandRequest, CommandResponse, MemTable, ServerStream, service::ServiceInner};
+use k3::{
+    CommandRequest, CommandResponse, MemTable, RequestContext, ServerStream, service::ServiceInner,
+};
 use tokio::net::TcpListener;
 
 // third party stream => customized stream
@@ -9,7 +11,10 @@ async fn main() -> Result<()> {
     let listener = TcpListener::bind(addr).await?;
     let store = MemTable::new();
     let svc_builder = ServiceInner::new(store)
-        .add_req_hook(|cmd_req: &CommandRequest| println!("hook 1 - request: {:?}", cmd_req))
+        .add_req_hook(|cmd_req: &CommandRequest, _: &RequestContext| {
+            println!("hook 1 - request: {:?}", cmd_req);
+            Ok(())
+        })
         .add_resp_hook(|resp: &mut CommandResponse| println!("hook 2 - resp: {:?}", resp));
     let service = svc_builder.build();
     loop {
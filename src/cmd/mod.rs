@@ -18,6 +18,7 @@ impl CommandRequest {
                 table: table_name.into(),
                 key: key.into(),
             })),
+            ..Default::default()
         }
     }
     pub fn new_hset(table_name: impl Into<String>, key: impl Into<String>, value: Value) -> Self {
@@ -25,7 +26,27 @@ impl CommandRequest {
             request_data: Some(RequestData::Hset(Hset {
                 table: table_name.into(),
                 pair: Some(Kvpair::new(key, value)),
+                ttl_ms: 0,
+                ..Default::default()
             })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hset_with_ttl(
+        table_name: impl Into<String>,
+        key: impl Into<String>,
+        value: Value,
+        ttl_ms: u64,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hset(Hset {
+                table: table_name.into(),
+                pair: Some(Kvpair::new(key, value)),
+                ttl_ms,
+                ..Default::default()
+            })),
+            ..Default::default()
         }
     }
 
@@ -35,6 +56,290 @@ impl CommandRequest {
                 table: table_name.into(),
                 key: key.into(),
             })),
+            ..Default::default()
+        }
+    }
+
+    /// 满足 condition 时才写入的 hset，condition 为 IfEqual 时和 expected 比较
+    pub fn new_hset_if(
+        table_name: impl Into<String>,
+        key: impl Into<String>,
+        value: Value,
+        condition: SetCondition,
+        expected: Option<Value>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hset(Hset {
+                table: table_name.into(),
+                pair: Some(Kvpair::new(key, value)),
+                ttl_ms: 0,
+                condition: condition as i32,
+                expected,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hexpire(table_name: impl Into<String>, key: impl Into<String>, ttl_ms: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hexpire(Hexpire {
+                table: table_name.into(),
+                key: key.into(),
+                ttl_ms,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_httl(table_name: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Httl(Httl {
+                table: table_name.into(),
+                key: key.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hpersist(table_name: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hpersist(Hpersist {
+                table: table_name.into(),
+                key: key.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hincrby(table_name: impl Into<String>, key: impl Into<String>, delta: i64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hincrby(Hincrby {
+                table: table_name.into(),
+                key: key.into(),
+                delta,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hincrbyfloat(
+        table_name: impl Into<String>,
+        key: impl Into<String>,
+        delta: f64,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hincrbyfloat(Hincrbyfloat {
+                table: table_name.into(),
+                key: key.into(),
+                delta,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_txn(
+        compares: Vec<Compare>,
+        success: Vec<CommandRequest>,
+        failure: Vec<CommandRequest>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Txn(Txn {
+                compares,
+                success,
+                failure,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_subscribe(topic: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Subscribe(Subscribe {
+                topic: topic.into(),
+                options: None,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_subscribe_with_options(topic: impl Into<String>, options: SubscribeOptions) -> Self {
+        Self {
+            request_data: Some(RequestData::Subscribe(Subscribe {
+                topic: topic.into(),
+                options: Some(options),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_unsubscribe(topic: impl Into<String>, id: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Unsubscribe(Unsubscribe {
+                topic: topic.into(),
+                id,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_publish(topic: impl Into<String>, data: Vec<Value>) -> Self {
+        Self {
+            request_data: Some(RequestData::Publish(Publish {
+                topic: topic.into(),
+                data,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_psubscribe(pattern: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Psubscribe(Psubscribe {
+                pattern: pattern.into(),
+                options: None,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_punsubscribe(pattern: impl Into<String>, id: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Punsubscribe(Punsubscribe {
+                pattern: pattern.into(),
+                id,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_watch(table_name: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Watch(Watch {
+                table: table_name.into(),
+                key: key.into(),
+                options: None,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_unwatch(table_name: impl Into<String>, key: impl Into<String>, id: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Unwatch(Unwatch {
+                table: table_name.into(),
+                key: key.into(),
+                id,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_topics() -> Self {
+        Self {
+            request_data: Some(RequestData::Topics(Topics {})),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_ping() -> Self {
+        Self {
+            request_data: Some(RequestData::Ping(Ping {})),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hello(hello: Hello) -> Self {
+        Self {
+            request_data: Some(RequestData::Hello(hello)),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hgetall(table_name: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hgetall(Hgetall {
+                table: table_name.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hscan(
+        table_name: impl Into<String>,
+        prefix: impl Into<String>,
+        cursor: impl Into<String>,
+        limit: u32,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hscan(Hscan {
+                table: table_name.into(),
+                prefix: prefix.into(),
+                cursor: cursor.into(),
+                limit,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hmget(table_name: impl Into<String>, keys: Vec<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmget(Hmget {
+                table: table_name.into(),
+                keys,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hmset(table_name: impl Into<String>, pairs: Vec<Kvpair>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmset(Hmset {
+                table: table_name.into(),
+                pairs,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hmdel(table_name: impl Into<String>, keys: Vec<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmdel(Hmdel {
+                table: table_name.into(),
+                keys,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hmexist(table_name: impl Into<String>, keys: Vec<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmexist(Hmexist {
+                table: table_name.into(),
+                keys,
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 重复执行多次和执行一次的效果相同，连接断开之后可以安全地重试
+    /// incr、带条件的 hset、txn、publish 和订阅相关的命令都不是幂等的
+    pub fn is_idempotent(&self) -> bool {
+        match &self.request_data {
+            Some(RequestData::Hset(param)) => param.condition() == SetCondition::Always,
+            Some(RequestData::Hget(_))
+            | Some(RequestData::Hmget(_))
+            | Some(RequestData::Hgetall(_))
+            | Some(RequestData::Hscan(_))
+            | Some(RequestData::Hmexist(_))
+            | Some(RequestData::Httl(_))
+            | Some(RequestData::Hdel(_))
+            | Some(RequestData::Hmset(_))
+            | Some(RequestData::Hmdel(_))
+            | Some(RequestData::Hexpire(_))
+            | Some(RequestData::Hpersist(_))
+            | Some(RequestData::Topics(_))
+            | Some(RequestData::Ping(_)) => true,
+            _ => false,
         }
     }
 }
@@ -47,6 +352,15 @@ impl CommandResponse {
         }
     }
 
+    /// 订阅结束的标记
+    pub fn stream_end() -> Self {
+        CommandResponse {
+            status: StatusCode::OK.as_u16() as _,
+            stream_end: true,
+            ..Default::default()
+        }
+    }
+
     pub fn internal_error(msg: String) -> Self {
         CommandResponse {
             status: StatusCode::INTERNAL_SERVER_ERROR.as_u16() as _,
@@ -77,6 +391,51 @@ impl Kvpair {
     }
 }
 
+impl SubscribeOptions {
+    pub fn new(policy: OverflowPolicy, capacity: u32, block_timeout_ms: u64) -> Self {
+        Self {
+            policy: policy as i32,
+            capacity,
+            block_timeout_ms,
+            ..Default::default()
+        }
+    }
+
+    /// 从 durable topic 的 offset 开始 replay
+    pub fn from_offset(mut self, offset: u64) -> Self {
+        self.from_offset = offset;
+        self
+    }
+
+    /// 从 durable topic 里某个时间点之后的消息开始 replay
+    pub fn from_timestamp_ms(mut self, timestamp_ms: u64) -> Self {
+        self.from_timestamp_ms = timestamp_ms;
+        self
+    }
+}
+
+impl Compare {
+    pub fn new(
+        table_name: impl Into<String>,
+        key: impl Into<String>,
+        op: CompareOp,
+        value: Option<Value>,
+    ) -> Self {
+        Self {
+            table: table_name.into(),
+            key: key.into(),
+            op: op as i32,
+            value,
+        }
+    }
+}
+
+impl From<(String, Value)> for Kvpair {
+    fn from(data: (String, Value)) -> Self {
+        Kvpair::new(data.0, data.1)
+    }
+}
+
 impl From<String> for Value {
     fn from(value: String) -> Self {
         Self {
@@ -111,7 +470,23 @@ impl From<u32> for Value {
 impl From<i64> for Value {
     fn from(value: i64) -> Self {
         Self {
-            value: Some(value::Value::Integer(value.into())),
+            value: Some(value::Value::Integer(value)),
+        }
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Self {
+            value: Some(value::Value::Float(value)),
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Self {
+            value: Some(value::Value::Bool(value)),
         }
     }
 }
@@ -119,19 +494,11 @@ impl From<i64> for Value {
 impl From<Bytes> for Value {
     fn from(buf: Bytes) -> Self {
         Self {
-            value: Some(value::Value::Binary(buf.to_vec())),
+            value: Some(value::Value::Binary(buf)),
         }
     }
 }
 
-// impl From<Bytes> for Value {
-//     fn from(buf: Bytes) -> Self {
-//         Self {
-//             value: Some(value::Value::Binary(buf)),
-//         }
-//     }
-// }
-
 impl TryFrom<&[u8]> for Value {
     type Error = KvError;
     fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
@@ -150,17 +517,50 @@ impl From<Value> for CommandResponse {
     }
 }
 
+impl From<Vec<Value>> for CommandResponse {
+    fn from(values: Vec<Value>) -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            values,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<Vec<Kvpair>> for CommandResponse {
+    fn from(pairs: Vec<Kvpair>) -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            pairs,
+            ..Default::default()
+        }
+    }
+}
+
 impl From<KvError> for CommandResponse {
     fn from(e: KvError) -> Self {
         let mut result = Self {
             status: StatusCode::INTERNAL_SERVER_ERROR.as_u16() as _,
             message: e.to_string(),
-            values: vec![],
-            pairs: vec![],
+            ..Default::default()
         };
         match e {
             KvError::NotFound(_) => result.status = StatusCode::NOT_FOUND.as_u16() as _,
-            KvError::InvalidCommand(_) => result.status = StatusCode::BAD_REQUEST.as_u16() as _,
+            KvError::InvalidCommand(_) | KvError::ConvertError(_, _) => {
+                result.status = StatusCode::BAD_REQUEST.as_u16() as _
+            }
+            KvError::PreconditionFailed(_) => {
+                result.status = StatusCode::PRECONDITION_FAILED.as_u16() as _
+            }
+            KvError::PermissionDenied(_) => result.status = StatusCode::FORBIDDEN.as_u16() as _,
+            KvError::Unauthenticated(_) => result.status = StatusCode::UNAUTHORIZED.as_u16() as _,
+            KvError::UnsupportedVersion(..) => {
+                result.status = StatusCode::UPGRADE_REQUIRED.as_u16() as _
+            }
+            KvError::FrameError => result.status = StatusCode::PAYLOAD_TOO_LARGE.as_u16() as _,
+            KvError::SlowSubscriber(_) => {
+                result.status = StatusCode::TOO_MANY_REQUESTS.as_u16() as _
+            }
             _ => {}
         }
         result
@@ -187,6 +587,18 @@ impl TryFrom<&Value> for i64 {
     }
 }
 
+impl TryFrom<&Value> for f64 {
+    type Error = KvError;
+
+    fn try_from(v: &Value) -> Result<Self, Self::Error> {
+        match v.value {
+            Some(value::Value::Integer(i)) => Ok(i as f64),
+            Some(value::Value::Float(f)) => Ok(f),
+            _ => Err(KvError::ConvertError(v.format(), "Float")),
+        }
+    }
+}
+
 impl TryFrom<&CommandResponse> for i64 {
     type Error = KvError;
 
@@ -194,7 +606,7 @@ impl TryFrom<&CommandResponse> for i64 {
         if value.status != StatusCode::OK.as_u16() as u32 {
             return Err(KvError::ConvertError(value.format(), "CommandResponse"));
         }
-        match value.values.get(0) {
+        match value.values.first() {
             Some(v) => v.try_into(),
             None => Err(KvError::ConvertError(value.format(), "CommandResponse")),
         }
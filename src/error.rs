@@ -1,4 +1,3 @@
-use crate::Value;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -13,10 +12,28 @@ pub enum KvError {
     Internal(String),
     #[error("Frame is larger than max size")]
     FrameError,
-    #[error("Certificate parse error: error to load {0} {0}")]
+    #[error("Frame checksum mismatch: expected {0:#010x}, got {1:#010x}")]
+    ChecksumMismatch(u32, u32),
+    #[error("Unsupported compression algorithm: {0}")]
+    UnsupportedCompression(u32),
+    #[error("Certificate parse error: error to load {0} {1}")]
     CertifcateParseError(&'static str, &'static str),
     #[error("Cannot convert value {0} to {1}")]
     ConvertError(String, &'static str),
+    #[error("Precondition failed: {0}")]
+    PreconditionFailed(String),
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+    #[error("Authentication failed: {0}")]
+    Unauthenticated(String),
+    #[error("Protocol version {0} is not supported, server supports {1} to {2}")]
+    UnsupportedVersion(u32, u32, u32),
+    #[error("Timed out: {0}")]
+    Timeout(String),
+    #[error("Subscription {0} is too slow and has been disconnected")]
+    SlowSubscriber(u32),
+    #[error("Connection closed by peer")]
+    ConnectionClosed,
 
     // auto impl error conversion
     #[error("Failed to encode protobuf message")]
@@ -27,4 +44,6 @@ pub enum KvError {
     SledError(#[from] sled::Error),
     #[error("futures I/O error")]
     IoError(#[from] futures::io::Error),
+    #[error("yamux connection error")]
+    YamuxError(#[from] yamux::ConnectionError),
 }
@@ -9,17 +9,27 @@ pub use cmd::abi::*;
 pub use error::KvError;
 // pub use network::ClientStream;
 // pub use network::ServerStream;
-pub use network::frame::{FrameCodec, read_frame};
+pub use network::codec::ProstCodec;
+pub use network::frame::{FrameCodec, FrameConfig, read_frame};
 pub use network::stream::ProstStream;
 pub use network::utils;
-pub use network::{ClientStream, ServerStream, YamuxHandle, spawn_yamux_driver};
+pub use network::{
+    Authenticator, BackoffConfig, ClientStream, ConnectionState, HandshakeConfig, KvPool,
+    PROTOCOL_VERSION, PipelinedClient, PoolConfig, PooledClient, ReconnectingClient, ServerStream,
+    StreamResult, TlsClientConnector, TlsServerAcceptor, YamuxClient, YamuxHandle, YamuxServer,
+    YamuxStream, spawn_yamux_driver,
+};
 pub use service::CmdService;
 pub use service::Service;
 pub use service::ServiceInner;
+pub use service::context::{PeerIdentity, RequestContext};
 pub use service::exec_cmd;
-pub use storage::Storage;
+pub use service::subscription::{SubscriptionReceiver, SubscriptionStats, SubscriptionTopic};
+pub use service::topic::{MsgBus, PubSub, TopicStats};
+pub use service::topic_service::{StreamingResponse, TopicService};
 pub use storage::memory::MemTable;
 pub use storage::sled::SledDb;
+pub use storage::{Storage, StorageIter, TxnOp, TxnResult};
 
 #[cfg(test)]
 pub use service::{assert_res_error, assert_res_ok};
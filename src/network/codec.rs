@@ -0,0 +1,140 @@
+use bytes::BytesMut;
+use std::marker::PhantomData;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::network::frame::{INITIAL_READ_CAPACITY, frame_len};
+use crate::{FrameCodec, FrameConfig, KvError};
+
+/// FrameCodec 帧格式的 Decoder / Encoder，可以直接配合 tokio_util 的 Framed 使用
+/// 一次读到的数据里可以有多个帧，也可以只有半个帧
+pub struct ProstCodec<In, Out> {
+    config: FrameConfig,
+    _in: PhantomData<In>,
+    _out: PhantomData<Out>,
+}
+
+impl<In, Out> ProstCodec<In, Out> {
+    pub fn new(config: FrameConfig) -> Self {
+        Self {
+            config,
+            _in: PhantomData,
+            _out: PhantomData,
+        }
+    }
+
+    pub fn config(&self) -> &FrameConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: FrameConfig) {
+        self.config = config;
+    }
+}
+
+impl<In, Out> Decoder for ProstCodec<In, Out>
+where
+    In: FrameCodec,
+{
+    type Item = In;
+    type Error = KvError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<In>, KvError> {
+        let Some(len) = frame_len(src, self.config.max_frame)? else {
+            return Ok(None);
+        };
+        if src.len() < len {
+            // 不相信对方声明的长度，每次最多多预留 INITIAL_READ_CAPACITY
+            src.reserve((len - src.len()).min(INITIAL_READ_CAPACITY));
+            return Ok(None);
+        }
+        // split_to 不拷贝数据，解出来的 Value::Binary 直接引用这块内存
+        let mut frame = src.split_to(len);
+        In::decode_frame_with(&mut frame, &self.config).map(Some)
+    }
+}
+
+impl<In, Out> Encoder<Out> for ProstCodec<In, Out>
+where
+    Out: FrameCodec,
+{
+    type Error = KvError;
+
+    fn encode(&mut self, item: Out, dst: &mut BytesMut) -> Result<(), KvError> {
+        item.encode_frame_with(dst, &self.config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CommandRequest, CommandResponse, Value, value};
+    use bytes::{BufMut, Bytes};
+
+    fn codec() -> ProstCodec<CommandResponse, CommandResponse> {
+        ProstCodec::new(FrameConfig::default())
+    }
+
+    #[test]
+    fn decoder_should_return_all_frames_in_buffer() {
+        let mut codec = codec();
+        let mut buf = BytesMut::new();
+        let resps: Vec<CommandResponse> = (0..3).map(|i| Value::from(i).into()).collect();
+        for resp in &resps {
+            codec.encode(resp.clone(), &mut buf).unwrap();
+        }
+        for resp in resps {
+            assert_eq!(codec.decode(&mut buf).unwrap(), Some(resp));
+        }
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decoder_should_wait_for_partial_frame() {
+        let mut codec = codec();
+        let mut encoded = BytesMut::new();
+        let resp: CommandResponse = Value::from("hello").into();
+        codec.encode(resp.clone(), &mut encoded).unwrap();
+
+        // 每次只到一个字节
+        let mut buf = BytesMut::new();
+        let last = encoded.split_off(encoded.len() - 1);
+        for b in encoded {
+            buf.put_u8(b);
+            assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        }
+        buf.put_slice(&last);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(resp));
+    }
+
+    #[test]
+    fn decoder_should_reject_oversized_frame_from_header() {
+        let mut codec: ProstCodec<CommandRequest, CommandRequest> = ProstCodec::new(FrameConfig {
+            max_frame: 1024,
+            ..Default::default()
+        });
+        let mut buf = BytesMut::new();
+        buf.put_u32(4096);
+        assert!(matches!(codec.decode(&mut buf), Err(KvError::FrameError)));
+    }
+
+    #[test]
+    fn binary_values_should_not_be_copied() {
+        let mut codec = ProstCodec::<CommandResponse, CommandResponse>::new(FrameConfig {
+            compression: crate::CompressionAlgorithm::None,
+            ..Default::default()
+        });
+        let data = Bytes::from(vec![42u8; 4096]);
+        let mut buf = BytesMut::new();
+        codec
+            .encode(Value::from(data.clone()).into(), &mut buf)
+            .unwrap();
+        let range = buf.as_ptr_range();
+        let resp = codec.decode(&mut buf).unwrap().unwrap();
+        let Some(value::Value::Binary(binary)) = &resp.values[0].value else {
+            panic!("expect binary value");
+        };
+        assert_eq!(binary, &data);
+        assert!(range.contains(&binary.as_ptr()));
+    }
+}
@@ -1,6 +1,6 @@
 use std::io::{Read, Write};
 
-use crate::{CommandRequest, CommandResponse, KvError};
+use crate::{CommandRequest, CommandResponse, CompressionAlgorithm, KvError};
 use bytes::{Buf, BufMut, BytesMut};
 use flate2::{Compression, read::GzDecoder, write::GzEncoder};
 use prost::Message;
@@ -8,83 +8,256 @@ use tokio::io::{AsyncRead, AsyncReadExt};
 use tracing::debug;
 
 pub const LEN_LEN: usize = 4;
-pub const MAX_FRAME: usize = 2 * 1024 * 1024 * 1024;
+// header 的高 3 位是压缩算法，第 28 位表示帧尾有 CRC32C，低 28 位是长度
+pub const MAX_FRAME: usize = LEN_MASK;
+/// 默认每个连接能接收的最大帧，解压之后的大小也不能超过它
+pub const DEFAULT_MAX_FRAME: usize = 16 * 1024 * 1024;
 pub const COMPRESSION_LIMIT: usize = 1436;
-// highest bit of 4 bytes
-const COMPRESSION_BIT: usize = 1 << 31;
+const ALGO_SHIFT: usize = 29;
+const CHECKSUM_FLAG: usize = 1 << 28;
+const LEN_MASK: usize = CHECKSUM_FLAG - 1;
+const CHECKSUM_LEN: usize = 4;
+// 读大帧时先分配这么多，之后随着数据到达再增长
+pub(crate) const INITIAL_READ_CAPACITY: usize = 64 * 1024;
+
+/// 一个连接上编码帧时使用的压缩配置
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameConfig {
+    pub compression: CompressionAlgorithm,
+    /// None 时使用算法的默认级别；lz4 没有级别
+    pub level: Option<i32>,
+    /// payload 超过 threshold 字节才压缩
+    pub threshold: usize,
+    /// 收到的帧（解压前和解压后）超过 max_frame 字节时返回 FrameError
+    pub max_frame: usize,
+    /// 对方能接收的最大帧，握手时得到；要发送的消息超过它时返回 FrameError
+    pub max_send_frame: usize,
+    /// 发送的帧带上 CRC32C，只有对方也支持时才能打开；打开之后收到的帧也必须带 CRC32C，
+    /// 没打开时收到的帧有 CRC32C 也会校验
+    pub checksum: bool,
+}
+
+impl Default for FrameConfig {
+    fn default() -> Self {
+        Self {
+            compression: CompressionAlgorithm::Gzip,
+            level: None,
+            threshold: COMPRESSION_LIMIT,
+            max_frame: DEFAULT_MAX_FRAME,
+            max_send_frame: MAX_FRAME,
+            checksum: false,
+        }
+    }
+}
 
 pub trait FrameCodec
 where
     Self: Message + Sized + Default + Send + Sync,
 {
     fn encode_frame(&self, buf: &mut BytesMut) -> Result<(), KvError> {
-        let raw_bit_size = self.encoded_len();
-        if raw_bit_size > MAX_FRAME {
+        self.encode_frame_with(buf, &FrameConfig::default())
+    }
+
+    fn encode_frame_with(&self, buf: &mut BytesMut, config: &FrameConfig) -> Result<(), KvError> {
+        let start = buf.len();
+        encode_payload(self, buf, config)?;
+        if config.checksum {
+            // 校验的是帧头和实际发送的（压缩之后的）payload，帧头里的长度和算法错了也能发现
+            let header = (&buf[start..]).get_u32() | CHECKSUM_FLAG as u32;
+            buf[start..start + LEN_LEN].copy_from_slice(&header.to_be_bytes());
+            let crc = crc32c::crc32c(&buf[start..]);
+            buf.put_u32(crc);
+        }
+        Ok(())
+    }
+
+    fn decode_frame(buf: &mut BytesMut) -> Result<Self, KvError> {
+        Self::decode_frame_with(buf, &FrameConfig::default())
+    }
+
+    // 可以解码任意一种支持的压缩算法，和编码端的配置无关
+    fn decode_frame_with(buf: &mut BytesMut, config: &FrameConfig) -> Result<Self, KvError> {
+        if buf.len() < LEN_LEN {
             return Err(KvError::FrameError);
         }
-        buf.put_u32(raw_bit_size as _);
-        if raw_bit_size > COMPRESSION_LIMIT {
-            let mut encoded_buf = Vec::with_capacity(raw_bit_size);
-            self.encode(&mut encoded_buf)?;
-            // buf: [place for length_info] + [place for payload]
-            let payload = buf.split_off(LEN_LEN);
-            buf.clear(); // buf => [vacant]
-            // encoder => data writer
-            let mut encoder = GzEncoder::new(payload.writer(), Compression::default());
-            encoder.write_all(&encoded_buf[..])?; // buf1 => [encoded_payload]
-            let compressed = encoder.finish()?.into_inner();
-            debug!(
-                "encode a frame: size {}({})",
-                raw_bit_size,
-                compressed.len()
-            );
-            buf.put_u32((compressed.len() | COMPRESSION_BIT) as _);
-            buf.unsplit(compressed);
-            Ok(())
-        } else {
-            self.encode(buf)?;
-            Ok(())
+        let header = buf.get_u32();
+        let (len, algo, checksum) = decode_header(header as _)?;
+        debug!(
+            "Got a frame: msg len {}, compression {:?}, checksum {}",
+            len, algo, checksum
+        );
+        // 协商好校验之后，没有 CRC32C 的帧可能是帧头里的标志位被改掉了
+        if config.checksum && !checksum {
+            return Err(KvError::FrameError);
+        }
+        if len > config.max_frame || len + trailer_len(checksum) > buf.len() {
+            return Err(KvError::FrameError);
+        }
+        let payload = buf.split_to(len);
+        if checksum {
+            let expected = buf.get_u32();
+            let actual = crc32c::crc32c_append(crc32c::crc32c(&header.to_be_bytes()), &payload);
+            if expected != actual {
+                return Err(KvError::ChecksumMismatch(expected, actual));
+            }
+        }
+        match algo {
+            CompressionAlgorithm::None => Ok(Self::decode(payload)?),
+            algo => Ok(Self::decode(
+                &decompress(algo, &payload, config.max_frame)?[..],
+            )?),
         }
     }
+}
 
-    fn decode_frame(buf: &mut BytesMut) -> Result<Self, KvError> {
-        let header = buf.get_u32() as usize;
-        let (len, compressed) = decode_header(header);
-        debug!("Got a frame: msg len {}, compressed {}", len, compressed);
-        if compressed {
-            // decoder => data reader
-            let mut decoder = GzDecoder::new(&buf[..len]);
-            let mut unzipped = Vec::with_capacity(len * 2);
-            decoder.read_to_end(&mut unzipped)?;
-            buf.advance(len);
-            Ok(Self::decode(&unzipped[..unzipped.len()])?)
-        } else {
-            let msg = Self::decode(&buf[..len])?;
-            buf.advance(len);
-            Ok(msg)
+impl FrameCodec for CommandRequest {}
+impl FrameCodec for CommandResponse {}
+
+// 写入帧头和 payload
+fn encode_payload(
+    msg: &impl Message,
+    buf: &mut BytesMut,
+    config: &FrameConfig,
+) -> Result<(), KvError> {
+    let raw_bit_size = msg.encoded_len();
+    // 对方解压之后也要检查大小，所以按压缩之前的大小算
+    if raw_bit_size > config.max_send_frame.min(MAX_FRAME) {
+        return Err(KvError::FrameError);
+    }
+    if config.compression != CompressionAlgorithm::None && raw_bit_size > config.threshold {
+        let encoded_buf = msg.encode_to_vec();
+        let compressed = compress(config, &encoded_buf)?;
+        debug!(
+            "encode a frame: size {}({}), {:?}",
+            raw_bit_size,
+            compressed.len(),
+            config.compression
+        );
+        // 压缩之后没有变小就直接发送原始数据
+        if compressed.len() < raw_bit_size {
+            buf.put_u32((compressed.len() | algo_bits(config.compression) << ALGO_SHIFT) as _);
+            buf.put_slice(&compressed);
+            return Ok(());
+        }
+    }
+    buf.put_u32(raw_bit_size as _);
+    msg.encode(buf)?;
+    Ok(())
+}
+
+// 旧的格式里最高位为 1 表示 gzip，所以 gzip 对应 0b100，旧的帧可以照常解码
+fn algo_bits(algo: CompressionAlgorithm) -> usize {
+    match algo {
+        CompressionAlgorithm::None => 0b000,
+        CompressionAlgorithm::Gzip => 0b100,
+        CompressionAlgorithm::Zstd => 0b101,
+        CompressionAlgorithm::Lz4 => 0b110,
+    }
+}
+
+// 返回 payload 的长度、压缩算法、帧尾是否有 CRC32C
+fn decode_header(header: usize) -> Result<(usize, CompressionAlgorithm, bool), KvError> {
+    let len = header & LEN_MASK;
+    let checksum = header & CHECKSUM_FLAG != 0;
+    let algo = match header >> ALGO_SHIFT {
+        0b000 => CompressionAlgorithm::None,
+        0b100 => CompressionAlgorithm::Gzip,
+        0b101 => CompressionAlgorithm::Zstd,
+        0b110 => CompressionAlgorithm::Lz4,
+        v => return Err(KvError::UnsupportedCompression(v as _)),
+    };
+    Ok((len, algo, checksum))
+}
+
+fn trailer_len(checksum: bool) -> usize {
+    if checksum { CHECKSUM_LEN } else { 0 }
+}
+
+// buf 开头的帧一共有多少字节（包括帧头和帧尾），帧头还不完整时返回 None
+pub(crate) fn frame_len(buf: &[u8], max_frame: usize) -> Result<Option<usize>, KvError> {
+    if buf.len() < LEN_LEN {
+        return Ok(None);
+    }
+    let header = (&buf[..LEN_LEN]).get_u32() as usize;
+    let (len, _, checksum) = decode_header(header)?;
+    if len > max_frame {
+        return Err(KvError::FrameError);
+    }
+    Ok(Some(LEN_LEN + len + trailer_len(checksum)))
+}
+
+fn compress(config: &FrameConfig, data: &[u8]) -> Result<Vec<u8>, KvError> {
+    match config.compression {
+        CompressionAlgorithm::None => Ok(data.to_vec()),
+        CompressionAlgorithm::Gzip => {
+            let level = match config.level {
+                Some(level) => Compression::new(level.clamp(0, 9) as _),
+                None => Compression::default(),
+            };
+            let mut encoder = GzEncoder::new(Vec::with_capacity(data.len()), level);
+            encoder.write_all(data)?;
+            Ok(encoder.finish()?)
         }
+        // zstd 的级别 0 表示默认级别
+        CompressionAlgorithm::Zstd => Ok(zstd::bulk::compress(data, config.level.unwrap_or(0))?),
+        CompressionAlgorithm::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
     }
 }
 
-fn decode_header(header: usize) -> (usize, bool) {
-    let len = header & !COMPRESSION_BIT;
-    let compressed = header & COMPRESSION_BIT == COMPRESSION_BIT;
-    (len, compressed)
+// 解压之后超过 limit 字节就放弃，防止压缩炸弹
+fn decompress(algo: CompressionAlgorithm, data: &[u8], limit: usize) -> Result<Vec<u8>, KvError> {
+    match algo {
+        CompressionAlgorithm::None => Ok(data.to_vec()),
+        CompressionAlgorithm::Gzip => read_limited(GzDecoder::new(data), data.len(), limit),
+        CompressionAlgorithm::Zstd => {
+            read_limited(zstd::stream::read::Decoder::new(data)?, data.len(), limit)
+        }
+        CompressionAlgorithm::Lz4 => {
+            // 开头 4 字节（小端）是解压之后的大小，先检查再分配
+            match data.get(..4) {
+                Some(size) if u32::from_le_bytes(size.try_into().unwrap()) as usize <= limit => {}
+                _ => return Err(KvError::FrameError),
+            }
+            lz4_flex::decompress_size_prepended(data)
+                .map_err(|e| KvError::Internal(format!("lz4: {}", e)))
+        }
+    }
 }
 
-impl FrameCodec for CommandRequest {}
-impl FrameCodec for CommandResponse {}
+fn read_limited(reader: impl Read, len: usize, limit: usize) -> Result<Vec<u8>, KvError> {
+    let mut unzipped = Vec::with_capacity((len * 2).min(limit));
+    reader.take(limit as u64 + 1).read_to_end(&mut unzipped)?;
+    if unzipped.len() > limit {
+        return Err(KvError::FrameError);
+    }
+    Ok(unzipped)
+}
 
-pub async fn read_frame<S>(stream: &mut S, buf: &mut BytesMut) -> Result<(), KvError>
+/// 读一个完整的帧（包括帧头）追加到 buf 里，长度超过 max_frame 时不读 payload，直接返回 FrameError
+pub async fn read_frame<S>(
+    stream: &mut S,
+    buf: &mut BytesMut,
+    max_frame: usize,
+) -> Result<(), KvError>
 where
     S: AsyncRead + Send + Unpin,
 {
     let header = stream.read_u32().await? as usize;
-    let (len, _compressed) = decode_header(header);
-    buf.reserve(LEN_LEN + len); // init buf len
-    buf.put_u32(header as _); // insert len info => buf
-    unsafe { buf.advance_mut(len) };
-    stream.read_exact(&mut buf[LEN_LEN..]).await?;
+    let (len, _, checksum) = decode_header(header)?;
+    if len > max_frame {
+        return Err(KvError::FrameError);
+    }
+    let len = len + trailer_len(checksum);
+    // 不能相信对方声明的长度，数据到了多少再分配多少
+    buf.reserve(LEN_LEN + len.min(INITIAL_READ_CAPACITY));
+    buf.put_u32(header as _);
+    let end = buf.len() + len;
+    let mut payload = stream.take(len as _);
+    while buf.len() < end {
+        if payload.read_buf(buf).await? == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+    }
     Ok(())
 }
 
@@ -127,6 +300,102 @@ mod tests {
         assert_eq!(resp, resp_1);
     }
 
+    fn large_resp() -> CommandResponse {
+        let value: Value = Bytes::from(vec![7u8; COMPRESSION_LIMIT * 4]).into();
+        value.into()
+    }
+
+    #[test]
+    fn all_compression_algorithms_should_roundtrip() {
+        let resp = large_resp();
+        for (algo, bits) in [
+            (CompressionAlgorithm::Gzip, 0b100),
+            (CompressionAlgorithm::Zstd, 0b101),
+            (CompressionAlgorithm::Lz4, 0b110),
+        ] {
+            let config = FrameConfig {
+                compression: algo,
+                level: Some(3),
+                ..Default::default()
+            };
+            let mut buf = BytesMut::new();
+            resp.encode_frame_with(&mut buf, &config).unwrap();
+            assert_eq!(buf[0] >> 5, bits);
+            assert!(buf.len() < resp.encoded_len());
+            assert_eq!(CommandResponse::decode_frame(&mut buf).unwrap(), resp);
+        }
+    }
+
+    #[test]
+    fn messages_larger_than_max_send_frame_should_not_be_encoded() {
+        // 压缩之后变小也不行，对方解压之后还是会超过限制
+        let config = FrameConfig {
+            compression: CompressionAlgorithm::Zstd,
+            max_send_frame: COMPRESSION_LIMIT * 2,
+            ..Default::default()
+        };
+        let mut buf = BytesMut::new();
+        let res = large_resp().encode_frame_with(&mut buf, &config);
+        assert!(matches!(res, Err(KvError::FrameError)));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn frames_below_threshold_or_incompressible_should_not_be_compressed() {
+        let config = FrameConfig {
+            compression: CompressionAlgorithm::Zstd,
+            threshold: COMPRESSION_LIMIT * 8,
+            ..Default::default()
+        };
+        let mut buf = BytesMut::new();
+        large_resp().encode_frame_with(&mut buf, &config).unwrap();
+        assert!(!is_compressed(&buf));
+
+        // 随机数据压缩之后不会变小
+        let mut x = 0x2545_f491_4f6c_dd1du64;
+        let data: Vec<u8> = (0..4096)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect();
+        let resp: CommandResponse = Value::from(Bytes::from(data)).into();
+        let config = FrameConfig {
+            compression: CompressionAlgorithm::Lz4,
+            ..Default::default()
+        };
+        let mut buf = BytesMut::new();
+        resp.encode_frame_with(&mut buf, &config).unwrap();
+        assert!(!is_compressed(&buf));
+        assert_eq!(CommandResponse::decode_frame(&mut buf).unwrap(), resp);
+    }
+
+    #[test]
+    fn legacy_gzip_frame_should_decode() {
+        // 旧的格式：最高位表示 gzip，低 31 位是长度
+        let resp = large_resp();
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(&resp.encode_to_vec()).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let mut buf = BytesMut::new();
+        buf.put_u32((compressed.len() | 1 << 31) as _);
+        buf.put_slice(&compressed);
+        assert_eq!(CommandResponse::decode_frame(&mut buf).unwrap(), resp);
+    }
+
+    #[test]
+    fn unknown_compression_should_be_rejected() {
+        let mut buf = BytesMut::new();
+        buf.put_u32(0b111 << 29 | 1);
+        buf.put_u8(0);
+        assert!(matches!(
+            CommandResponse::decode_frame(&mut buf),
+            Err(KvError::UnsupportedCompression(0b111))
+        ));
+    }
+
     #[tokio::test]
     async fn read_frame_should_work() {
         let mut buf = BytesMut::new();
@@ -134,11 +403,156 @@ mod tests {
         cmd.encode_frame(&mut buf).unwrap();
         let mut stream = DummyStream { buf };
         let mut data = BytesMut::new();
-        read_frame(&mut stream, &mut data).await.unwrap();
+        read_frame(&mut stream, &mut data, DEFAULT_MAX_FRAME)
+            .await
+            .unwrap();
         let cmd1 = CommandRequest::decode_frame(&mut data).unwrap();
         assert_eq!(cmd, cmd1);
     }
 
+    #[tokio::test]
+    async fn checksum_frames_should_roundtrip() {
+        for compression in [CompressionAlgorithm::None, CompressionAlgorithm::Zstd] {
+            let config = FrameConfig {
+                compression,
+                checksum: true,
+                ..Default::default()
+            };
+            let resp = large_resp();
+            let mut buf = BytesMut::new();
+            resp.encode_frame_with(&mut buf, &config).unwrap();
+            assert_eq!(buf[0] & 0x10, 0x10);
+
+            // read_frame 要把帧尾的 CRC32C 一起读出来
+            let mut stream = DummyStream { buf };
+            let mut data = BytesMut::new();
+            read_frame(&mut stream, &mut data, DEFAULT_MAX_FRAME)
+                .await
+                .unwrap();
+            assert!(stream.buf.is_empty());
+            assert_eq!(CommandResponse::decode_frame(&mut data).unwrap(), resp);
+        }
+    }
+
+    #[test]
+    fn corrupted_frames_should_be_detected() {
+        let config = FrameConfig {
+            checksum: true,
+            ..Default::default()
+        };
+        let mut buf = BytesMut::new();
+        CommandRequest::new_hset("t1", "k1", "v1".into())
+            .encode_frame_with(&mut buf, &config)
+            .unwrap();
+
+        // 改掉 payload 或者帧尾里的任意一位
+        for pos in [LEN_LEN, buf.len() / 2, buf.len() - 1] {
+            let mut corrupted = buf.clone();
+            corrupted[pos] ^= 0x01;
+            assert!(matches!(
+                CommandRequest::decode_frame(&mut corrupted),
+                Err(KvError::ChecksumMismatch(_, _))
+            ));
+        }
+        assert!(CommandRequest::decode_frame(&mut buf).is_ok());
+    }
+
+    #[test]
+    fn corrupted_headers_should_be_detected() {
+        let config = FrameConfig {
+            checksum: true,
+            ..Default::default()
+        };
+        let mut buf = BytesMut::new();
+        CommandRequest::new_hset("t1", "k1", "v1".into())
+            .encode_frame_with(&mut buf, &config)
+            .unwrap();
+        // 后面多放一些数据，长度变大时也能读到一个“完整”的帧
+        buf.put_slice(&[0u8; 64]);
+
+        // 长度、校验标志、算法里的任意一位被改掉都不能解出消息
+        for bit in 0..32 {
+            let mut corrupted = buf.clone();
+            corrupted[3 - bit / 8] ^= 1 << (bit % 8);
+            let res = CommandRequest::decode_frame_with(&mut corrupted, &config);
+            assert!(res.is_err(), "flipping header bit {} is not detected", bit);
+        }
+        assert!(CommandRequest::decode_frame_with(&mut buf, &config).is_ok());
+    }
+
+    #[test]
+    fn frames_without_checksum_should_be_rejected_once_negotiated() {
+        let mut buf = BytesMut::new();
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        cmd.encode_frame(&mut buf).unwrap();
+        let config = FrameConfig {
+            checksum: true,
+            ..Default::default()
+        };
+        let res = CommandRequest::decode_frame_with(&mut buf.clone(), &config);
+        assert!(matches!(res, Err(KvError::FrameError)));
+        // 没有协商校验时照常解码
+        assert_eq!(CommandRequest::decode_frame(&mut buf).unwrap(), cmd);
+    }
+
+    #[tokio::test]
+    async fn read_frame_should_reject_oversized_frame_before_reading() {
+        let mut buf = BytesMut::new();
+        buf.put_u32(MAX_FRAME as _);
+        let mut stream = DummyStream { buf };
+        let mut data = BytesMut::new();
+        let res = read_frame(&mut stream, &mut data, 1024).await;
+        assert!(matches!(res, Err(KvError::FrameError)));
+        assert_eq!(data.capacity(), 0);
+    }
+
+    #[test]
+    fn decode_frame_should_reject_truncated_frame() {
+        let mut buf = BytesMut::new();
+        CommandRequest::new_hdel("t1", "k1")
+            .encode_frame(&mut buf)
+            .unwrap();
+        let mut short = buf.split_to(buf.len() - 1);
+        assert!(matches!(
+            CommandRequest::decode_frame(&mut short),
+            Err(KvError::FrameError)
+        ));
+        let mut empty = BytesMut::new();
+        assert!(matches!(
+            CommandRequest::decode_frame(&mut empty),
+            Err(KvError::FrameError)
+        ));
+    }
+
+    #[test]
+    fn decompression_bombs_should_be_rejected() {
+        // 1 MiB 的 0 压缩之后只有几 KB
+        let resp: CommandResponse = Value::from(Bytes::from(vec![0u8; 1024 * 1024])).into();
+        let small = FrameConfig {
+            max_frame: 64 * 1024,
+            ..Default::default()
+        };
+        for algo in [
+            CompressionAlgorithm::Gzip,
+            CompressionAlgorithm::Zstd,
+            CompressionAlgorithm::Lz4,
+        ] {
+            let config = FrameConfig {
+                compression: algo,
+                ..Default::default()
+            };
+            let mut buf = BytesMut::new();
+            resp.encode_frame_with(&mut buf, &config).unwrap();
+            assert!(buf.len() < small.max_frame);
+            let mut copy = buf.clone();
+            assert!(matches!(
+                CommandResponse::decode_frame_with(&mut buf, &small),
+                Err(KvError::FrameError)
+            ));
+            assert_eq!(CommandResponse::decode_frame(&mut copy).unwrap(), resp);
+        }
+    }
+
     fn is_compressed(data: &[u8]) -> bool {
         if let [v] = data[..1] {
             v >> 7 == 1
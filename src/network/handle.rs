@@ -60,11 +60,11 @@ where
             //         => Ready(None)
             let net_fut = future::poll_fn(|cx| {
                 // if someone's waiting => try outbound
-                if !pending_outbounds.is_empty() {
-                    if let std::task::Poll::Ready(out_stream) = conn.poll_new_outbound(cx) {
-                        // Some((true, 出站结果))
-                        return std::task::Poll::Ready(Some((true, out_stream)));
-                    }
+                if !pending_outbounds.is_empty()
+                    && let std::task::Poll::Ready(out_stream) = conn.poll_new_outbound(cx)
+                {
+                    // Some((true, 出站结果))
+                    return std::task::Poll::Ready(Some((true, out_stream)));
                 }
                 // try inbound
                 match conn.poll_next_inbound(cx) {
@@ -0,0 +1,168 @@
+use std::time::Duration;
+
+use crate::network::frame::DEFAULT_MAX_FRAME;
+use crate::{CompressionAlgorithm, Credentials, Hello, KvError, Welcome};
+
+/// 当前的协议版本
+pub const PROTOCOL_VERSION: u32 = 1;
+/// 服务端还能接受的最老的协议版本
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// 默认的握手超时时间
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 校验 Hello 里的认证信息，通过之后返回用户名
+pub type Authenticator = fn(&Credentials) -> Result<String, KvError>;
+
+/// 连接的状态：Handshaking 时等待 Hello，握手成功之后进入 Ready
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Handshaking,
+    Ready,
+    Closed,
+}
+
+/// 服务端的握手配置
+#[derive(Debug, Clone)]
+pub struct HandshakeConfig {
+    /// 为 false 时第一个请求不是 Hello 的连接按旧协议处理
+    pub require_hello: bool,
+    /// 服务端能接收的最大帧，通过 Welcome 告诉客户端
+    pub max_frame_size: u32,
+    /// 为 None 时不要求认证
+    pub authenticator: Option<Authenticator>,
+    /// require_hello 时，连接建立之后超过这个时间还没收到 Hello 就关闭连接
+    pub timeout: Duration,
+}
+
+impl Default for HandshakeConfig {
+    fn default() -> Self {
+        Self {
+            require_hello: false,
+            max_frame_size: DEFAULT_MAX_FRAME as _,
+            authenticator: None,
+            timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+        }
+    }
+}
+
+impl HandshakeConfig {
+    /// 检查客户端的 Hello，返回协商好的 Welcome 和通过认证的用户名
+    pub fn negotiate(&self, hello: &Hello) -> Result<(Welcome, Option<String>), KvError> {
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&hello.version) {
+            return Err(KvError::UnsupportedVersion(
+                hello.version,
+                MIN_PROTOCOL_VERSION,
+                PROTOCOL_VERSION,
+            ));
+        }
+        let user = match (self.authenticator, &hello.credentials) {
+            (None, _) => None,
+            (Some(auth), Some(creds)) => Some(auth(creds)?),
+            (Some(_), None) => {
+                return Err(KvError::Unauthenticated("credentials required".into()));
+            }
+        };
+        // 选客户端最想要的、服务端也认识的算法
+        let compression = hello
+            .compressions
+            .iter()
+            .find_map(|v| CompressionAlgorithm::from_i32(*v))
+            .unwrap_or(CompressionAlgorithm::None);
+        let welcome = Welcome {
+            version: hello.version,
+            compression: compression as _,
+            max_frame_size: self.max_frame_size,
+            checksum: hello.checksum,
+        };
+        Ok((welcome, user))
+    }
+}
+
+impl Hello {
+    /// 使用当前协议版本，支持所有压缩算法
+    pub fn new() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            compressions: vec![
+                CompressionAlgorithm::Zstd as _,
+                CompressionAlgorithm::Lz4 as _,
+                CompressionAlgorithm::Gzip as _,
+            ],
+            max_frame_size: DEFAULT_MAX_FRAME as _,
+            credentials: None,
+            checksum: false,
+        }
+    }
+
+    pub fn with_credentials(
+        mut self,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Self {
+        self.credentials = Some(Credentials {
+            username: username.into(),
+            password: password.into(),
+        });
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_password(creds: &Credentials) -> Result<String, KvError> {
+        match creds.password.as_str() {
+            "secret" => Ok(creds.username.clone()),
+            _ => Err(KvError::Unauthenticated(creds.username.clone())),
+        }
+    }
+
+    #[test]
+    fn negotiate_should_pick_first_supported_compression() {
+        let config = HandshakeConfig::default();
+        let mut hello = Hello::new();
+        hello.compressions = vec![42, CompressionAlgorithm::Lz4 as _];
+        let (welcome, user) = config.negotiate(&hello).unwrap();
+        assert_eq!(welcome.version, PROTOCOL_VERSION);
+        assert_eq!(welcome.compression(), CompressionAlgorithm::Lz4);
+        assert_eq!(user, None);
+
+        hello.compressions.clear();
+        let (welcome, _) = config.negotiate(&hello).unwrap();
+        assert_eq!(welcome.compression(), CompressionAlgorithm::None);
+    }
+
+    #[test]
+    fn negotiate_should_reject_unsupported_version() {
+        let hello = Hello {
+            version: PROTOCOL_VERSION + 1,
+            ..Hello::new()
+        };
+        assert!(matches!(
+            HandshakeConfig::default().negotiate(&hello),
+            Err(KvError::UnsupportedVersion(v, _, _)) if v == PROTOCOL_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn negotiate_should_check_credentials() {
+        let config = HandshakeConfig {
+            authenticator: Some(check_password),
+            ..Default::default()
+        };
+        assert!(matches!(
+            config.negotiate(&Hello::new()),
+            Err(KvError::Unauthenticated(_))
+        ));
+        let hello = Hello::new().with_credentials("alice", "wrong");
+        assert!(matches!(
+            config.negotiate(&hello),
+            Err(KvError::Unauthenticated(_))
+        ));
+        let hello = Hello::new().with_credentials("alice", "secret");
+        let (_, user) = config.negotiate(&hello).unwrap();
+        assert_eq!(user.as_deref(), Some("alice"));
+    }
+}
@@ -1,18 +1,56 @@
+pub mod codec;
 pub mod frame;
 pub mod handle;
+pub mod handshake;
+pub mod multiplex;
+pub mod pipeline;
+pub mod pool;
+pub mod reconnect;
 pub mod stream;
+pub mod tls;
 
-pub use frame::{FrameCodec, read_frame};
+pub use codec::ProstCodec;
+pub use frame::{FrameCodec, FrameConfig, read_frame};
 pub use handle::{YamuxHandle, spawn_yamux_driver};
+pub use handshake::{
+    Authenticator, ConnectionState, DEFAULT_HANDSHAKE_TIMEOUT, HandshakeConfig,
+    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+pub use multiplex::{YamuxClient, YamuxServer, YamuxStream};
+pub use pipeline::PipelinedClient;
+pub use pool::{KvPool, PoolConfig, PooledClient};
+pub use reconnect::{BackoffConfig, ReconnectingClient};
+pub use tls::{TlsClientConnector, TlsServerAcceptor};
 
-use futures::{SinkExt, StreamExt};
-use tokio::io::{AsyncRead, AsyncWrite};
+use futures::{SinkExt, Stream, StreamExt};
+use prost::Message;
+use std::{pin::Pin, sync::Arc};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{Semaphore, mpsc},
+    task::JoinHandle,
+};
+use tracing::{debug, warn};
 
-use crate::{CommandRequest, CommandResponse, KvError, ProstStream, Service};
+use crate::{
+    CommandRequest, CommandResponse, Hello, KvError, ProstStream, RequestContext, RequestData,
+    Service, StreamingResponse, Welcome, network::frame::MAX_FRAME,
+};
+
+// 单个连接上等待写出的 response 数量
+const WRITE_QUEUE_CAPACITY: usize = 128;
+// 单个连接上同时处理的带 id 的请求数量，达到之后暂停读取新的请求
+const MAX_INFLIGHT_REQUESTS: usize = 128;
+
+pub type StreamResult = Pin<Box<dyn Stream<Item = Result<CommandResponse, KvError>> + Send>>;
 
 pub struct ServerStream<S> {
     inner: ProstStream<S, CommandRequest, CommandResponse>,
     service: Service,
+    // 连接上所有请求共享，比如 mTLS 验证过的客户端身份
+    ctx: Arc<RequestContext>,
+    handshake: HandshakeConfig,
+    state: ConnectionState,
 }
 
 pub struct ClientStream<S> {
@@ -21,22 +59,254 @@ pub struct ClientStream<S> {
 
 impl<S> ServerStream<S>
 where
-    S: AsyncRead + AsyncWrite + Unpin + Send,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
     pub fn new(stream: S, service: Service) -> Self {
         Self {
             inner: ProstStream::new(stream),
-            service: service,
+            service,
+            ctx: Default::default(),
+            handshake: HandshakeConfig::default(),
+            state: ConnectionState::Handshaking,
         }
     }
 
+    /// 设置握手的要求，比如必须先发送 Hello、需要认证；
+    /// 超过 max_frame_size 的请求会被拒绝
+    pub fn with_handshake(mut self, config: HandshakeConfig) -> Self {
+        let frame = FrameConfig {
+            max_frame: config.max_frame_size as _,
+            ..*self.inner.frame_config()
+        };
+        self.inner.set_frame_config(frame);
+        self.handshake = config;
+        self
+    }
+
+    /// 设置这个连接的请求上下文，会传给 Service::process_request
+    pub fn with_context(mut self, ctx: RequestContext) -> Self {
+        self.ctx = Arc::new(ctx);
+        self
+    }
+
+    /// 设置 response 的压缩算法、级别和阈值
+    pub fn with_frame_config(mut self, config: FrameConfig) -> Self {
+        self.inner = self.inner.with_frame_config(config);
+        self
+    }
+
+    /// 还没写出的 response 超过 high_water 字节时，推送和命令的处理要等它们写出去
+    pub fn with_high_water_mark(mut self, high_water: usize) -> Self {
+        self.inner = self.inner.with_high_water_mark(high_water);
+        self
+    }
+
+    // id 为 0 的请求按顺序处理，response 按请求的顺序返回；
+    // 带 id 的请求并发处理，客户端按 id 匹配 response
     pub async fn process(mut self) -> Result<(), KvError> {
-        while let Some(Ok(cmd)) = self.inner.next().await {
-            println!("got a new cmd: {:?}", cmd);
-            let resp = self.service.process_request(cmd);
-            self.inner.send(resp).await?;
+        let first = self.handshake().await?;
+        // 握手失败时连接已经关闭
+        if self.state != ConnectionState::Ready {
+            return Ok(());
+        }
+        let max_send_frame = self.inner.frame_config().max_send_frame.min(MAX_FRAME);
+        let (mut sink, stream) = self.inner.split();
+        // 旧的客户端不发送 Hello，第一个请求就是普通命令
+        let mut stream = futures::stream::iter(first.map(Ok)).chain(stream);
+        // 所有 response 都经由 writer 任务写出，subscription 的推送和普通命令互不阻塞
+        let (tx, mut rx) = mpsc::channel::<(u64, Arc<CommandResponse>)>(WRITE_QUEUE_CAPACITY);
+        let writer = tokio::spawn(async move {
+            while let Some(item) = rx.recv().await {
+                sink.feed(stamp(item, max_send_frame)).await?;
+                // 已经排队的 response 攒在一起写出去，最多攒一个队列的长度
+                for _ in 1..WRITE_QUEUE_CAPACITY {
+                    match rx.try_recv() {
+                        Ok(item) => sink.feed(stamp(item, max_send_frame)).await?,
+                        Err(_) => break,
+                    }
+                }
+                sink.flush().await?;
+            }
+            sink.close().await?;
+            Ok::<_, KvError>(())
+        });
+
+        let mut tasks: Vec<JoinHandle<()>> = vec![];
+        let inflight = Arc::new(Semaphore::new(MAX_INFLIGHT_REQUESTS));
+        loop {
+            // 先拿到 permit 再读下一个请求，处理不过来时不再读取，由 TCP 反压给客户端
+            let Ok(permit) = inflight.clone().acquire_owned().await else {
+                break;
+            };
+            let Some(res) = stream.next().await else {
+                break;
+            };
+            let cmd = match res {
+                Ok(cmd) => cmd,
+                // 对方断开连接
+                Err(KvError::IoError(_)) => break,
+                // 比如帧太大：告诉客户端原因之后关闭连接
+                Err(e) => {
+                    warn!("invalid frame: {:?}", e);
+                    let _ = tx.send((0, Arc::new(e.into()))).await;
+                    break;
+                }
+            };
+            debug!("got a new cmd: {:?}", cmd);
+            let id = cmd.id;
+            let is_subscribe = matches!(
+                cmd.request_data,
+                Some(RequestData::Subscribe(_))
+                    | Some(RequestData::Psubscribe(_))
+                    | Some(RequestData::Watch(_))
+            );
+            let tx = tx.clone();
+            if id != 0 {
+                let service = self.service.clone();
+                let ctx = self.ctx.clone();
+                // 订阅一直占着任务，不能占着 permit，否则订阅多了之后连 Unsubscribe 都读不到
+                let permit = (!is_subscribe).then_some(permit);
+                tasks.push(tokio::spawn(async move {
+                    let resps = service.process_request(cmd, &ctx);
+                    forward(id, resps, tx.clone()).await;
+                    if is_subscribe {
+                        let _ = tx.send((id, Arc::new(CommandResponse::stream_end()))).await;
+                    }
+                    drop(permit);
+                }));
+            } else if is_subscribe {
+                // 订阅消息一直转发，直到取消订阅或连接断开
+                let resps = self.service.process_request(cmd, &self.ctx);
+                tasks.push(tokio::spawn(forward(id, resps, tx)));
+            } else {
+                let resps = self.service.process_request(cmd, &self.ctx);
+                forward(id, resps, tx).await;
+            }
+            tasks.retain(|handle| !handle.is_finished());
+        }
+
+        // 连接断开：停止转发，等转发任务退出、订阅从 MsgBus 里删掉之后再返回
+        for handle in &tasks {
+            handle.abort();
+        }
+        for handle in tasks {
+            let _ = handle.await;
+        }
+        drop(tx);
+        match writer.await {
+            Ok(res) => res,
+            Err(e) => Err(KvError::Internal(e.to_string())),
+        }
+    }
+
+    // Handshaking => Ready：收到合法的 Hello，或者不要求握手时收到普通命令（返回这个命令）
+    // Handshaking => Closed：Hello 不合法，或者要求握手时收到普通命令、等 Hello 超时
+    async fn handshake(&mut self) -> Result<Option<CommandRequest>, KvError> {
+        let first = if self.handshake.require_hello {
+            let timeout = self.handshake.timeout;
+            match tokio::time::timeout(timeout, self.inner.next()).await {
+                Ok(first) => first,
+                Err(_) => {
+                    self.state = ConnectionState::Closed;
+                    let _ = self.inner.close().await;
+                    return Err(KvError::Timeout(format!("no Hello in {:?}", timeout)));
+                }
+            }
+        } else {
+            self.inner.next().await
+        };
+        let cmd = match first {
+            Some(Ok(cmd)) => cmd,
+            // 对方断开连接
+            Some(Err(e @ KvError::IoError(_))) => {
+                self.state = ConnectionState::Closed;
+                return Err(e);
+            }
+            // 比如第一个请求就太大：和握手之后一样，告诉客户端原因之后关闭连接
+            Some(Err(e)) => {
+                warn!("invalid first frame: {:?}", e);
+                return self.refuse(0, e).await;
+            }
+            None => {
+                self.state = ConnectionState::Closed;
+                return Ok(None);
+            }
+        };
+        let id = cmd.id;
+        match cmd.request_data {
+            Some(RequestData::Hello(hello)) => match self.handshake.negotiate(&hello) {
+                Ok((welcome, user)) => {
+                    let compression = welcome.compression();
+                    let checksum = welcome.checksum;
+                    let resp = CommandResponse {
+                        status: 200,
+                        id,
+                        welcome: Some(welcome),
+                        ..Default::default()
+                    };
+                    self.inner.send(resp).await?;
+                    // 之后的 response 都使用协商好的压缩算法和校验，不超过客户端能接收的大小
+                    let mut config = FrameConfig {
+                        compression,
+                        checksum,
+                        ..*self.inner.frame_config()
+                    };
+                    if hello.max_frame_size > 0 {
+                        config.max_send_frame = hello.max_frame_size as _;
+                    }
+                    self.inner.set_frame_config(config);
+                    if user.is_some() {
+                        Arc::make_mut(&mut self.ctx).user = user;
+                    }
+                    self.state = ConnectionState::Ready;
+                    Ok(None)
+                }
+                Err(e) => self.refuse(id, e).await,
+            },
+            request_data if !self.handshake.require_hello => {
+                self.state = ConnectionState::Ready;
+                Ok(Some(CommandRequest { request_data, id }))
+            }
+            _ => {
+                let e = KvError::InvalidCommand("Hello is required before any command".into());
+                self.refuse(id, e).await
+            }
+        }
+    }
+
+    // 告诉客户端为什么被拒绝，然后关闭连接
+    async fn refuse(&mut self, id: u64, e: KvError) -> Result<Option<CommandRequest>, KvError> {
+        let mut resp: CommandResponse = e.into();
+        resp.id = id;
+        self.state = ConnectionState::Closed;
+        self.inner.send(resp).await?;
+        self.inner.close().await?;
+        Ok(None)
+    }
+}
+
+// 超过客户端 max_frame_size 的 response 换成 413，连接还可以继续使用；
+// SplitSink 把编码推迟到下一次 poll，所以要在 feed 之前检查
+fn stamp((id, resp): (u64, Arc<CommandResponse>), max_send_frame: usize) -> CommandResponse {
+    let mut resp = if resp.encoded_len() > max_send_frame {
+        KvError::FrameError.into()
+    } else {
+        Arc::unwrap_or_clone(resp)
+    };
+    resp.id = id;
+    resp
+}
+
+// 把一个请求的所有 response 交给 writer 任务
+async fn forward(
+    id: u64,
+    mut resps: StreamingResponse,
+    tx: mpsc::Sender<(u64, Arc<CommandResponse>)>,
+) {
+    while let Some(resp) = resps.next().await {
+        if tx.send((id, resp)).await.is_err() {
+            break;
         }
-        Ok(())
     }
 }
 
@@ -50,14 +320,81 @@ where
         }
     }
 
+    /// 设置请求的压缩算法、级别和阈值
+    pub fn with_frame_config(mut self, config: FrameConfig) -> Self {
+        self.inner = self.inner.with_frame_config(config);
+        self
+    }
+
+    /// 还没写出的请求超过 high_water 字节时，发送要等它们写出去
+    pub fn with_high_water_mark(mut self, high_water: usize) -> Self {
+        self.inner = self.inner.with_high_water_mark(high_water);
+        self
+    }
+
+    /// 连接建立之后第一个发送，之后的请求使用协商好的压缩算法和校验；
+    /// 收到的帧不能超过 hello.max_frame_size，发送的帧不能超过 welcome.max_frame_size
+    pub async fn handshake(&mut self, hello: Hello) -> Result<Welcome, KvError> {
+        let max_frame = hello.max_frame_size as usize;
+        let resp = self.execute(CommandRequest::new_hello(hello)).await?;
+        match resp.welcome {
+            Some(welcome) if resp.status == 200 => {
+                let mut config = FrameConfig {
+                    compression: welcome.compression(),
+                    checksum: welcome.checksum,
+                    ..*self.inner.frame_config()
+                };
+                if max_frame > 0 {
+                    config.max_frame = max_frame;
+                }
+                if welcome.max_frame_size > 0 {
+                    config.max_send_frame = welcome.max_frame_size as _;
+                }
+                self.inner.set_frame_config(config);
+                Ok(welcome)
+            }
+            _ => Err(KvError::Internal(format!(
+                "handshake failed: {} {}",
+                resp.status, resp.message
+            ))),
+        }
+    }
+
+    // 握手之后交给 PipelinedClient 等其它客户端继续使用
+    pub(crate) fn into_inner(self) -> ProstStream<S, CommandResponse, CommandRequest> {
+        self.inner
+    }
+
     pub async fn execute(&mut self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
         self.inner.send(cmd).await?;
         let raw_resp = self.inner.next().await;
         match raw_resp {
             Some(v) => v,
-            None => Err(KvError::Internal("no response".to_string())),
+            None => Err(KvError::ConnectionClosed),
         }
     }
+
+    // subscribe 之后这个连接上只会收到推送，所以直接消费 self
+    // 第一个 response 是 subscription id，之后的每个 response 都是一条推送
+    pub async fn execute_streaming(mut self, cmd: CommandRequest) -> Result<StreamResult, KvError>
+    where
+        S: 'static,
+    {
+        self.inner.send(cmd).await?;
+        let first = match self.inner.next().await {
+            Some(v) => v?,
+            None => return Err(KvError::ConnectionClosed),
+        };
+        if first.status != 200 {
+            return Err(KvError::Internal(format!(
+                "subscribe failed: {} {}",
+                first.status, first.message
+            )));
+        }
+        Ok(Box::pin(
+            futures::stream::once(async { Ok(first) }).chain(self.inner),
+        ))
+    }
 }
 
 pub mod utils {
@@ -81,8 +418,9 @@ pub mod utils {
             _cx: &mut std::task::Context<'_>,
             buf: &mut tokio::io::ReadBuf<'_>,
         ) -> std::task::Poll<std::io::Result<()>> {
-            let len = buf.capacity();
-            let data = self.get_mut().buf.split_to(len);
+            let this = self.get_mut();
+            let len = buf.remaining().min(this.buf.len());
+            let data = this.buf.split_to(len);
             buf.put_slice(&data); // struct data => buf_in
             std::task::Poll::Ready(Ok(()))
         }
@@ -119,10 +457,10 @@ mod tests {
 
     use anyhow::Result;
     use bytes::Bytes;
-    use std::net::SocketAddr;
+    use std::{convert::TryInto, net::SocketAddr};
     use tokio::net::{TcpListener, TcpStream};
 
-    use crate::{MemTable, ServiceInner, Value, assert_res_ok};
+    use crate::{Kvpair, MemTable, ServiceInner, Value, assert_res_ok};
 
     use super::*;
 
@@ -143,6 +481,72 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn client_server_pub_sub_should_work() -> Result<()> {
+        let addr = start_shared_server().await?;
+        let stream = TcpStream::connect(addr).await?;
+        let client = ClientStream::new(stream);
+        let mut sub = client
+            .execute_streaming(CommandRequest::new_subscribe("lobby"))
+            .await?;
+        let id: i64 = (&sub.next().await.unwrap()?.values[0]).try_into()?;
+        assert!(id > 0);
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ClientStream::new(stream);
+        let v: Value = "hello".into();
+        let resp = client
+            .execute(CommandRequest::new_publish("lobby", vec![v.clone()]))
+            .await?;
+        assert_res_ok(&resp, &[], &[]);
+        let msg = sub.next().await.unwrap()?;
+        assert_res_ok(&msg, std::slice::from_ref(&v), &[]);
+
+        // 别的连接不能取消这个订阅
+        let resp = client
+            .execute(CommandRequest::new_unsubscribe("lobby", id as _))
+            .await?;
+        assert_eq!(resp.status, 404);
+        let resp = client
+            .execute(CommandRequest::new_publish("lobby", vec![v.clone()]))
+            .await?;
+        assert_res_ok(&resp, &[], &[]);
+        let msg = sub.next().await.unwrap()?;
+        assert_res_ok(&msg, &[v], &[]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn subscriptions_should_be_removed_when_client_disconnects() -> Result<()> {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let addr = start_server_with(service.clone()).await?;
+        let stream = TcpStream::connect(addr).await?;
+        let client = ClientStream::new(stream);
+        let mut sub = client
+            .execute_streaming(CommandRequest::new_subscribe("lobby"))
+            .await?;
+        sub.next().await.unwrap()?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ClientStream::new(stream);
+        let resp = client.execute(CommandRequest::new_topics()).await?;
+        assert_res_ok(&resp, &[0.into()], &[Kvpair::new("topic:lobby", 1.into())]);
+
+        drop(sub);
+        let mut cleaned = false;
+        for _ in 0..100 {
+            if service.topic_stats().is_empty() {
+                cleaned = true;
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(cleaned);
+        let resp = client.execute(CommandRequest::new_topics()).await?;
+        assert_res_ok(&resp, &[], &[]);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn client_server_compression_should_work() -> Result<()> {
         let addr = start_server().await?;
@@ -158,6 +562,285 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn small_high_water_mark_should_still_deliver_large_frames() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service: Service = ServiceInner::new(MemTable::new()).into();
+            ServerStream::new(stream, service)
+                .with_high_water_mark(1)
+                .process()
+                .await
+        });
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ClientStream::new(stream).with_high_water_mark(1);
+        // 压缩不了的数据，写出去的帧比 high water mark 大得多
+        let data: Vec<u8> = (0..256 * 1024u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        let v: Value = Bytes::from(data).into();
+        let resp = client
+            .execute(CommandRequest::new_hset("t1", "k1", v.clone()))
+            .await?;
+        assert_res_ok(&resp, &[Value::default()], &[]);
+        let resp = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_res_ok(&resp, &[v], &[]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_and_server_can_use_different_compression() -> Result<()> {
+        let addr = start_server().await?;
+        let stream = TcpStream::connect(addr).await?;
+        let config = FrameConfig {
+            compression: crate::CompressionAlgorithm::Zstd,
+            level: Some(1),
+            ..Default::default()
+        };
+        let mut client = ClientStream::new(stream).with_frame_config(config);
+        let v: Value = Bytes::from(vec![0u8; 16384]).into();
+        let cmd = CommandRequest::new_hset("t2", "k2", v.clone());
+        client.execute(cmd).await?;
+        let resp = client.execute(CommandRequest::new_hget("t2", "k2")).await?;
+        assert_res_ok(&resp, &[v], &[]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handshake_should_negotiate_compression_and_user() -> Result<()> {
+        let config = HandshakeConfig {
+            require_hello: true,
+            authenticator: Some(check_password),
+            ..Default::default()
+        };
+        let addr = start_handshake_server(config).await?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ClientStream::new(stream);
+        let hello = Hello {
+            compressions: vec![crate::CompressionAlgorithm::Lz4 as _],
+            checksum: true,
+            ..Hello::new().with_credentials("alice", "secret")
+        };
+        let welcome = client.handshake(hello).await?;
+        assert!(welcome.checksum && client.inner.frame_config().checksum);
+        assert_eq!(welcome.version, PROTOCOL_VERSION);
+        assert_eq!(welcome.compression(), crate::CompressionAlgorithm::Lz4);
+        assert_eq!(
+            client.inner.frame_config().compression,
+            crate::CompressionAlgorithm::Lz4
+        );
+
+        // 认证过的用户名会传给 req hook
+        let v: Value = Bytes::from(vec![0u8; 16384]).into();
+        let resp = client
+            .execute(CommandRequest::new_hset("t1", "k1", v.clone()))
+            .await?;
+        assert_eq!(resp.status, 200);
+        let resp = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_res_ok(&resp, &[v], &[]);
+
+        // 握手只能进行一次
+        let resp = client
+            .execute(CommandRequest::new_hello(Hello::new()))
+            .await?;
+        assert_eq!(resp.status, 400);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handshake_should_refuse_incompatible_clients() -> Result<()> {
+        let config = HandshakeConfig {
+            require_hello: true,
+            authenticator: Some(check_password),
+            ..Default::default()
+        };
+        let addr = start_handshake_server(config).await?;
+
+        let hello = Hello {
+            version: PROTOCOL_VERSION + 1,
+            ..Hello::new().with_credentials("alice", "secret")
+        };
+        let resp = refused(addr, CommandRequest::new_hello(hello)).await?;
+        assert_eq!(resp.status, 426);
+
+        let hello = Hello::new().with_credentials("alice", "wrong");
+        let resp = refused(addr, CommandRequest::new_hello(hello)).await?;
+        assert_eq!(resp.status, 401);
+
+        let resp = refused(addr, CommandRequest::new_hget("t1", "k1")).await?;
+        assert_eq!(resp.status, 400);
+        assert!(resp.message.contains("Hello is required"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn legacy_clients_should_work_without_hello() -> Result<()> {
+        let addr = start_handshake_server(HandshakeConfig::default()).await?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ClientStream::new(stream);
+        // 没有握手，所以没有用户名
+        let resp = client
+            .execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await?;
+        assert_eq!(resp.status, 403);
+        let resp = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_eq!(resp.status, 404);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn oversized_frames_should_be_rejected_and_connection_closed() -> Result<()> {
+        let config = HandshakeConfig {
+            max_frame_size: 1024,
+            ..Default::default()
+        };
+        let addr = start_handshake_server(config).await?;
+        for compression in [
+            crate::CompressionAlgorithm::None,
+            crate::CompressionAlgorithm::Gzip,
+        ] {
+            let stream = TcpStream::connect(addr).await?;
+            let config = FrameConfig {
+                compression,
+                ..Default::default()
+            };
+            let mut client = ClientStream::new(stream).with_frame_config(config);
+            let resp = client.execute(CommandRequest::new_ping()).await?;
+            assert_eq!(resp.status, 200);
+
+            // 不压缩时超过帧的大小，压缩时超过解压之后的大小
+            let key = "k".repeat(4096);
+            let resp = client.execute(CommandRequest::new_hget("t1", key)).await?;
+            assert_eq!(resp.status, 413);
+            assert!(client.execute(CommandRequest::new_ping()).await.is_err());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn oversized_first_request_should_get_413() -> Result<()> {
+        let config = HandshakeConfig {
+            max_frame_size: 1024,
+            ..Default::default()
+        };
+        assert!(!config.require_hello);
+        let addr = start_handshake_server(config).await?;
+        let mut client = ClientStream::new(TcpStream::connect(addr).await?);
+        let key = "k".repeat(4096);
+        let resp = client.execute(CommandRequest::new_hget("t1", key)).await?;
+        assert_eq!(resp.status, 413);
+        assert!(client.execute(CommandRequest::new_ping()).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_should_respect_client_max_frame_size() -> Result<()> {
+        let addr = start_server().await?;
+        let mut client = ClientStream::new(TcpStream::connect(addr).await?);
+        let hello = Hello {
+            max_frame_size: 1024,
+            ..Hello::new()
+        };
+        client.handshake(hello).await?;
+        assert_eq!(client.inner.frame_config().max_frame, 1024);
+
+        let v: Value = "v".repeat(4096).into();
+        let resp = client
+            .execute(CommandRequest::new_hset("t1", "k1", v))
+            .await?;
+        assert_eq!(resp.status, 200);
+        // response 太大时服务端返回 413，连接还可以继续使用
+        let resp = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_eq!(resp.status, 413);
+        let resp = client.execute(CommandRequest::new_ping()).await?;
+        assert_eq!(resp.status, 200);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_should_respect_server_max_frame_size() -> Result<()> {
+        let config = HandshakeConfig {
+            max_frame_size: 1024,
+            ..Default::default()
+        };
+        let addr = start_handshake_server(config).await?;
+        let mut client = ClientStream::new(TcpStream::connect(addr).await?);
+        let welcome = client.handshake(Hello::new()).await?;
+        assert_eq!(welcome.max_frame_size, 1024);
+        assert_eq!(client.inner.frame_config().max_send_frame, 1024);
+
+        // 请求太大时不会发出去，连接还可以继续使用
+        let key = "k".repeat(4096);
+        let res = client.execute(CommandRequest::new_hget("t1", key)).await;
+        assert!(matches!(res, Err(KvError::FrameError)));
+        let resp = client.execute(CommandRequest::new_ping()).await?;
+        assert_eq!(resp.status, 200);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_should_close_connection_without_hello_in_time() -> Result<()> {
+        let config = HandshakeConfig {
+            require_hello: true,
+            timeout: std::time::Duration::from_millis(50),
+            ..Default::default()
+        };
+        let addr = start_handshake_server(config).await?;
+        let mut client = ClientStream::new(TcpStream::connect(addr).await?);
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert!(client.handshake(Hello::new()).await.is_err());
+
+        // 及时握手的连接不受影响
+        let mut client = ClientStream::new(TcpStream::connect(addr).await?);
+        client.handshake(Hello::new()).await?;
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let resp = client.execute(CommandRequest::new_ping()).await?;
+        assert_eq!(resp.status, 200);
+        Ok(())
+    }
+
+    fn check_password(creds: &crate::Credentials) -> Result<String, KvError> {
+        match creds.password.as_str() {
+            "secret" => Ok(creds.username.clone()),
+            _ => Err(KvError::Unauthenticated(creds.username.clone())),
+        }
+    }
+
+    // 服务端返回错误之后会关闭连接
+    async fn refused(addr: SocketAddr, cmd: CommandRequest) -> Result<CommandResponse> {
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ClientStream::new(stream);
+        let resp = client.execute(cmd).await?;
+        let res = client.execute(CommandRequest::new_ping()).await;
+        assert!(res.is_err());
+        Ok(resp)
+    }
+
+    // 只有登录的用户可以写入
+    async fn start_handshake_server(config: HandshakeConfig) -> Result<SocketAddr> {
+        let service: Service = ServiceInner::new(MemTable::new())
+            .add_req_hook(|cmd_req, ctx| match cmd_req.request_data {
+                Some(RequestData::Hset(_)) if ctx.user.is_none() => {
+                    Err(KvError::PermissionDenied("login required".into()))
+                }
+                _ => Ok(()),
+            })
+            .into();
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let server =
+                    ServerStream::new(stream, service.clone()).with_handshake(config.clone());
+                tokio::spawn(server.process());
+            }
+        });
+        Ok(addr)
+    }
+
     async fn start_server() -> Result<SocketAddr> {
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = listener.local_addr().unwrap();
@@ -171,4 +854,23 @@ mod tests {
         });
         Ok(addr)
     }
+
+    // 所有连接共享同一个 service，才能跨连接 publish / subscribe
+    async fn start_shared_server() -> Result<SocketAddr> {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        start_server_with(service).await
+    }
+
+    async fn start_server_with(service: Service) -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let server = ServerStream::new(stream, service.clone());
+                tokio::spawn(server.process());
+            }
+        });
+        Ok(addr)
+    }
 }
@@ -0,0 +1,196 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt};
+use yamux::{Config, Mode};
+
+use crate::{
+    ClientStream, HandshakeConfig, Hello, KvError, RequestContext, ServerStream, Service,
+    YamuxHandle, spawn_yamux_driver,
+};
+
+/// yamux 子流，可以直接交给 ServerStream / ClientStream
+pub type YamuxStream = Compat<yamux::Stream>;
+
+/// 服务端：在一个 TCP 或 TLS 连接上跑 yamux，每个入站子流一个 ServerStream，
+/// 所有子流共享同一个 Service 和连接的 RequestContext
+pub struct YamuxServer {
+    service: Service,
+    ctx: RequestContext,
+    handshake: HandshakeConfig,
+}
+
+/// 客户端：在一个连接上为每个逻辑通道打开一个新的 ClientStream，
+/// 比如一个子流用来订阅，另一个子流用来执行普通命令
+pub struct YamuxClient {
+    handle: YamuxHandle,
+    // 每个新打开的子流先握手
+    hello: Option<Hello>,
+}
+
+impl YamuxServer {
+    pub fn new(service: Service) -> Self {
+        Self {
+            service,
+            ctx: RequestContext::default(),
+            handshake: HandshakeConfig::default(),
+        }
+    }
+
+    /// 每个子流都按这个配置握手
+    pub fn with_handshake(mut self, config: HandshakeConfig) -> Self {
+        self.handshake = config;
+        self
+    }
+
+    /// 设置连接的请求上下文，比如 mTLS 验证过的客户端身份
+    pub fn with_context(mut self, ctx: RequestContext) -> Self {
+        self.ctx = ctx;
+        self
+    }
+
+    /// 处理一个连接上的所有子流，连接断开之后返回
+    pub async fn serve<S>(self, stream: S)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut handle = spawn_yamux_driver(stream, Mode::Server, Config::default());
+        while let Some(sub) = handle.next_incoming().await {
+            let server = ServerStream::new(sub.compat(), self.service.clone())
+                .with_context(self.ctx.clone())
+                .with_handshake(self.handshake.clone());
+            tokio::spawn(server.process());
+        }
+    }
+}
+
+impl YamuxClient {
+    pub fn new<S>(stream: S) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        Self {
+            handle: spawn_yamux_driver(stream, Mode::Client, Config::default()),
+            hello: None,
+        }
+    }
+
+    /// 之后打开的每个子流都先用 hello 握手
+    pub fn with_hello(mut self, hello: Hello) -> Self {
+        self.hello = Some(hello);
+        self
+    }
+
+    /// 打开一个新的子流
+    pub async fn open_stream(&self) -> Result<ClientStream<YamuxStream>, KvError> {
+        let stream = self.handle.open_outbound().await?;
+        let mut client = ClientStream::new(stream.compat());
+        if let Some(hello) = &self.hello {
+            client.handshake(hello.clone()).await?;
+        }
+        Ok(client)
+    }
+
+    /// 关闭底层连接，已经打开的子流都会断开
+    pub fn close(&self) {
+        self.handle.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        CommandRequest, MemTable, ServiceInner, TlsClientConnector, TlsServerAcceptor, Value,
+        assert_res_ok, network::tls::tests::DOMAIN, network::tls::tests::generate_server_certs,
+    };
+    use anyhow::Result;
+    use futures::StreamExt;
+    use std::{net::SocketAddr, time::Duration};
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn start_yamux_server(
+        acceptor: Option<TlsServerAcceptor>,
+        config: HandshakeConfig,
+    ) -> Result<SocketAddr> {
+        let service: Service = ServiceInner::new(MemTable::new()).build();
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let server = YamuxServer::new(service.clone()).with_handshake(config.clone());
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    match acceptor {
+                        Some(acceptor) => {
+                            server.serve(acceptor.accept(stream).await.unwrap()).await
+                        }
+                        None => server.serve(stream).await,
+                    }
+                });
+            }
+        });
+        Ok(addr)
+    }
+
+    // 订阅和普通命令共用一个连接
+    async fn subscription_should_share_connection(client: YamuxClient) -> Result<()> {
+        let mut sub = client
+            .open_stream()
+            .await?
+            .execute_streaming(CommandRequest::new_subscribe("lobby"))
+            .await?;
+        sub.next().await.unwrap()?;
+
+        let mut cmd = client.open_stream().await?;
+        let resp = cmd
+            .execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await?;
+        assert_res_ok(&resp, &[Value::default()], &[]);
+        let v: Value = "hello".into();
+        let resp = cmd
+            .execute(CommandRequest::new_publish("lobby", vec![v.clone()]))
+            .await?;
+        assert_res_ok(&resp, &[], &[]);
+
+        let msg = tokio::time::timeout(Duration::from_secs(1), sub.next())
+            .await?
+            .unwrap()?;
+        assert_res_ok(&msg, &[v], &[]);
+
+        // 关闭连接之后所有子流都结束
+        client.close();
+        let end = tokio::time::timeout(Duration::from_secs(1), sub.next()).await?;
+        assert!(!matches!(end, Some(Ok(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn yamux_over_tcp_should_work() -> Result<()> {
+        let addr = start_yamux_server(None, HandshakeConfig::default()).await?;
+        let stream = TcpStream::connect(addr).await?;
+        subscription_should_share_connection(YamuxClient::new(stream)).await
+    }
+
+    #[tokio::test]
+    async fn yamux_over_tls_should_work() -> Result<()> {
+        let (ca, cert, key) = generate_server_certs();
+        let acceptor = TlsServerAcceptor::new(&cert, &key, None)?;
+        let addr = start_yamux_server(Some(acceptor), HandshakeConfig::default()).await?;
+
+        let connector = TlsClientConnector::new(DOMAIN, None, Some(&ca))?;
+        let stream = connector.connect(TcpStream::connect(addr).await?).await?;
+        subscription_should_share_connection(YamuxClient::new(stream)).await
+    }
+
+    #[tokio::test]
+    async fn yamux_substreams_should_handshake() -> Result<()> {
+        let config = HandshakeConfig {
+            require_hello: true,
+            ..Default::default()
+        };
+        let addr = start_yamux_server(None, config).await?;
+        let stream = TcpStream::connect(addr).await?;
+        let client = YamuxClient::new(stream).with_hello(Hello::new());
+        subscription_should_share_connection(client).await
+    }
+}
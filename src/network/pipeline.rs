@@ -0,0 +1,495 @@
+use futures::{SinkExt, StreamExt};
+use prost::Message;
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{mpsc, oneshot},
+};
+
+use crate::{
+    ClientStream, CommandRequest, CommandResponse, Hello, KvError, ProstStream, RequestData,
+    StreamResult, Welcome, network::frame::MAX_FRAME,
+};
+
+// 等待写出的请求数量
+const REQUEST_QUEUE_CAPACITY: usize = 128;
+// 每个订阅在客户端缓存的推送数量
+const STREAM_CAPACITY: usize = 128;
+
+// 等待 response 的请求
+enum Pending {
+    Once(oneshot::Sender<Result<CommandResponse, KvError>>),
+    Stream(StreamSub),
+}
+
+// 订阅的推送先放进 tx，调用方从对应的 rx 里读
+struct StreamSub {
+    tx: mpsc::Sender<Result<CommandResponse, KvError>>,
+    // 调用方读得太慢、缓存满了的时候，通过它告诉调用方订阅为什么结束
+    overflow: Option<oneshot::Sender<KvError>>,
+    // 订阅命令，调用方不再接收时用它生成取消订阅的命令
+    cmd: Option<RequestData>,
+    // 服务端返回的 subscription id，第一个 response 里才有
+    sub_id: Option<u32>,
+}
+
+// 连接断开之后为 None
+type PendingMap = Arc<Mutex<Option<HashMap<u64, Pending>>>>;
+type RequestSender = mpsc::Sender<(CommandRequest, Pending)>;
+
+/// 可以 clone 的客户端，多个任务同时在一个连接上发请求，按 id 匹配 response
+#[derive(Clone)]
+pub struct PipelinedClient {
+    tx: RequestSender,
+    next_id: Arc<AtomicU64>,
+    // 写出失败会断开连接，所以太大的请求在入队之前就拒绝
+    max_send_frame: usize,
+}
+
+impl PipelinedClient {
+    /// 启动读写两个后台任务，所有 clone 都 drop 之后连接关闭，还没结束的订阅也随之结束
+    pub fn new<S>(stream: S) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        Self::from_stream(ProstStream::new(stream))
+    }
+
+    /// 先用 Hello 握手，之后的请求使用协商好的压缩算法、校验和帧大小
+    pub async fn connect<S>(stream: S, hello: Hello) -> Result<(Self, Welcome), KvError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut client = ClientStream::new(stream);
+        let welcome = client.handshake(hello).await?;
+        Ok((Self::from_stream(client.into_inner()), welcome))
+    }
+
+    fn from_stream<S>(stream: ProstStream<S, CommandResponse, CommandRequest>) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let max_send_frame = stream.frame_config().max_send_frame.min(MAX_FRAME);
+        let (mut sink, mut stream) = stream.split();
+        let (tx, mut rx) = mpsc::channel::<(CommandRequest, Pending)>(REQUEST_QUEUE_CAPACITY);
+        let pending: PendingMap = Arc::new(Mutex::new(Some(HashMap::new())));
+        let next_id = Arc::new(AtomicU64::new(1));
+
+        // reader 只持有弱引用，否则所有 clone 都 drop 之后 writer 也等不到 rx 结束
+        let reader_pending = pending.clone();
+        let weak_tx = tx.downgrade();
+        let reader_next_id = next_id.clone();
+        let reader = tokio::spawn(async move {
+            while let Some(Ok(resp)) = stream.next().await {
+                let id = resp.id;
+                match remove(&reader_pending, id) {
+                    Some(Pending::Once(tx)) => {
+                        let _ = tx.send(Ok(resp));
+                    }
+                    // 订阅一直保留，直到服务端发来结束标记，或者调用方不再接收
+                    Some(Pending::Stream(_)) if resp.stream_end => {}
+                    Some(Pending::Stream(mut sub)) => {
+                        if sub.sub_id.is_none() && resp.status == 200 {
+                            sub.sub_id = i64::try_from(&resp).ok().map(|v| v as u32);
+                        }
+                        // 不能等调用方，否则一个读得慢的订阅会卡住这个连接上所有的 response
+                        match sub.tx.try_send(Ok(resp)) {
+                            Ok(()) => {
+                                if let Some(map) = reader_pending.lock().unwrap().as_mut() {
+                                    map.insert(id, Pending::Stream(sub));
+                                }
+                            }
+                            Err(e) => {
+                                if let mpsc::error::TrySendError::Full(_) = e
+                                    && let Some(overflow) = sub.overflow.take()
+                                {
+                                    let sub_id = sub.sub_id.unwrap_or_default();
+                                    let _ = overflow.send(KvError::SlowSubscriber(sub_id));
+                                }
+                                if let Some(tx) = weak_tx.upgrade() {
+                                    cancel(&sub, &tx, &reader_next_id);
+                                }
+                            }
+                        }
+                    }
+                    None => {}
+                }
+            }
+            // 连接断开：drop 掉所有等待中的 sender，调用方会收到错误
+            reader_pending.lock().unwrap().take();
+        });
+
+        tokio::spawn(async move {
+            while let Some((cmd, p)) = rx.recv().await {
+                let id = cmd.id;
+                // 先登记再发送，避免 response 比登记先到
+                match pending.lock().unwrap().as_mut() {
+                    Some(map) => map.insert(id, p),
+                    None => {
+                        p.fail(closed());
+                        continue;
+                    }
+                };
+                if let Err(e) = sink.send(cmd).await {
+                    if let Some(p) = remove(&pending, id) {
+                        p.fail(e);
+                    }
+                    break;
+                }
+            }
+            // 所有 clone 都 drop 了，或者写出失败：关掉连接，不再读 response
+            let _ = sink.close().await;
+            reader.abort();
+            pending.lock().unwrap().take();
+        });
+
+        Self {
+            tx,
+            next_id,
+            max_send_frame,
+        }
+    }
+
+    pub async fn execute(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let (tx, rx) = oneshot::channel();
+        self.send(cmd, Pending::Once(tx)).await?;
+        rx.await.map_err(|_| closed())?
+    }
+
+    /// 用于 Subscribe / Psubscribe / Watch：第一个 response 是 subscription id，
+    /// 之后的每个 response 都是一条推送，取消订阅之后 stream 结束；
+    /// 读得太慢、缓存的推送超过 STREAM_CAPACITY 时，最后一项是 SlowSubscriber 错误；
+    /// drop 掉返回的 stream 时会在服务端取消这个订阅
+    pub async fn execute_streaming(&self, cmd: CommandRequest) -> Result<StreamResult, KvError> {
+        let (tx, mut rx) = mpsc::channel(STREAM_CAPACITY);
+        let (overflow_tx, overflow_rx) = oneshot::channel();
+        let sub = StreamSub {
+            tx,
+            overflow: Some(overflow_tx),
+            cmd: cmd.request_data.clone(),
+            sub_id: None,
+        };
+        self.send(cmd, Pending::Stream(sub)).await?;
+        let first = rx.recv().await.ok_or_else(closed)??;
+        if first.status != 200 {
+            return Err(KvError::Internal(format!(
+                "subscribe failed: {} {}",
+                first.status, first.message
+            )));
+        }
+        let rest = futures::stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|resp| (resp, rx))
+        });
+        // 推送结束之后，如果是因为读得太慢，再给调用方一个错误
+        let overflow =
+            futures::stream::once(overflow_rx).filter_map(|res| async { res.ok().map(Err) });
+        Ok(Box::pin(
+            futures::stream::once(async { Ok(first) })
+                .chain(rest)
+                .chain(overflow),
+        ))
+    }
+
+    async fn send(&self, cmd: CommandRequest, p: Pending) -> Result<(), KvError> {
+        let cmd = assign_id(cmd, &self.next_id);
+        if cmd.encoded_len() > self.max_send_frame {
+            return Err(KvError::FrameError);
+        }
+        self.tx.send((cmd, p)).await.map_err(|_| closed())
+    }
+}
+
+impl Pending {
+    fn fail(self, e: KvError) {
+        match self {
+            Pending::Once(tx) => {
+                let _ = tx.send(Err(e));
+            }
+            Pending::Stream(sub) => {
+                let _ = sub.tx.try_send(Err(e));
+            }
+        }
+    }
+}
+
+fn assign_id(mut cmd: CommandRequest, next_id: &AtomicU64) -> CommandRequest {
+    cmd.id = next_id.fetch_add(1, Ordering::Relaxed);
+    cmd
+}
+
+// 调用方不再接收推送：让服务端取消这个订阅，response 不需要等
+fn cancel(sub: &StreamSub, tx: &RequestSender, next_id: &AtomicU64) {
+    let Some(sub_id) = sub.sub_id else {
+        return;
+    };
+    let cmd = match &sub.cmd {
+        Some(RequestData::Subscribe(v)) => CommandRequest::new_unsubscribe(&v.topic, sub_id),
+        Some(RequestData::Psubscribe(v)) => CommandRequest::new_punsubscribe(&v.pattern, sub_id),
+        Some(RequestData::Watch(v)) => CommandRequest::new_unwatch(&v.table, &v.key, sub_id),
+        _ => return,
+    };
+    let cmd = assign_id(cmd, next_id);
+    let tx = tx.clone();
+    // 不能在 reader 里等 writer 的队列，否则两边可能互相等待
+    tokio::spawn(async move {
+        let (once, _) = oneshot::channel();
+        let _ = tx.send((cmd, Pending::Once(once))).await;
+    });
+}
+
+fn remove(pending: &PendingMap, id: u64) -> Option<Pending> {
+    pending.lock().unwrap().as_mut()?.remove(&id)
+}
+
+fn closed() -> KvError {
+    KvError::ConnectionClosed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        HandshakeConfig, MemTable, RequestContext, ServerStream, Service, ServiceInner, Value,
+        assert_res_ok,
+    };
+    use anyhow::Result;
+    use std::{net::SocketAddr, time::Duration};
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    // 比服务端单个连接同时处理的请求数量多
+    const SUBSCRIPTIONS: usize = 200;
+
+    async fn start_server() -> Result<SocketAddr> {
+        start_handshake_server(HandshakeConfig::default()).await
+    }
+
+    async fn start_handshake_server(config: HandshakeConfig) -> Result<SocketAddr> {
+        let service: Service = ServiceInner::new(MemTable::new()).build();
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let server =
+                    ServerStream::new(stream, service.clone()).with_handshake(config.clone());
+                tokio::spawn(server.process());
+            }
+        });
+        Ok(addr)
+    }
+
+    #[tokio::test]
+    async fn connect_should_handshake_and_respect_max_frame_size() -> Result<()> {
+        let config = HandshakeConfig {
+            require_hello: true,
+            max_frame_size: 1024,
+            ..Default::default()
+        };
+        let addr = start_handshake_server(config).await?;
+        let (client, welcome) =
+            PipelinedClient::connect(TcpStream::connect(addr).await?, Hello::new()).await?;
+        assert_eq!(welcome.max_frame_size, 1024);
+
+        // 太大的请求直接返回错误，不会断开连接
+        let key = "k".repeat(4096);
+        let res = client.execute(CommandRequest::new_hget("t1", key)).await;
+        assert!(matches!(res, Err(KvError::FrameError)));
+        let resp = client.execute(CommandRequest::new_ping()).await?;
+        assert_eq!(resp.status, 200);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_should_share_one_connection() -> Result<()> {
+        let addr = start_server().await?;
+        let client = PipelinedClient::new(TcpStream::connect(addr).await?);
+
+        let tasks: Vec<_> = (0..50)
+            .map(|i| {
+                let client = client.clone();
+                tokio::spawn(async move {
+                    let key = format!("k{}", i);
+                    let cmd = CommandRequest::new_hset("t1", &key, i.into());
+                    client.execute(cmd).await?;
+                    client.execute(CommandRequest::new_hget("t1", &key)).await
+                })
+            })
+            .collect();
+        for (i, task) in tasks.into_iter().enumerate() {
+            let resp = task.await??;
+            assert_res_ok(&resp, &[(i as i64).into()], &[]);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn subscription_should_share_connection_with_commands() -> Result<()> {
+        let addr = start_server().await?;
+        let client = PipelinedClient::new(TcpStream::connect(addr).await?);
+
+        let mut sub = client
+            .execute_streaming(CommandRequest::new_subscribe("lobby"))
+            .await?;
+        sub.next().await.unwrap()?;
+
+        let v: Value = "hello".into();
+        let resp = client
+            .execute(CommandRequest::new_publish("lobby", vec![v.clone()]))
+            .await?;
+        assert_res_ok(&resp, &[], &[]);
+        let msg = tokio::time::timeout(Duration::from_secs(1), sub.next())
+            .await?
+            .unwrap()?;
+        assert_res_ok(&msg, &[v], &[]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn subscription_stream_should_end_after_unsubscribe() -> Result<()> {
+        let addr = start_server().await?;
+        let client = PipelinedClient::new(TcpStream::connect(addr).await?);
+
+        // 订阅不占用服务端处理请求的名额，订阅再多也能取消订阅
+        let mut subs = vec![];
+        for _ in 0..SUBSCRIPTIONS {
+            let mut sub = client
+                .execute_streaming(CommandRequest::new_subscribe("lobby"))
+                .await?;
+            let id: i64 = (&sub.next().await.unwrap()?).try_into()?;
+            subs.push((id, sub));
+        }
+        for (id, mut sub) in subs {
+            let resp = client
+                .execute(CommandRequest::new_unsubscribe("lobby", id as _))
+                .await?;
+            assert_res_ok(&resp, &[], &[]);
+            let end = tokio::time::timeout(Duration::from_secs(1), sub.next()).await?;
+            assert!(end.is_none());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_subscription_should_not_block_connection() -> Result<()> {
+        fn no_subscribe(cmd: &CommandRequest, _: &RequestContext) -> Result<(), KvError> {
+            match cmd.request_data {
+                Some(RequestData::Subscribe(_)) => Err(KvError::PermissionDenied("no".into())),
+                _ => Ok(()),
+            }
+        }
+        let service: Service = ServiceInner::new(MemTable::new())
+            .add_req_hook(no_subscribe)
+            .build();
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            ServerStream::new(stream, service).process().await
+        });
+        let client = PipelinedClient::new(TcpStream::connect(addr).await?);
+        for _ in 0..3 {
+            let res = client
+                .execute_streaming(CommandRequest::new_subscribe("lobby"))
+                .await;
+            assert!(res.is_err());
+        }
+        let resp = client.execute(CommandRequest::new_ping()).await?;
+        assert_res_ok(&resp, &["PONG".into()], &[]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connection_should_close_after_all_clones_dropped() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let client = PipelinedClient::new(TcpStream::connect(addr).await?);
+        let (mut stream, _) = listener.accept().await?;
+        let other = client.clone();
+        drop(client);
+        drop(other);
+
+        // 服务端读到 EOF
+        let mut buf = vec![];
+        let n =
+            tokio::time::timeout(Duration::from_secs(1), stream.read_to_end(&mut buf)).await??;
+        assert_eq!(n, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn slow_subscriber_should_not_block_other_responses() -> Result<()> {
+        let addr = start_server().await?;
+        let client = PipelinedClient::new(TcpStream::connect(addr).await?);
+        let mut sub = client
+            .execute_streaming(CommandRequest::new_subscribe("lobby"))
+            .await?;
+        sub.next().await.unwrap()?;
+
+        // 订阅一直不读，其它命令照常返回
+        for i in 0..STREAM_CAPACITY * 2 {
+            let cmd = CommandRequest::new_publish("lobby", vec![(i as i64).into()]);
+            let resp = tokio::time::timeout(Duration::from_secs(1), client.execute(cmd)).await??;
+            assert_res_ok(&resp, &[], &[]);
+        }
+
+        // 缓存的推送读完之后是 SlowSubscriber 错误，之后 stream 结束
+        let mut received = 0;
+        let last = loop {
+            match tokio::time::timeout(Duration::from_secs(1), sub.next()).await? {
+                Some(Ok(_)) => received += 1,
+                other => break other,
+            }
+        };
+        assert_eq!(received, STREAM_CAPACITY);
+        assert!(matches!(last, Some(Err(KvError::SlowSubscriber(_)))));
+        assert!(sub.next().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dropped_subscription_should_be_cancelled_on_server() -> Result<()> {
+        let addr = start_server().await?;
+        let client = PipelinedClient::new(TcpStream::connect(addr).await?);
+        let mut sub = client
+            .execute_streaming(CommandRequest::new_subscribe("lobby"))
+            .await?;
+        sub.next().await.unwrap()?;
+        drop(sub);
+
+        // 下一条推送到达时客户端发现没人接收，让服务端取消订阅
+        let cmd = CommandRequest::new_publish("lobby", vec![1.into()]);
+        client.execute(cmd).await?;
+        for _ in 0..100 {
+            let resp = client.execute(CommandRequest::new_topics()).await?;
+            if resp.pairs.is_empty() {
+                return Ok(());
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("subscription is not cancelled");
+    }
+
+    #[tokio::test]
+    async fn requests_should_fail_after_connection_closed() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        // 收到连接之后立刻关掉
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            drop(stream);
+        });
+        let client = PipelinedClient::new(TcpStream::connect(addr).await?);
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await;
+        assert!(res.is_err());
+        Ok(())
+    }
+}
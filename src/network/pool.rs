@@ -0,0 +1,394 @@
+use std::{
+    ops::Deref,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+use tokio::{
+    net::TcpStream,
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
+use tracing::warn;
+
+use crate::{ClientStream, CommandRequest, CommandResponse, Hello, KvError, StreamResult};
+
+/// 连接池的配置
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// 所有服务器加起来最多的连接数，包括借出去的和空闲的
+    pub max_size: usize,
+    /// 等待可用连接的最长时间，包括建立新连接的时间
+    pub acquire_timeout: Duration,
+    /// 借出空闲连接之前先 ping 一下
+    pub validate_on_acquire: bool,
+    /// 新连接建立之后先用它握手，为 None 时不握手
+    pub hello: Option<Hello>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 16,
+            acquire_timeout: Duration::from_secs(5),
+            validate_on_acquire: true,
+            hello: None,
+        }
+    }
+}
+
+/// 到一个或多个 k3 服务器的连接池，可以 clone 之后在多个任务里共享
+#[derive(Clone)]
+pub struct KvPool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    addrs: Vec<String>,
+    // 新连接轮流连到不同的服务器
+    next_addr: AtomicUsize,
+    idle: Mutex<Vec<ClientStream<TcpStream>>>,
+    permits: Arc<Semaphore>,
+    config: PoolConfig,
+}
+
+/// 借出去的连接，drop 的时候还给连接池；请求出错或者被取消的连接会被丢掉
+pub struct PooledClient {
+    client: Option<ClientStream<TcpStream>>,
+    pool: Arc<PoolInner>,
+    // 发出请求之前置为 true，读到 response 之后才清掉；
+    // 请求的 future 被中途 drop 时连接上可能还有没读的 response，不能再复用
+    broken: bool,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl KvPool {
+    pub fn new(addrs: Vec<String>, config: PoolConfig) -> Result<Self, KvError> {
+        if addrs.is_empty() {
+            return Err(KvError::InvalidCommand(
+                "KvPool needs at least one server".into(),
+            ));
+        }
+        Ok(Self {
+            inner: Arc::new(PoolInner {
+                addrs,
+                next_addr: AtomicUsize::new(0),
+                idle: Mutex::new(Vec::with_capacity(config.max_size)),
+                permits: Arc::new(Semaphore::new(config.max_size)),
+                config,
+            }),
+        })
+    }
+
+    /// 借一个连接，优先复用空闲连接；连接数达到上限时等待别人归还，超时返回 Timeout
+    pub async fn acquire(&self) -> Result<PooledClient, KvError> {
+        let timeout = self.inner.config.acquire_timeout;
+        match tokio::time::timeout(timeout, self.acquire_inner()).await {
+            Ok(res) => res,
+            Err(_) => Err(KvError::Timeout(format!(
+                "acquire a connection in {:?}",
+                timeout
+            ))),
+        }
+    }
+
+    /// 当前空闲的连接数
+    pub fn idle_count(&self) -> usize {
+        self.inner.idle.lock().unwrap().len()
+    }
+
+    async fn acquire_inner(&self) -> Result<PooledClient, KvError> {
+        let permit = self
+            .inner
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|e| KvError::Internal(e.to_string()))?;
+        let client = match self.take_idle().await {
+            Some(client) => client,
+            None => self.connect().await?,
+        };
+        Ok(PooledClient {
+            client: Some(client),
+            pool: self.inner.clone(),
+            broken: false,
+            _permit: permit,
+        })
+    }
+
+    // ping 失败的空闲连接直接丢掉
+    async fn take_idle(&self) -> Option<ClientStream<TcpStream>> {
+        loop {
+            let mut client = self.inner.idle.lock().unwrap().pop()?;
+            if !self.inner.config.validate_on_acquire {
+                return Some(client);
+            }
+            match client.execute(CommandRequest::new_ping()).await {
+                Ok(resp) if resp.status == 200 => return Some(client),
+                res => warn!("evict a broken connection: {:?}", res),
+            }
+        }
+    }
+
+    // 从下一个服务器开始，每个服务器最多试一次
+    async fn connect(&self) -> Result<ClientStream<TcpStream>, KvError> {
+        let addrs = &self.inner.addrs;
+        let start = self.inner.next_addr.fetch_add(1, Ordering::Relaxed);
+        let mut last_err = None;
+        for i in 0..addrs.len() {
+            let addr = &addrs[(start + i) % addrs.len()];
+            match self.connect_one(addr).await {
+                Ok(client) => return Ok(client),
+                Err(e) => {
+                    warn!("failed to connect to {}: {:?}", addr, e);
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| KvError::Internal("no server to connect".into())))
+    }
+
+    async fn connect_one(&self, addr: &str) -> Result<ClientStream<TcpStream>, KvError> {
+        let mut client = ClientStream::new(TcpStream::connect(addr).await?);
+        if let Some(hello) = &self.inner.config.hello {
+            client.handshake(hello.clone()).await?;
+        }
+        Ok(client)
+    }
+}
+
+impl PooledClient {
+    /// 和 ClientStream::execute 一样；出错或者被取消之后这个连接不会再还给连接池
+    pub async fn execute(&mut self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let was_broken = std::mem::replace(&mut self.broken, true);
+        let client = self.client.as_mut().unwrap();
+        let res = client.execute(cmd).await;
+        if res.is_ok() && !was_broken {
+            self.broken = false;
+        }
+        res
+    }
+
+    /// 订阅会一直占用这个连接，所以连接不再还给连接池
+    pub async fn execute_streaming(mut self, cmd: CommandRequest) -> Result<StreamResult, KvError> {
+        let client = self.client.take().unwrap();
+        client.execute_streaming(cmd).await
+    }
+}
+
+impl Deref for PooledClient {
+    type Target = ClientStream<TcpStream>;
+
+    fn deref(&self) -> &Self::Target {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        // 先还连接再释放 permit，等待的任务可以直接拿到空闲连接
+        if let Some(client) = self.client.take()
+            && !self.broken
+        {
+            self.pool.idle.lock().unwrap().push(client);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        HandshakeConfig, MemTable, ProstStream, ServerStream, Service, ServiceInner, Value,
+        assert_res_ok,
+    };
+    use anyhow::Result;
+    use futures::{SinkExt, StreamExt};
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+
+    // close_after 为 Some(n) 时每个连接处理 n 个请求之后断开
+    async fn start_server(close_after: Option<usize>) -> Result<(SocketAddr, Arc<AtomicUsize>)> {
+        let service: Service = ServiceInner::new(MemTable::new()).build();
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let service = service.clone();
+                tokio::spawn(async move {
+                    let Some(n) = close_after else {
+                        return ServerStream::new(stream, service).process().await;
+                    };
+                    let mut stream = ProstStream::<_, CommandRequest, CommandResponse>::new(stream);
+                    for _ in 0..n {
+                        let Some(Ok(cmd)) = stream.next().await else {
+                            break;
+                        };
+                        let mut resps = service.process_request(cmd, &Default::default());
+                        let resp = resps.next().await.unwrap();
+                        stream.send(Arc::unwrap_or_clone(resp)).await?;
+                    }
+                    Ok(())
+                });
+            }
+        });
+        Ok((addr, accepted))
+    }
+
+    async fn start_hello_server() -> Result<SocketAddr> {
+        let service: Service = ServiceInner::new(MemTable::new()).build();
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let config = HandshakeConfig {
+            require_hello: true,
+            ..Default::default()
+        };
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let server =
+                    ServerStream::new(stream, service.clone()).with_handshake(config.clone());
+                tokio::spawn(server.process());
+            }
+        });
+        Ok(addr)
+    }
+
+    fn new_pool(addrs: Vec<SocketAddr>, max_size: usize) -> KvPool {
+        let config = PoolConfig {
+            max_size,
+            acquire_timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        KvPool::new(addrs.iter().map(|a| a.to_string()).collect(), config).unwrap()
+    }
+
+    #[test]
+    fn pool_without_servers_should_be_rejected() {
+        let res = KvPool::new(vec![], PoolConfig::default());
+        assert!(matches!(res, Err(KvError::InvalidCommand(_))));
+    }
+
+    #[tokio::test]
+    async fn pool_should_reuse_connections() -> Result<()> {
+        let (addr, accepted) = start_server(None).await?;
+        let pool = new_pool(vec![addr], 2);
+
+        for i in 0..5 {
+            let mut client = pool.acquire().await?;
+            let resp = client
+                .execute(CommandRequest::new_hset("t1", "k1", i.into()))
+                .await?;
+            assert_eq!(resp.status, 200);
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        assert_eq!(pool.idle_count(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn acquire_should_time_out_when_pool_is_exhausted() -> Result<()> {
+        let (addr, _) = start_server(None).await?;
+        let pool = new_pool(vec![addr], 1);
+
+        let client = pool.acquire().await?;
+        assert!(matches!(pool.acquire().await, Err(KvError::Timeout(_))));
+
+        // 归还之后可以再借出
+        drop(client);
+        pool.acquire().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn broken_connections_should_be_evicted() -> Result<()> {
+        let (addr, accepted) = start_server(Some(1)).await?;
+        let pool = new_pool(vec![addr], 1);
+
+        // 连接在第一个请求之后被服务端关掉，I/O 错误之后不再还给连接池
+        let mut client = pool.acquire().await?;
+        client.execute(CommandRequest::new_ping()).await?;
+        assert!(client.execute(CommandRequest::new_ping()).await.is_err());
+        drop(client);
+        assert_eq!(pool.idle_count(), 0);
+
+        // 空闲连接 ping 失败之后被丢掉，换一个新连接
+        let mut client = pool.acquire().await?;
+        client.execute(CommandRequest::new_ping()).await?;
+        drop(client);
+        let mut client = pool.acquire().await?;
+        let resp = client.execute(CommandRequest::new_ping()).await?;
+        assert_res_ok(&resp, &[Value::from("PONG")], &[]);
+        assert_eq!(accepted.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cancelled_request_should_not_return_connection() -> Result<()> {
+        // 服务端收下连接之后一直不回 response
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+        let pool = new_pool(vec![addr], 1);
+
+        let mut client = pool.acquire().await?;
+        let res = tokio::time::timeout(
+            Duration::from_millis(20),
+            client.execute(CommandRequest::new_ping()),
+        )
+        .await;
+        assert!(res.is_err());
+        drop(client);
+        assert_eq!(pool.idle_count(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pool_should_spread_connections_across_servers() -> Result<()> {
+        let (addr1, accepted1) = start_server(None).await?;
+        let (addr2, accepted2) = start_server(None).await?;
+        let pool = new_pool(vec![addr1, addr2], 4);
+
+        let mut c1 = pool.acquire().await?;
+        let mut c2 = pool.acquire().await?;
+        c1.execute(CommandRequest::new_ping()).await?;
+        c2.execute(CommandRequest::new_ping()).await?;
+        assert_eq!(accepted1.load(Ordering::SeqCst), 1);
+        assert_eq!(accepted2.load(Ordering::SeqCst), 1);
+        drop((c1, c2));
+        assert_eq!(pool.idle_count(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pool_should_handshake_new_connections() -> Result<()> {
+        let addr = start_hello_server().await?;
+        let config = PoolConfig {
+            hello: Some(Hello::new()),
+            ..Default::default()
+        };
+        let pool = KvPool::new(vec![addr.to_string()], config)?;
+        for _ in 0..2 {
+            let mut client = pool.acquire().await?;
+            let resp = client.execute(CommandRequest::new_ping()).await?;
+            assert_eq!(resp.status, 200);
+        }
+        assert_eq!(pool.idle_count(), 1);
+
+        // 没有握手的连接会被服务端拒绝
+        let pool = new_pool(vec![addr], 1);
+        let mut client = pool.acquire().await?;
+        let resp = client.execute(CommandRequest::new_ping()).await?;
+        assert_ne!(resp.status, 200);
+        Ok(())
+    }
+}
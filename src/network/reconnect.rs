@@ -0,0 +1,438 @@
+use futures::{FutureExt, future::BoxFuture};
+use std::{
+    collections::hash_map::RandomState,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_rustls::client::TlsStream as ClientTlsStream;
+use tracing::warn;
+
+use crate::{ClientStream, CommandRequest, CommandResponse, Hello, KvError, TlsClientConnector};
+
+/// 重连的退避策略：第 n 次重试前等待 min(initial * multiplier^n, max)，
+/// 再随机减掉其中最多 jitter 比例的时间，避免所有客户端同时重连
+#[derive(Debug, Clone)]
+pub struct BackoffConfig {
+    pub initial: Duration,
+    pub max: Duration,
+    pub multiplier: f64,
+    /// 0.0 ~ 1.0
+    pub jitter: f64,
+    /// 最多重试的次数，不包括第一次执行
+    pub max_retries: usize,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter: 0.5,
+            max_retries: 8,
+        }
+    }
+}
+
+impl BackoffConfig {
+    /// 第 attempt 次重试前等待的时间，attempt 从 0 开始
+    pub fn delay(&self, attempt: usize) -> Duration {
+        let base = self.initial.as_secs_f64() * self.multiplier.powi(attempt as i32);
+        let base = base.min(self.max.as_secs_f64());
+        let jitter = self.jitter.clamp(0.0, 1.0) * random();
+        Duration::from_secs_f64(base * (1.0 - jitter))
+    }
+}
+
+type Connect<S> = Box<dyn Fn() -> BoxFuture<'static, Result<S, KvError>> + Send + Sync>;
+
+/// 连接断开之后自动重连的客户端
+/// 幂等的命令会按照 BackoffConfig 重试；非幂等的命令只在请求还没有发出去
+/// （建立连接失败）时重试，除非调用方用 execute_idempotent 明确允许
+pub struct ReconnectingClient<S = TcpStream> {
+    addr: String,
+    backoff: BackoffConfig,
+    connector: Connect<S>,
+    // 每次建立新连接之后先握手
+    hello: Option<Hello>,
+    client: Option<ClientStream<S>>,
+}
+
+impl ReconnectingClient<TcpStream> {
+    /// 第一次执行命令的时候才建立连接
+    pub fn new(addr: impl Into<String>, backoff: BackoffConfig) -> Self {
+        let addr = addr.into();
+        let target = addr.clone();
+        Self::with_connector(addr, backoff, move || {
+            let addr = target.clone();
+            async move { Ok(TcpStream::connect(addr).await?) }
+        })
+    }
+}
+
+impl ReconnectingClient<ClientTlsStream<TcpStream>> {
+    /// 每次重连都重新建立 TCP 连接并完成 TLS 握手
+    pub fn new_tls(
+        addr: impl Into<String>,
+        connector: TlsClientConnector,
+        backoff: BackoffConfig,
+    ) -> Self {
+        let addr = addr.into();
+        let target = addr.clone();
+        Self::with_connector(addr, backoff, move || {
+            let addr = target.clone();
+            let connector = connector.clone();
+            async move { connector.connect(TcpStream::connect(addr).await?).await }
+        })
+    }
+}
+
+impl<S> ReconnectingClient<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    /// connect 负责建立一个新的连接，addr 只用于日志
+    pub fn with_connector<F, Fut>(
+        addr: impl Into<String>,
+        backoff: BackoffConfig,
+        connect: F,
+    ) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<S, KvError>> + Send + 'static,
+    {
+        Self {
+            addr: addr.into(),
+            backoff,
+            connector: Box::new(move || connect().boxed()),
+            hello: None,
+            client: None,
+        }
+    }
+
+    /// 每次建立新连接之后先用 hello 握手，被服务端拒绝时不会重试
+    pub fn with_hello(mut self, hello: Hello) -> Self {
+        self.hello = Some(hello);
+        self
+    }
+
+    pub async fn execute(&mut self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let retry = cmd.is_idempotent();
+        self.execute_with_retry(cmd, retry).await
+    }
+
+    /// 调用方保证 cmd 重复执行也没有问题，连接断开之后总是重试
+    pub async fn execute_idempotent(
+        &mut self,
+        cmd: CommandRequest,
+    ) -> Result<CommandResponse, KvError> {
+        self.execute_with_retry(cmd, true).await
+    }
+
+    async fn execute_with_retry(
+        &mut self,
+        cmd: CommandRequest,
+        retry: bool,
+    ) -> Result<CommandResponse, KvError> {
+        let mut attempt = 0;
+        loop {
+            // 连接失败时请求还没有发出去，任何命令都可以重试
+            let (res, sent) = match self.connect().await {
+                Ok(client) => (client.execute(cmd.clone()).await, true),
+                Err(e) => (Err(e), false),
+            };
+            let e = match res {
+                Err(e) if is_disconnected(&e) => e,
+                res => return res,
+            };
+            self.client = None;
+            if attempt >= self.backoff.max_retries || (sent && !retry) {
+                return Err(e);
+            }
+            let delay = self.backoff.delay(attempt);
+            warn!(
+                "connection to {} lost: {:?}, retry in {:?}",
+                self.addr, e, delay
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    async fn connect(&mut self) -> Result<&mut ClientStream<S>, KvError> {
+        if self.client.is_none() {
+            let mut client = ClientStream::new((self.connector)().await?);
+            if let Some(hello) = &self.hello {
+                client.handshake(hello.clone()).await?;
+            }
+            self.client = Some(client);
+        }
+        Ok(self.client.as_mut().unwrap())
+    }
+}
+
+// 连接已经不可用，需要重连
+fn is_disconnected(e: &KvError) -> bool {
+    matches!(e, KvError::IoError(_) | KvError::ConnectionClosed)
+}
+
+// 0.0 ~ 1.0 之间的随机数，只用来打散重连的时间，不需要引入 rand
+fn random() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(0);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        HandshakeConfig, MemTable, ServerStream, Service, ServiceInner, TlsServerAcceptor, Value,
+        assert_res_ok,
+        network::tls::tests::{DOMAIN, generate_server_certs},
+    };
+    use anyhow::Result;
+    use std::net::SocketAddr;
+    use tokio::{net::TcpListener, task::JoinSet};
+
+    // 返回的任务被 abort 之后，监听和所有连接都会断开
+    async fn start_server(
+        addr: SocketAddr,
+        service: Service,
+    ) -> Result<tokio::task::JoinHandle<()>> {
+        start_server_with_tls(addr, service, None).await
+    }
+
+    async fn start_server_with_tls(
+        addr: SocketAddr,
+        service: Service,
+        acceptor: Option<TlsServerAcceptor>,
+    ) -> Result<tokio::task::JoinHandle<()>> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(tokio::spawn(async move {
+            let mut conns = JoinSet::new();
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let service = service.clone();
+                match acceptor.clone() {
+                    Some(acceptor) => conns.spawn(async move {
+                        if let Ok(stream) = acceptor.accept(stream).await {
+                            let _ = ServerStream::new(stream, service).process().await;
+                        }
+                    }),
+                    None => conns.spawn(async move {
+                        let _ = ServerStream::new(stream, service).process().await;
+                    }),
+                };
+            }
+        }))
+    }
+
+    async fn unused_addr() -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        Ok(listener.local_addr()?)
+    }
+
+    fn backoff() -> BackoffConfig {
+        BackoffConfig {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(50),
+            max_retries: 20,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn delay_should_grow_exponentially_with_jitter() {
+        let backoff = BackoffConfig {
+            initial: Duration::from_millis(100),
+            max: Duration::from_millis(1000),
+            multiplier: 2.0,
+            jitter: 0.5,
+            max_retries: 10,
+        };
+        for _ in 0..100 {
+            let d = backoff.delay(2);
+            assert!(d > Duration::from_millis(200) && d <= Duration::from_millis(400));
+            let d = backoff.delay(10);
+            assert!(d > Duration::from_millis(500) && d <= Duration::from_millis(1000));
+        }
+        let backoff = BackoffConfig {
+            jitter: 0.0,
+            ..backoff
+        };
+        assert_eq!(backoff.delay(0), Duration::from_millis(100));
+        assert_eq!(backoff.delay(3), Duration::from_millis(800));
+    }
+
+    #[test]
+    fn only_safe_commands_should_be_idempotent() {
+        assert!(CommandRequest::new_hget("t1", "k1").is_idempotent());
+        assert!(CommandRequest::new_hset("t1", "k1", 1.into()).is_idempotent());
+        assert!(!CommandRequest::new_hincrby("t1", "k1", 1).is_idempotent());
+        assert!(!CommandRequest::new_publish("lobby", vec![]).is_idempotent());
+    }
+
+    #[tokio::test]
+    async fn idempotent_commands_should_survive_server_restart() -> Result<()> {
+        let service: Service = ServiceInner::new(MemTable::new()).build();
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        drop(listener);
+        let server = start_server(addr, service.clone()).await?;
+
+        let mut client = ReconnectingClient::new(addr.to_string(), backoff());
+        client
+            .execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await?;
+
+        // 重启服务器，重启期间客户端一直在重试
+        server.abort();
+        let _ = server.await;
+        let restart = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            start_server(addr, service).await
+        };
+        let (resp, server) = tokio::join!(
+            client.execute(CommandRequest::new_hget("t1", "k1")),
+            restart
+        );
+        assert_res_ok(&resp?, &["v1".into()], &[]);
+        server?.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn non_idempotent_commands_should_not_be_retried() -> Result<()> {
+        let service: Service = ServiceInner::new(MemTable::new()).build();
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        drop(listener);
+        let server = start_server(addr, service.clone()).await?;
+
+        let mut client = ReconnectingClient::new(addr.to_string(), backoff());
+        client
+            .execute(CommandRequest::new_hincrby("t1", "n", 1))
+            .await?;
+
+        server.abort();
+        let _ = server.await;
+        let server = start_server(addr, service).await?;
+
+        // 旧连接已经断开，incr 可能已经发出去了，不能重试
+        let res = client
+            .execute(CommandRequest::new_hincrby("t1", "n", 1))
+            .await;
+        assert!(matches!(
+            res,
+            Err(KvError::IoError(_) | KvError::ConnectionClosed)
+        ));
+
+        // 之后的命令使用新的连接
+        let resp = client
+            .execute(CommandRequest::new_hincrby("t1", "n", 1))
+            .await?;
+        assert_res_ok(&resp, &[Value::from(2)], &[]);
+
+        // 调用方明确允许时也会重试
+        let resp = client
+            .execute_idempotent(CommandRequest::new_hget("t1", "n"))
+            .await?;
+        assert_res_ok(&resp, &[Value::from(2)], &[]);
+        server.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_should_give_up_after_max_retries() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        drop(listener);
+
+        let backoff = BackoffConfig {
+            max_retries: 3,
+            ..backoff()
+        };
+        let mut client = ReconnectingClient::new(addr.to_string(), backoff);
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await;
+        assert!(matches!(res, Err(KvError::IoError(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_client_should_reconnect_after_server_restart() -> Result<()> {
+        let (ca, cert, key) = generate_server_certs();
+        let acceptor = TlsServerAcceptor::new(&cert, &key, None)?;
+        let service: Service = ServiceInner::new(MemTable::new()).build();
+        let addr = unused_addr().await?;
+        let server = start_server_with_tls(addr, service.clone(), Some(acceptor.clone())).await?;
+
+        let connector = TlsClientConnector::new(DOMAIN, None, Some(&ca))?;
+        let mut client = ReconnectingClient::new_tls(addr.to_string(), connector, backoff());
+        client
+            .execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await?;
+
+        server.abort();
+        let _ = server.await;
+        let server = start_server_with_tls(addr, service, Some(acceptor)).await?;
+
+        let resp = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_res_ok(&resp, &["v1".into()], &[]);
+        server.abort();
+        Ok(())
+    }
+
+    #[test]
+    fn connection_closed_should_be_treated_as_disconnected() {
+        assert!(is_disconnected(&KvError::ConnectionClosed));
+        assert!(!is_disconnected(&KvError::Internal("no response".into())));
+    }
+
+    #[tokio::test]
+    async fn client_should_handshake_after_reconnect() -> Result<()> {
+        let service: Service = ServiceInner::new(MemTable::new()).build();
+        let addr = unused_addr().await?;
+        let config = HandshakeConfig {
+            require_hello: true,
+            ..Default::default()
+        };
+        let start = |service: Service| {
+            let config = config.clone();
+            async move {
+                let listener = TcpListener::bind(addr).await?;
+                Ok::<_, anyhow::Error>(tokio::spawn(async move {
+                    let mut conns = JoinSet::new();
+                    loop {
+                        let (stream, _) = listener.accept().await.unwrap();
+                        let server = ServerStream::new(stream, service.clone())
+                            .with_handshake(config.clone());
+                        conns.spawn(server.process());
+                    }
+                }))
+            }
+        };
+        let server = start(service.clone()).await?;
+
+        let mut client =
+            ReconnectingClient::new(addr.to_string(), backoff()).with_hello(Hello::new());
+        let resp = client
+            .execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await?;
+        assert_eq!(resp.status, 200);
+
+        server.abort();
+        let _ = server.await;
+        let server = start(service).await?;
+
+        let resp = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_res_ok(&resp, &["v1".into()], &[]);
+        server.abort();
+        Ok(())
+    }
+}
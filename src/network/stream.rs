@@ -1,21 +1,44 @@
-use bytes::BytesMut;
-use futures::{FutureExt, Sink, Stream, ready};
+use bytes::{Buf, Bytes, BytesMut};
+use futures::{Sink, Stream, ready};
 use std::{
-    marker::PhantomData,
+    collections::VecDeque,
+    io::{self, IoSlice},
     pin::Pin,
     task::{Context, Poll},
 };
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::{
+    codec::{Decoder, Encoder},
+    io::poll_read_buf,
+};
+
+use crate::{FrameCodec, FrameConfig, KvError, ProstCodec};
 
-use crate::{FrameCodec, KvError, read_frame};
+// 每次从 socket 读之前保证 rbuf 至少有这么多空闲空间
+const READ_BUF_SIZE: usize = 8 * 1024;
+// 一次 write_vectored 最多带上的帧数
+const MAX_IOVECS: usize = 64;
+// 不支持 vectored write 时，小于它的帧先合并再写，和一个 TLS record 一样大
+const COALESCE_LIMIT: usize = 16 * 1024;
+/// 默认的写缓冲上限
+pub const DEFAULT_HIGH_WATER_MARK: usize = 64 * 1024;
 
 pub struct ProstStream<S, In, Out> {
     stream: S,
+    // 编码用的 buffer，编码好的帧从这里切出去放进 pending，剩下的空间继续复用
     wbuf: BytesMut,
-    written: usize,
+    // 等待写出的帧，第一个帧可能已经写出了一部分
+    pending: VecDeque<Bytes>,
+    // pending 里还没写出的字节数
+    buffered: usize,
+    // buffered 超过它时 poll_ready 先把数据写出去，生产者要等待
+    high_water: usize,
+    // 读到的数据先放在这里，解出来的帧直接引用它的内存；帧被释放之后空间可以复用
     rbuf: BytesMut,
-    _in: PhantomData<In>,
-    _out: PhantomData<Out>,
+    // 写出时的压缩配置，读的时候按帧头里的算法解压
+    codec: ProstCodec<In, Out>,
+    // 读帧出错之后无法再找到下一帧的边界，之后的 poll_next 都返回 None
+    read_failed: bool,
 }
 
 impl<S, In, Out> Stream for ProstStream<S, In, Out>
@@ -25,13 +48,41 @@ where
     Out: Unpin + Send,
 {
     type Item = Result<In, KvError>;
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        assert!(self.rbuf.is_empty());
-        let mut rest = self.rbuf.split_off(0);
-        let fut = read_frame(&mut self.stream, &mut rest);
-        ready!(Box::pin(fut).poll_unpin(cx))?;
-        self.rbuf.unsplit(rest);
-        Poll::Ready(Some(In::decode_frame(&mut self.rbuf)))
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.read_failed {
+            return Poll::Ready(None);
+        }
+        loop {
+            // 上一次读到的数据里可能已经有完整的帧，先不去读 socket
+            match this.codec.decode(&mut this.rbuf) {
+                Ok(Some(item)) => return Poll::Ready(Some(Ok(item))),
+                Ok(None) => {}
+                Err(e) => {
+                    this.read_failed = true;
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
+            this.rbuf.reserve(READ_BUF_SIZE);
+            let res = ready!(poll_read_buf(
+                Pin::new(&mut this.stream),
+                cx,
+                &mut this.rbuf
+            ));
+            match res {
+                Ok(n) if n > 0 => {}
+                // 对方关闭连接也当作 I/O 错误，和没读完的帧一样
+                Ok(_) => {
+                    this.read_failed = true;
+                    let e = io::Error::from(io::ErrorKind::UnexpectedEof);
+                    return Poll::Ready(Some(Err(e.into())));
+                }
+                Err(e) => {
+                    this.read_failed = true;
+                    return Poll::Ready(Some(Err(e.into())));
+                }
+            }
+        }
     }
 }
 
@@ -42,24 +93,28 @@ where
     Out: Unpin + Send + FrameCodec,
 {
     type Error = KvError;
-    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        while this.buffered >= this.high_water {
+            ready!(this.poll_write_pending(cx))?;
+        }
         Poll::Ready(Ok(()))
     }
 
     fn start_send(self: Pin<&mut Self>, item: Out) -> Result<(), Self::Error> {
         let this = self.get_mut();
-        item.encode_frame(&mut this.wbuf)?;
+        this.codec.encode(item, &mut this.wbuf)?;
+        let frame = this.wbuf.split().freeze();
+        this.buffered += frame.len();
+        this.pending.push_back(frame);
         Ok(())
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         let this = self.get_mut();
-        while this.written != this.wbuf.len() {
-            let n = ready!(Pin::new(&mut this.stream).poll_write(cx, &this.wbuf[this.written..]))?;
-            this.written += n;
+        while !this.pending.is_empty() {
+            ready!(this.poll_write_pending(cx))?;
         }
-        this.wbuf.clear();
-        this.written = 0;
         ready!(Pin::new(&mut this.stream).poll_flush(cx)?);
         Poll::Ready(Ok(()))
     }
@@ -71,6 +126,59 @@ where
     }
 }
 
+impl<S, In, Out> ProstStream<S, In, Out>
+where
+    S: AsyncWrite + Unpin,
+{
+    // 把排队的帧用一次 write_vectored 写出去，写出多少就从 pending 里去掉多少；
+    // 不支持 vectored write 的 stream（比如 TLS）只会写第一个帧，先把小帧拷贝到一起再写
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), KvError>> {
+        let mut n = if self.stream.is_write_vectored() {
+            let mut slices = [IoSlice::new(&[]); MAX_IOVECS];
+            let count = self
+                .pending
+                .iter()
+                .zip(slices.iter_mut())
+                .map(|(frame, slice)| *slice = IoSlice::new(frame))
+                .count();
+            ready!(Pin::new(&mut self.stream).poll_write_vectored(cx, &slices[..count]))?
+        } else {
+            self.coalesce_pending();
+            ready!(Pin::new(&mut self.stream).poll_write(cx, &self.pending[0]))?
+        };
+        if n == 0 {
+            return Poll::Ready(Err(io::Error::from(io::ErrorKind::WriteZero).into()));
+        }
+        self.buffered -= n;
+        while n > 0 {
+            let frame = self.pending.front_mut().unwrap();
+            if n < frame.len() {
+                // 只写出了一部分，下次从剩下的地方开始
+                frame.advance(n);
+                break;
+            }
+            n -= frame.len();
+            self.pending.pop_front();
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    // 开头的小帧合并成一个不超过 COALESCE_LIMIT 的帧，借用 wbuf 的空间
+    fn coalesce_pending(&mut self) {
+        if self.pending.len() < 2 || self.pending[0].len() >= COALESCE_LIMIT {
+            return;
+        }
+        while let Some(frame) = self.pending.front() {
+            if !self.wbuf.is_empty() && self.wbuf.len() + frame.len() > COALESCE_LIMIT {
+                break;
+            }
+            self.wbuf.extend_from_slice(frame);
+            self.pending.pop_front();
+        }
+        self.pending.push_front(self.wbuf.split().freeze());
+    }
+}
+
 impl<S, In, Out> ProstStream<S, In, Out>
 where
     S: AsyncRead + AsyncWrite + Send + Unpin,
@@ -79,12 +187,33 @@ where
         Self {
             stream,
             wbuf: BytesMut::new(),
-            written: 0,
+            pending: VecDeque::new(),
+            buffered: 0,
+            high_water: DEFAULT_HIGH_WATER_MARK,
             rbuf: BytesMut::new(),
-            _in: PhantomData::default(),
-            _out: PhantomData::default(),
+            codec: ProstCodec::new(FrameConfig::default()),
+            read_failed: false,
         }
     }
+
+    pub fn with_frame_config(mut self, config: FrameConfig) -> Self {
+        self.codec.set_config(config);
+        self
+    }
+
+    /// 还没写出的数据超过 high_water 字节时，发送方要等这些数据写出去之后才能继续发送
+    pub fn with_high_water_mark(mut self, high_water: usize) -> Self {
+        self.high_water = high_water.max(1);
+        self
+    }
+
+    pub fn frame_config(&self) -> &FrameConfig {
+        self.codec.config()
+    }
+
+    pub fn set_frame_config(&mut self, config: FrameConfig) {
+        self.codec.set_config(config);
+    }
 }
 
 #[cfg(test)]
@@ -92,7 +221,7 @@ mod tests {
     use super::*;
     use crate::{CommandRequest, utils::DummyStream};
     use anyhow::Result;
-    use futures::{SinkExt, StreamExt};
+    use futures::{FutureExt, SinkExt, StreamExt};
     #[allow(clippy::all)]
     #[tokio::test]
     async fn prost_stream_should_work() -> Result<()> {
@@ -108,4 +237,222 @@ mod tests {
         }
         Ok(())
     }
+
+    // 每次最多写 max_write 字节，blocked 时所有写都返回 Pending，
+    // stutter 时每写一次就返回一次 Pending
+    #[derive(Default)]
+    struct MockWriter {
+        data: Vec<u8>,
+        max_write: usize,
+        blocked: bool,
+        stutter: bool,
+        stalled: bool,
+        writes: usize,
+        // 不支持 vectored write，每次只写第一个 buffer
+        scalar: bool,
+    }
+
+    impl AsyncRead for MockWriter {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for MockWriter {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+        }
+
+        fn poll_write_vectored(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            bufs: &[IoSlice<'_>],
+        ) -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            if this.blocked {
+                return Poll::Pending;
+            }
+            if this.stalled {
+                this.stalled = false;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            this.stalled = this.stutter;
+            this.writes += 1;
+            let bufs = if this.scalar { &bufs[..1] } else { bufs };
+            let before = this.data.len();
+            for buf in bufs {
+                let n = buf.len().min(this.max_write - (this.data.len() - before));
+                this.data.extend_from_slice(&buf[..n]);
+            }
+            Poll::Ready(Ok(this.data.len() - before))
+        }
+
+        fn is_write_vectored(&self) -> bool {
+            !self.scalar
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn encoded(cmds: &[CommandRequest]) -> Vec<u8> {
+        encoded_with(cmds, &FrameConfig::default())
+    }
+
+    fn encoded_with(cmds: &[CommandRequest], config: &FrameConfig) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        for cmd in cmds {
+            cmd.encode_frame_with(&mut buf, config).unwrap();
+        }
+        buf.to_vec()
+    }
+
+    fn cmds(n: usize) -> Vec<CommandRequest> {
+        (0..n)
+            .map(|i| CommandRequest::new_hget("t1", format!("k{}", i)))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn queued_frames_should_be_written_in_one_vectored_write() -> Result<()> {
+        let writer = MockWriter {
+            max_write: usize::MAX,
+            ..Default::default()
+        };
+        let mut stream = ProstStream::<_, CommandRequest, CommandRequest>::new(writer);
+        let cmds = cmds(10);
+        for cmd in &cmds {
+            stream.feed(cmd.clone()).await?;
+        }
+        stream.flush().await?;
+        assert_eq!(stream.stream.writes, 1);
+        assert_eq!(stream.stream.data, encoded(&cmds));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn small_frames_should_be_coalesced_without_vectored_write() -> Result<()> {
+        let writer = MockWriter {
+            max_write: usize::MAX,
+            scalar: true,
+            ..Default::default()
+        };
+        let config = FrameConfig {
+            compression: crate::CompressionAlgorithm::None,
+            ..Default::default()
+        };
+        let mut stream =
+            ProstStream::<_, CommandRequest, CommandRequest>::new(writer).with_frame_config(config);
+        let cmds = cmds(10);
+        for cmd in &cmds {
+            stream.feed(cmd.clone()).await?;
+        }
+        stream.flush().await?;
+        assert_eq!(stream.stream.writes, 1);
+        assert_eq!(stream.stream.data, encoded_with(&cmds, &config));
+
+        // 大帧不拷贝，单独写出
+        let large = CommandRequest::new_hget("t1", "k".repeat(COALESCE_LIMIT));
+        let cmds = [cmds[..3].to_vec(), vec![large], cmds[..3].to_vec()].concat();
+        stream.stream.writes = 0;
+        stream.stream.data.clear();
+        for cmd in &cmds {
+            stream.feed(cmd.clone()).await?;
+        }
+        stream.flush().await?;
+        assert_eq!(stream.stream.writes, 3);
+        assert_eq!(stream.stream.data, encoded_with(&cmds, &config));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn partial_writes_should_resume_where_they_stopped() -> Result<()> {
+        let writer = MockWriter {
+            max_write: 3,
+            stutter: true,
+            ..Default::default()
+        };
+        let mut stream = ProstStream::<_, CommandRequest, CommandRequest>::new(writer);
+        let cmds = cmds(5);
+        for cmd in &cmds {
+            stream.feed(cmd.clone()).await?;
+        }
+        // 第一个帧只写出 3 字节就返回了 Pending，之后的 flush 接着写
+        let total = stream.buffered;
+        assert!(stream.flush().now_or_never().is_none());
+        assert_eq!(stream.stream.data.len(), 3);
+        assert_eq!(stream.buffered, total - 3);
+        for cmd in &cmds {
+            stream.feed(cmd.clone()).await?;
+        }
+        stream.flush().await?;
+        assert!(stream.pending.is_empty() && stream.buffered == 0);
+        assert_eq!(stream.stream.data, encoded(&[cmds.clone(), cmds].concat()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sender_should_wait_when_buffer_is_above_high_water_mark() -> Result<()> {
+        let writer = MockWriter {
+            max_write: usize::MAX,
+            blocked: true,
+            ..Default::default()
+        };
+        let mut stream =
+            ProstStream::<_, CommandRequest, CommandRequest>::new(writer).with_high_water_mark(64);
+        let mut sent = vec![];
+        for cmd in cmds(100) {
+            match stream.feed(cmd.clone()).now_or_never() {
+                Some(res) => {
+                    res?;
+                    sent.push(cmd);
+                }
+                None => break,
+            }
+        }
+        // 对方不读的时候，缓冲的数据不会无限增长
+        assert!(!sent.is_empty() && sent.len() < 100);
+        assert!(stream.buffered >= 64 && stream.buffered < 128);
+
+        stream.stream.blocked = false;
+        stream.flush().await?;
+        assert_eq!(stream.stream.data, encoded(&sent));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prost_stream_should_read_multiple_frames_from_one_read() -> Result<()> {
+        let mut buf = BytesMut::new();
+        let cmds: Vec<_> = (0..3)
+            .map(|i| CommandRequest::new_hget("t1", format!("k{}", i)))
+            .collect();
+        for cmd in &cmds {
+            cmd.encode_frame(&mut buf)?;
+        }
+        let mut stream = ProstStream::<_, CommandRequest, CommandRequest>::new(DummyStream { buf });
+        for cmd in cmds {
+            assert_eq!(stream.next().await.unwrap()?, cmd);
+        }
+        // 数据读完之后对方关闭了连接
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(KvError::IoError(_)))
+        ));
+        assert!(stream.next().await.is_none());
+        Ok(())
+    }
 }
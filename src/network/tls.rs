@@ -0,0 +1,404 @@
+use std::{io::Cursor, sync::Arc};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{
+    TlsAcceptor, TlsConnector,
+    client::TlsStream as ClientTlsStream,
+    rustls::{
+        AllowAnyAuthenticatedClient, Certificate, ClientConfig, NoClientAuth, PrivateKey,
+        RootCertStore, ServerConfig, Session, internal::pemfile,
+    },
+    server::TlsStream as ServerTlsStream,
+    webpki::DNSNameRef,
+};
+use x509_parser::{extensions::GeneralName, prelude::FromDer, prelude::X509Certificate};
+
+use crate::{KvError, PeerIdentity};
+
+/// 通过 ALPN 协商的协议名
+const ALPN_KV: &str = "kv";
+
+/// 服务端：持有证书和私钥，把 TCP 连接升级成 TLS
+#[derive(Clone)]
+pub struct TlsServerAcceptor {
+    inner: Arc<ServerConfig>,
+}
+
+/// 客户端：校验服务端证书，把 TCP 连接升级成 TLS
+#[derive(Clone)]
+pub struct TlsClientConnector {
+    config: Arc<ClientConfig>,
+    domain: Arc<String>,
+}
+
+impl TlsServerAcceptor {
+    /// cert / key 是 PEM 格式的证书链和私钥（PKCS8 或 RSA）；
+    /// 提供 client_ca 时开启 mTLS，只接受这个 CA 签发的客户端证书
+    pub fn new(cert: &str, key: &str, client_ca: Option<&str>) -> Result<Self, KvError> {
+        let certs = load_certs("server", cert)?;
+        let key = load_key("server", key)?;
+        let mut config = match client_ca {
+            Some(ca) => ServerConfig::new(AllowAnyAuthenticatedClient::new(load_root_store(ca)?)),
+            None => ServerConfig::new(NoClientAuth::new()),
+        };
+        config
+            .set_single_cert(certs, key)
+            .map_err(|_| KvError::CertifcateParseError("server", "cert"))?;
+        config.set_protocols(&[Vec::from(ALPN_KV)]);
+        Ok(Self {
+            inner: Arc::new(config),
+        })
+    }
+
+    /// 完成 TLS 握手，返回的 stream 可以直接交给 ServerStream::new
+    pub async fn accept<S>(&self, stream: S) -> Result<ServerTlsStream<S>, KvError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let acceptor = TlsAcceptor::from(self.inner.clone());
+        Ok(acceptor.accept(stream).await?)
+    }
+
+    /// 握手时验证过的客户端证书里的身份，没有开启 mTLS 时返回 None
+    pub fn peer_identity<S>(stream: &ServerTlsStream<S>) -> Option<PeerIdentity> {
+        let certs = stream.get_ref().1.get_peer_certificates()?;
+        parse_identity(certs.first()?).ok()
+    }
+}
+
+impl TlsClientConnector {
+    /// domain 要和服务端证书里的域名一致；
+    /// identity 是客户端的 (cert, key)，服务端开启 mTLS 时需要；
+    /// 提供 server_ca 时只信任这个 CA，否则使用系统的根证书
+    pub fn new(
+        domain: impl Into<String>,
+        identity: Option<(&str, &str)>,
+        server_ca: Option<&str>,
+    ) -> Result<Self, KvError> {
+        let mut config = ClientConfig::new();
+        if let Some((cert, key)) = identity {
+            config
+                .set_single_client_cert(load_certs("client", cert)?, load_key("client", key)?)
+                .map_err(|_| KvError::CertifcateParseError("client", "cert"))?;
+        }
+        config.root_store = match server_ca {
+            Some(ca) => load_root_store(ca)?,
+            None => match rustls_native_certs::load_native_certs() {
+                Ok(store) | Err((Some(store), _)) => store,
+                Err((None, e)) => return Err(e.into()),
+            },
+        };
+        config.set_protocols(&[Vec::from(ALPN_KV)]);
+        Ok(Self {
+            config: Arc::new(config),
+            domain: Arc::new(domain.into()),
+        })
+    }
+
+    /// 完成 TLS 握手，返回的 stream 可以直接交给 ClientStream::new
+    pub async fn connect<S>(&self, stream: S) -> Result<ClientTlsStream<S>, KvError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let dns = DNSNameRef::try_from_ascii_str(self.domain.as_str())
+            .map_err(|_| KvError::Internal(format!("invalid domain: {}", self.domain)))?;
+        let stream = TlsConnector::from(self.config.clone())
+            .connect(dns, stream)
+            .await?;
+        Ok(stream)
+    }
+}
+
+// role 是证书的用途（server / client），出错时放在错误信息里
+fn load_certs(role: &'static str, cert: &str) -> Result<Vec<Certificate>, KvError> {
+    let mut cert = Cursor::new(cert);
+    match pemfile::certs(&mut cert) {
+        Ok(certs) if !certs.is_empty() => Ok(certs),
+        _ => Err(KvError::CertifcateParseError(role, "cert")),
+    }
+}
+
+fn load_key(role: &'static str, key: &str) -> Result<PrivateKey, KvError> {
+    let mut cursor = Cursor::new(key);
+    // 先按 PKCS8 解析，不行再按 RSA 解析
+    if let Ok(mut keys) = pemfile::pkcs8_private_keys(&mut cursor)
+        && !keys.is_empty()
+    {
+        return Ok(keys.remove(0));
+    }
+    cursor.set_position(0);
+    if let Ok(mut keys) = pemfile::rsa_private_keys(&mut cursor)
+        && !keys.is_empty()
+    {
+        return Ok(keys.remove(0));
+    }
+    Err(KvError::CertifcateParseError(role, "key"))
+}
+
+fn parse_identity(cert: &Certificate) -> Result<PeerIdentity, KvError> {
+    let (_, cert) = X509Certificate::from_der(&cert.0)
+        .map_err(|_| KvError::CertifcateParseError("peer", "cert"))?;
+    let subject = cert.subject();
+    let common_name = subject
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .map(String::from);
+    let mut sans = vec![];
+    if let Ok(Some(ext)) = cert.subject_alternative_name() {
+        for name in &ext.value.general_names {
+            match name {
+                GeneralName::DNSName(s) | GeneralName::RFC822Name(s) | GeneralName::URI(s) => {
+                    sans.push(s.to_string())
+                }
+                GeneralName::IPAddress(ip) => match <[u8; 4]>::try_from(*ip) {
+                    Ok(v4) => sans.push(std::net::Ipv4Addr::from(v4).to_string()),
+                    Err(_) => {
+                        if let Ok(v6) = <[u8; 16]>::try_from(*ip) {
+                            sans.push(std::net::Ipv6Addr::from(v6).to_string())
+                        }
+                    }
+                },
+                _ => {}
+            }
+        }
+    }
+    Ok(PeerIdentity {
+        subject: subject.to_string(),
+        common_name,
+        sans,
+    })
+}
+
+fn load_root_store(ca: &str) -> Result<RootCertStore, KvError> {
+    let mut store = RootCertStore::empty();
+    match store.add_pem_file(&mut Cursor::new(ca)) {
+        Ok((valid, _)) if valid > 0 => Ok(store),
+        _ => Err(KvError::CertifcateParseError("CA", "cert")),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        ClientStream, CommandRequest, MemTable, RequestContext, RequestData, ServerStream, Service,
+        ServiceInner, Value, assert_res_error, assert_res_ok,
+    };
+    use anyhow::Result;
+    use certify::{CA, CertSigAlgo, generate_ca, generate_cert};
+    use std::net::SocketAddr;
+    use tokio::net::{TcpListener, TcpStream};
+
+    pub(crate) const DOMAIN: &str = "kvserver.acme.inc";
+
+    fn generate_test_ca() -> (String, CA) {
+        let (ca_cert, ca_key) = generate_ca(
+            "CN",
+            "Acme Inc.",
+            "Acme CA",
+            CertSigAlgo::EcDsa,
+            None,
+            Some(10),
+        )
+        .unwrap();
+        let ca = CA::load(&ca_cert, &ca_key).unwrap();
+        (ca_cert, ca)
+    }
+
+    fn generate_test_cert(ca: &CA, domain: &str, cn: &str, is_client: bool) -> (String, String) {
+        generate_cert(
+            ca,
+            vec![domain],
+            "CN",
+            "Acme Inc.",
+            cn,
+            CertSigAlgo::EcDsa,
+            None,
+            is_client,
+            Some(10),
+        )
+        .unwrap()
+    }
+
+    /// 测试用的证书：(ca cert, server cert, server key)
+    pub(crate) fn generate_server_certs() -> (String, String, String) {
+        let (ca_cert, ca) = generate_test_ca();
+        let (cert, key) = generate_test_cert(&ca, DOMAIN, "Acme KV server", false);
+        (ca_cert, cert, key)
+    }
+
+    async fn start_tls_server(acceptor: TlsServerAcceptor, service: Service) -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                let service = service.clone();
+                tokio::spawn(async move {
+                    if let Ok(stream) = acceptor.accept(stream).await {
+                        let ctx = RequestContext::new(TlsServerAcceptor::peer_identity(&stream));
+                        let _ = ServerStream::new(stream, service)
+                            .with_context(ctx)
+                            .process()
+                            .await;
+                    }
+                });
+            }
+        });
+        Ok(addr)
+    }
+
+    async fn connect(
+        connector: &TlsClientConnector,
+        addr: SocketAddr,
+    ) -> Result<ClientStream<ClientTlsStream<TcpStream>>> {
+        let stream = TcpStream::connect(addr).await?;
+        let stream = connector.connect(stream).await?;
+        Ok(ClientStream::new(stream))
+    }
+
+    // 只有 admin 可以写
+    fn admin_only(cmd: &CommandRequest, ctx: &RequestContext) -> Result<(), KvError> {
+        match (&cmd.request_data, &ctx.peer) {
+            (Some(RequestData::Hget(_)), _) => Ok(()),
+            (_, Some(peer)) if peer.matches("admin.acme.inc") => Ok(()),
+            (_, Some(peer)) => Err(KvError::PermissionDenied(peer.subject.clone())),
+            (_, None) => Err(KvError::PermissionDenied("anonymous".into())),
+        }
+    }
+
+    #[tokio::test]
+    async fn tls_should_work() -> Result<()> {
+        let (ca, cert, key) = generate_server_certs();
+        let acceptor = TlsServerAcceptor::new(&cert, &key, None)?;
+        let addr = start_tls_server(acceptor, ServiceInner::new(MemTable::new()).build()).await?;
+
+        let connector = TlsClientConnector::new(DOMAIN, None, Some(&ca))?;
+        let mut client = connect(&connector, addr).await?;
+
+        let resp = client
+            .execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await?;
+        assert_res_ok(&resp, &[Value::default()], &[]);
+        let resp = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_res_ok(&resp, &["v1".into()], &[]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_with_untrusted_ca_should_fail() -> Result<()> {
+        let (_, cert, key) = generate_server_certs();
+        let (other_ca, _, _) = generate_server_certs();
+        let acceptor = TlsServerAcceptor::new(&cert, &key, None)?;
+        let addr = start_tls_server(acceptor, ServiceInner::new(MemTable::new()).build()).await?;
+
+        let connector = TlsClientConnector::new(DOMAIN, None, Some(&other_ca))?;
+        assert!(connect(&connector, addr).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mtls_should_pass_peer_identity_to_req_hooks() -> Result<()> {
+        let (ca_cert, ca) = generate_test_ca();
+        let (cert, key) = generate_test_cert(&ca, DOMAIN, "Acme KV server", false);
+        let acceptor = TlsServerAcceptor::new(&cert, &key, Some(&ca_cert))?;
+        let service = ServiceInner::new(MemTable::new())
+            .add_req_hook(admin_only)
+            .build();
+        let addr = start_tls_server(acceptor, service).await?;
+
+        let (cert, key) = generate_test_cert(&ca, "admin.acme.inc", "admin", true);
+        let connector = TlsClientConnector::new(DOMAIN, Some((&cert, &key)), Some(&ca_cert))?;
+        let mut admin = connect(&connector, addr).await?;
+        let resp = admin
+            .execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await?;
+        assert_res_ok(&resp, &[Value::default()], &[]);
+
+        let (cert, key) = generate_test_cert(&ca, "reader.acme.inc", "reader", true);
+        let connector = TlsClientConnector::new(DOMAIN, Some((&cert, &key)), Some(&ca_cert))?;
+        let mut reader = connect(&connector, addr).await?;
+        let resp = reader
+            .execute(CommandRequest::new_hset("t1", "k1", "v2".into()))
+            .await?;
+        assert_res_error(resp, 403, "CN=reader");
+        let resp = reader.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_res_ok(&resp, &["v1".into()], &[]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mtls_should_reject_client_without_trusted_cert() -> Result<()> {
+        let (ca_cert, ca) = generate_test_ca();
+        let (cert, key) = generate_test_cert(&ca, DOMAIN, "Acme KV server", false);
+        let acceptor = TlsServerAcceptor::new(&cert, &key, Some(&ca_cert))?;
+        let addr = start_tls_server(acceptor, ServiceInner::new(MemTable::new()).build()).await?;
+
+        // 没有客户端证书
+        let connector = TlsClientConnector::new(DOMAIN, None, Some(&ca_cert))?;
+        let mut client = connect(&connector, addr).await?;
+        assert!(
+            client
+                .execute(CommandRequest::new_hget("t1", "k1"))
+                .await
+                .is_err()
+        );
+
+        // 客户端证书不是这个 CA 签发的
+        let (_, other_ca) = generate_test_ca();
+        let (cert, key) = generate_test_cert(&other_ca, "admin.acme.inc", "admin", true);
+        let connector = TlsClientConnector::new(DOMAIN, Some((&cert, &key)), Some(&ca_cert))?;
+        let mut client = connect(&connector, addr).await?;
+        assert!(
+            client
+                .execute(CommandRequest::new_hget("t1", "k1"))
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn client_cert_should_parse_into_peer_identity() {
+        let (_, ca) = generate_test_ca();
+        let (cert, _) = generate_test_cert(&ca, "admin.acme.inc", "admin", true);
+        let identity = parse_identity(&load_certs("client", &cert).unwrap()[0]).unwrap();
+        assert_eq!(identity.common_name.as_deref(), Some("admin"));
+        // certify 会额外加上本机的 IP
+        assert_eq!(identity.sans, vec!["admin.acme.inc", "127.0.0.1", "::1"]);
+        assert!(identity.subject.contains("O=Acme Inc."));
+        assert!(identity.matches("admin.acme.inc"));
+        assert!(!identity.matches("reader.acme.inc"));
+    }
+
+    #[test]
+    fn invalid_pem_should_return_certificate_error() {
+        let (ca, cert, key) = generate_server_certs();
+        assert!(matches!(
+            TlsServerAcceptor::new("bad cert", &key, None),
+            Err(KvError::CertifcateParseError("server", "cert"))
+        ));
+        assert!(matches!(
+            TlsServerAcceptor::new(&cert, "bad key", None),
+            Err(KvError::CertifcateParseError("server", "key"))
+        ));
+        assert!(matches!(
+            TlsServerAcceptor::new(&cert, &key, Some("bad ca")),
+            Err(KvError::CertifcateParseError("CA", "cert"))
+        ));
+        assert!(matches!(
+            TlsClientConnector::new(DOMAIN, None, Some("bad ca")),
+            Err(KvError::CertifcateParseError("CA", "cert"))
+        ));
+        assert!(matches!(
+            TlsClientConnector::new(DOMAIN, Some(("bad cert", &key)), Some(&ca)),
+            Err(KvError::CertifcateParseError("client", "cert"))
+        ));
+        assert!(matches!(
+            TlsClientConnector::new(DOMAIN, Some((&cert, "bad key")), Some(&ca)),
+            Err(KvError::CertifcateParseError("client", "key"))
+        ));
+        assert!(TlsClientConnector::new(DOMAIN, None, Some(&ca)).is_ok());
+    }
+}
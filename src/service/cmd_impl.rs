@@ -1,4 +1,5 @@
-use crate::{CmdService, CommandResponse, KvError, MemTable, SledDb, Storage};
+use crate::storage::{DEFAULT_SCAN_LIMIT, MAX_SCAN_LIMIT};
+use crate::{CmdService, CommandResponse, KvError, RequestData, Storage, TxnOp};
 
 use crate::cmd::abi::*;
 
@@ -14,12 +15,27 @@ impl CmdService for Hget {
 
 impl CmdService for Hset {
     fn execute(self, store: &impl Storage) -> CommandResponse {
+        let condition = self.condition();
         match self.pair {
-            Some(v) => match store.set(&self.table, v.key, v.value.unwrap_or_default()) {
-                Ok(Some(v)) => v.into(),
-                Ok(None) => Value::default().into(),
-                Err(e) => e.into(),
-            },
+            Some(v) => {
+                let value = v.value.unwrap_or_default();
+                // 带 ttl 的写入和过期时间在 storage 里一起生效
+                let res = match (condition, self.ttl_ms) {
+                    (SetCondition::Always, 0) => store.set(&self.table, v.key, value),
+                    (condition, ttl_ms) => store.set_if(
+                        &self.table,
+                        v.key,
+                        value,
+                        condition,
+                        self.expected.as_ref(),
+                        ttl_ms,
+                    ),
+                };
+                match res {
+                    Ok(old) => old.unwrap_or_default().into(),
+                    Err(e) => e.into(),
+                }
+            }
             None => KvError::InvalidCommand(format!("{:?}", self)).into(),
         }
     }
@@ -35,8 +51,219 @@ impl CmdService for Hdel {
     }
 }
 
+impl CmdService for Hexpire {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.expire(&self.table, &self.key, self.ttl_ms) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CmdService for Httl {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.ttl(&self.table, &self.key) {
+            Ok(Some(ttl)) => Value::from(ttl as i64).into(),
+            Ok(None) => match store.contains(&self.table, &self.key) {
+                Ok(true) => Value::from(-1).into(),
+                Ok(false) => {
+                    KvError::NotFound(format!("table {} key {}", self.table, self.key)).into()
+                }
+                Err(e) => e.into(),
+            },
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CmdService for Hpersist {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.persist(&self.table, &self.key) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CmdService for Hincrby {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.incr(&self.table, &self.key, self.delta) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CmdService for Hincrbyfloat {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.incr_float(&self.table, &self.key, self.delta) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CmdService for Txn {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let to_ops = |cmds: Vec<CommandRequest>| -> Result<Vec<TxnOp>, KvError> {
+            cmds.into_iter().map(TxnOp::try_from).collect()
+        };
+        let (success, failure) = match (to_ops(self.success), to_ops(self.failure)) {
+            (Ok(success), Ok(failure)) => (success, failure),
+            (Err(e), _) | (_, Err(e)) => return e.into(),
+        };
+        match store.txn(&self.compares, &success, &failure) {
+            Ok((succeeded, results)) => {
+                let mut values = Vec::with_capacity(results.len() + 1);
+                values.push(succeeded.into());
+                values.extend(results.into_iter().map(|r| r.value));
+                values.into()
+            }
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl TryFrom<CommandRequest> for TxnOp {
+    type Error = KvError;
+
+    fn try_from(cmd: CommandRequest) -> Result<Self, Self::Error> {
+        let op = match cmd.request_data {
+            Some(RequestData::Hget(Hget { table, key })) => TxnOp::Get { table, key },
+            Some(RequestData::Hset(Hset {
+                table,
+                pair: Some(Kvpair { key, value }),
+                ttl_ms: 0,
+                condition: 0,
+                expected: None,
+            })) => TxnOp::Set {
+                table,
+                key,
+                value: value.unwrap_or_default(),
+            },
+            Some(RequestData::Hdel(Hdel { table, key })) => TxnOp::Del { table, key },
+            Some(RequestData::Hincrby(Hincrby { table, key, delta })) => {
+                TxnOp::Incr { table, key, delta }
+            }
+            Some(RequestData::Hincrbyfloat(Hincrbyfloat { table, key, delta })) => {
+                TxnOp::IncrFloat { table, key, delta }
+            }
+            data => {
+                return Err(KvError::InvalidCommand(format!(
+                    "{:?} is not supported in a transaction",
+                    data
+                )));
+            }
+        };
+        Ok(op)
+    }
+}
+
+impl CmdService for Hgetall {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.get_all(&self.table) {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CmdService for Hscan {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let limit = match self.limit as usize {
+            0 => DEFAULT_SCAN_LIMIT,
+            n => n.min(MAX_SCAN_LIMIT),
+        };
+        match store.scan(&self.table, &self.prefix, &self.cursor, limit) {
+            Ok((pairs, next)) => {
+                let mut resp: CommandResponse = pairs.into();
+                resp.values = vec![next.unwrap_or_default().into()];
+                resp
+            }
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CmdService for Hmget {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let mut values = Vec::with_capacity(self.keys.len());
+        for key in self.keys.iter() {
+            match store.get(&self.table, key) {
+                Ok(v) => values.push(v.unwrap_or_default()),
+                Err(e) => return e.into(),
+            }
+        }
+        values.into()
+    }
+}
+
+// 放在一个事务里执行，中间出错时整批都不生效
+impl CmdService for Hmset {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let ops: Vec<TxnOp> = self
+            .pairs
+            .into_iter()
+            .map(|pair| TxnOp::Set {
+                table: self.table.clone(),
+                key: pair.key,
+                value: pair.value.unwrap_or_default(),
+            })
+            .collect();
+        match store.txn(&[], &ops, &[]) {
+            Ok((_, results)) => results
+                .into_iter()
+                .map(|r| r.value)
+                .collect::<Vec<_>>()
+                .into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CmdService for Hmdel {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let ops: Vec<TxnOp> = self
+            .keys
+            .into_iter()
+            .map(|key| TxnOp::Del {
+                table: self.table.clone(),
+                key,
+            })
+            .collect();
+        match store.txn(&[], &ops, &[]) {
+            Ok((_, results)) => results
+                .into_iter()
+                .map(|r| r.value)
+                .collect::<Vec<_>>()
+                .into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CmdService for Ping {
+    fn execute(self, _store: &impl Storage) -> CommandResponse {
+        Value::from("PONG").into()
+    }
+}
+
+impl CmdService for Hmexist {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let mut values: Vec<Value> = Vec::with_capacity(self.keys.len());
+        for key in self.keys.iter() {
+            match store.contains(&self.table, key) {
+                Ok(v) => values.push(v.into()),
+                Err(e) => return e.into(),
+            }
+        }
+        values.into()
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use crate::MemTable;
     use crate::service::{assert_res_error, assert_res_ok, exec_cmd};
 
     use super::*;
@@ -51,6 +278,12 @@ mod tests {
         assert_res_ok(&res, &[10.into()], &[]);
     }
 
+    #[test]
+    fn ping_should_return_pong() {
+        let res = exec_cmd(CommandRequest::new_ping(), &MemTable::new());
+        assert_res_ok(&res, &["PONG".into()], &[]);
+    }
+
     #[test]
     fn hget_with_non_exist_key_should_return_404() {
         let store = MemTable::new();
@@ -72,6 +305,34 @@ mod tests {
         assert_res_ok(&res, &["world".into()], &[]);
     }
 
+    #[test]
+    fn hset_if_should_work() {
+        let store = MemTable::new();
+        let cmd =
+            CommandRequest::new_hset_if("t1", "k1", "v1".into(), SetCondition::IfAbsent, None);
+        let res = exec_cmd(cmd.clone(), &store);
+        assert_res_ok(&res, &[Value::default()], &[]);
+        let res = exec_cmd(cmd, &store);
+        assert_res_error(res, 412, "Precondition failed");
+
+        let cmd = CommandRequest::new_hset_if(
+            "t1",
+            "k1",
+            "v2".into(),
+            SetCondition::IfEqual,
+            Some("v1".into()),
+        );
+        let res = exec_cmd(cmd.clone(), &store);
+        assert_res_ok(&res, &["v1".into()], &[]);
+        let res = exec_cmd(cmd, &store);
+        assert_res_error(res, 412, "Precondition failed");
+
+        let cmd =
+            CommandRequest::new_hset_if("t1", "k2", "v1".into(), SetCondition::IfPresent, None);
+        let res = exec_cmd(cmd, &store);
+        assert_res_error(res, 412, "Precondition failed");
+    }
+
     #[test]
     fn hdel_should_work() {
         let store = MemTable::new();
@@ -84,6 +345,184 @@ mod tests {
         assert_res_ok(&res, &["v1".into()], &[]);
     }
 
+    #[test]
+    fn hset_with_ttl_should_expire() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_hset_with_ttl("t1", "hello", "world".into(), 10_000);
+        let res = exec_cmd(cmd, &store);
+        assert_res_ok(&res, &[Value::default()], &[]);
+        let res = exec_cmd(CommandRequest::new_httl("t1", "hello"), &store);
+        let ttl: i64 = (&res).try_into().unwrap();
+        assert!(ttl > 9_000 && ttl <= 10_000);
+
+        let cmd = CommandRequest::new_hexpire("t1", "hello", 0);
+        let res = exec_cmd(cmd, &store);
+        assert_res_ok(&res, &[true.into()], &[]);
+        let res = exec_cmd(CommandRequest::new_hget("t1", "hello"), &store);
+        assert_res_error(res, 404, "Not found");
+        let res = exec_cmd(CommandRequest::new_httl("t1", "hello"), &store);
+        assert_res_error(res, 404, "Not found");
+    }
+
+    #[test]
+    fn hpersist_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("u1", "v1")], &store);
+        let res = exec_cmd(CommandRequest::new_httl("t1", "u1"), &store);
+        assert_res_ok(&res, &[(-1).into()], &[]);
+        let res = exec_cmd(CommandRequest::new_hpersist("t1", "u1"), &store);
+        assert_res_ok(&res, &[false.into()], &[]);
+        exec_cmd(CommandRequest::new_hexpire("t1", "u1", 10_000), &store);
+        let res = exec_cmd(CommandRequest::new_hpersist("t1", "u1"), &store);
+        assert_res_ok(&res, &[true.into()], &[]);
+        let res = exec_cmd(CommandRequest::new_httl("t1", "u1"), &store);
+        assert_res_ok(&res, &[(-1).into()], &[]);
+    }
+
+    #[test]
+    fn hincrby_should_work() {
+        let store = MemTable::new();
+        let res = exec_cmd(CommandRequest::new_hincrby("score", "u1", 10), &store);
+        assert_res_ok(&res, &[10.into()], &[]);
+        let res = exec_cmd(CommandRequest::new_hincrby("score", "u1", -3), &store);
+        assert_res_ok(&res, &[7.into()], &[]);
+        let res = exec_cmd(CommandRequest::new_hincrbyfloat("score", "u1", 0.5), &store);
+        assert_res_ok(&res, &[7.5.into()], &[]);
+    }
+
+    #[test]
+    fn hincrby_on_non_numeric_value_should_return_400() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("u1", "v1")], &store);
+        let res = exec_cmd(CommandRequest::new_hincrby("t1", "u1", 1), &store);
+        assert_res_error(res, 400, "Cannot convert value");
+        let res = exec_cmd(CommandRequest::new_hincrbyfloat("t1", "u1", 1.0), &store);
+        assert_res_error(res, 400, "Cannot convert value");
+    }
+
+    #[test]
+    fn txn_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("lock", vec![("leader", "n1")], &store);
+        let compares = vec![Compare::new(
+            "lock",
+            "leader",
+            CompareOp::Equal,
+            Some("n1".into()),
+        )];
+        let success = vec![
+            CommandRequest::new_hset("lock", "leader", "n2".into()),
+            CommandRequest::new_hincrby("lock", "term", 1),
+        ];
+        let failure = vec![CommandRequest::new_hget("lock", "leader")];
+        let cmd = CommandRequest::new_txn(compares, success, failure);
+        let res = exec_cmd(cmd.clone(), &store);
+        assert_res_ok(&res, &[true.into(), "n1".into(), 1.into()], &[]);
+        let res = exec_cmd(cmd, &store);
+        assert_res_ok(&res, &[false.into(), "n2".into()], &[]);
+    }
+
+    #[test]
+    fn txn_with_unsupported_command_should_return_400() {
+        let store = MemTable::new();
+        let success = vec![
+            CommandRequest::new_hset("t1", "k1", "v1".into()),
+            CommandRequest::new_hgetall("t1"),
+        ];
+        let cmd = CommandRequest::new_txn(vec![], success, vec![]);
+        let res = exec_cmd(cmd, &store);
+        assert_res_error(res, 400, "not supported in a transaction");
+        // 整个事务都没有执行
+        let res = exec_cmd(CommandRequest::new_hget("t1", "k1"), &store);
+        assert_res_error(res, 404, "Not found");
+    }
+
+    #[test]
+    fn hgetall_should_work() {
+        let store = MemTable::new();
+        set_key_pairs(
+            "score",
+            vec![("u1", 10), ("u2", 8), ("u3", 11), ("u1", 6)],
+            &store,
+        );
+        let cmd = CommandRequest::new_hgetall("score");
+        let res = exec_cmd(cmd, &store);
+        let pairs = &[
+            Kvpair::new("u1", 6.into()),
+            Kvpair::new("u2", 8.into()),
+            Kvpair::new("u3", 11.into()),
+        ];
+        assert_res_ok(&res, &[], pairs);
+    }
+
+    #[test]
+    fn hscan_should_work() {
+        let store = MemTable::new();
+        set_key_pairs(
+            "score",
+            vec![("u1", 10), ("u2", 8), ("u3", 11), ("x1", 6)],
+            &store,
+        );
+        let cmd = CommandRequest::new_hscan("score", "u", "", 2);
+        let res = exec_cmd(cmd, &store);
+        let pairs = &[Kvpair::new("u1", 10.into()), Kvpair::new("u2", 8.into())];
+        assert_res_ok(&res, &["u2".into()], pairs);
+
+        let cmd = CommandRequest::new_hscan("score", "u", "u2", 2);
+        let res = exec_cmd(cmd, &store);
+        assert_res_ok(&res, &["".into()], &[Kvpair::new("u3", 11.into())]);
+    }
+
+    #[test]
+    fn hmget_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("user", vec![("u1", "Tyr"), ("u2", "Lindsey")], &store);
+        let cmd = CommandRequest::new_hmget("user", vec!["u2".into(), "u3".into(), "u1".into()]);
+        let res = exec_cmd(cmd, &store);
+        assert_res_ok(
+            &res,
+            &["Lindsey".into(), Value::default(), "Tyr".into()],
+            &[],
+        );
+    }
+
+    #[test]
+    fn hmset_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("u1", "v1")], &store);
+        let pairs = vec![
+            Kvpair::new("u2", "v2".into()),
+            Kvpair::new("u1", "v3".into()),
+        ];
+        let cmd = CommandRequest::new_hmset("t1", pairs);
+        let res = exec_cmd(cmd, &store);
+        assert_res_ok(&res, &[Value::default(), "v1".into()], &[]);
+        let cmd = CommandRequest::new_hmget("t1", vec!["u1".into(), "u2".into()]);
+        let res = exec_cmd(cmd, &store);
+        assert_res_ok(&res, &["v3".into(), "v2".into()], &[]);
+    }
+
+    #[test]
+    fn hmdel_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("u1", "v1"), ("u2", "v2")], &store);
+        let cmd = CommandRequest::new_hmdel("t1", vec!["u2".into(), "u3".into(), "u1".into()]);
+        let res = exec_cmd(cmd, &store);
+        assert_res_ok(&res, &["v2".into(), Value::default(), "v1".into()], &[]);
+        let cmd = CommandRequest::new_hmexist("t1", vec!["u1".into(), "u2".into()]);
+        let res = exec_cmd(cmd, &store);
+        assert_res_ok(&res, &[false.into(), false.into()], &[]);
+    }
+
+    #[test]
+    fn hmexist_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("u1", "v1"), ("u2", "v2")], &store);
+        let cmd = CommandRequest::new_hmexist("t1", vec!["u1".into(), "u3".into(), "u2".into()]);
+        let res = exec_cmd(cmd, &store);
+        assert_res_ok(&res, &[true.into(), false.into(), true.into()], &[]);
+    }
+
     fn set_key_pairs<T: Into<Value>>(table: &str, pairs: Vec<(&str, T)>, store: &impl Storage) {
         pairs
             .into_iter()
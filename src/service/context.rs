@@ -0,0 +1,60 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+/// 通过 mTLS 验证过的客户端身份
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerIdentity {
+    /// 证书的完整 subject，比如 `C=CN, O=Acme Inc., CN=kv-client`
+    pub subject: String,
+    /// subject 里的 CN
+    pub common_name: Option<String>,
+    /// subjectAltName 里的 DNS / email / URI / IP
+    pub sans: Vec<String>,
+}
+
+impl PeerIdentity {
+    /// CN 或者任意一个 SAN 等于 name
+    pub fn matches(&self, name: &str) -> bool {
+        self.common_name.as_deref() == Some(name) || self.sans.iter().any(|s| s == name)
+    }
+}
+
+/// 一个连接上所有请求共享的上下文，req hook 可以根据它做鉴权
+#[derive(Debug, Clone, Default)]
+pub struct RequestContext {
+    /// 没有开启 mTLS 时为 None
+    pub peer: Option<PeerIdentity>,
+    /// 握手时通过认证的用户名
+    pub user: Option<String>,
+    // 这个连接上创建的 subscription id，clone 出来的上下文共享同一份
+    pub(crate) subscriptions: SubscriptionIds,
+}
+
+impl RequestContext {
+    pub fn new(peer: Option<PeerIdentity>) -> Self {
+        Self {
+            peer,
+            ..Default::default()
+        }
+    }
+}
+
+/// 只有创建订阅的连接才能取消它，其它连接看到的是 NotFound
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SubscriptionIds(Arc<Mutex<HashSet<u32>>>);
+
+impl SubscriptionIds {
+    pub(crate) fn insert(&self, id: u32) {
+        self.0.lock().unwrap().insert(id);
+    }
+
+    pub(crate) fn remove(&self, id: u32) {
+        self.0.lock().unwrap().remove(&id);
+    }
+
+    pub(crate) fn contains(&self, id: u32) -> bool {
+        self.0.lock().unwrap().contains(&id)
+    }
+}
@@ -0,0 +1,433 @@
+use futures::stream;
+use prost::Message;
+use std::{collections::VecDeque, sync::Arc};
+
+use crate::{
+    CommandRequest, CommandResponse, Compare, CompareOp, KvError, MsgBus, PubSub, Publish,
+    RequestData, Storage, Subscribe, TxnOp, Value,
+    service::{ServiceInner, subscription::SubscriptionReceiver, topic_service::StreamingResponse},
+    storage::{DEFAULT_SCAN_LIMIT, now_ms},
+    value,
+};
+
+// durable topic 的 log 存在 `__topic@{topic}` 这个 table 里，key 是补零的 offset
+const LOG_TABLE_PREFIX: &str = "__topic@";
+// 每个 topic 最后一个 offset
+const OFFSET_TABLE: &str = "__topic_offsets";
+/// 每个 durable topic 默认保留的消息数
+pub const DEFAULT_DURABLE_RETENTION: u64 = 100_000;
+
+fn log_table(topic: &str) -> String {
+    format!("{}{}", LOG_TABLE_PREFIX, topic)
+}
+
+// 补零之后字典序和数字大小一致，可以直接用 scan 顺序读
+fn offset_key(offset: u64) -> String {
+    format!("{:020}", offset)
+}
+
+// durable topic 的 log 和 offset 只能通过 Publish 修改
+fn is_reserved_table(table: &str) -> bool {
+    table.starts_with(LOG_TABLE_PREFIX) || table == OFFSET_TABLE
+}
+
+/// 拒绝直接修改 durable topic 的 log 和 offset 的请求，读不受影响
+pub fn check_write(cmd: &CommandRequest) -> Result<(), KvError> {
+    let table = match &cmd.request_data {
+        Some(RequestData::Hset(param)) => &param.table,
+        Some(RequestData::Hdel(param)) => &param.table,
+        Some(RequestData::Hexpire(param)) => &param.table,
+        Some(RequestData::Hpersist(param)) => &param.table,
+        Some(RequestData::Hincrby(param)) => &param.table,
+        Some(RequestData::Hincrbyfloat(param)) => &param.table,
+        Some(RequestData::Hmset(param)) => &param.table,
+        Some(RequestData::Hmdel(param)) => &param.table,
+        Some(RequestData::Txn(param)) => {
+            return param
+                .success
+                .iter()
+                .chain(&param.failure)
+                .try_for_each(check_write);
+        }
+        _ => return Ok(()),
+    };
+    if is_reserved_table(table) {
+        return Err(KvError::PermissionDenied(format!(
+            "table {} is reserved for durable topics",
+            table
+        )));
+    }
+    Ok(())
+}
+
+/// 把消息追加到 topic 的 log，填上 offset 和写入时间
+/// offset 和 log 在同一个事务里写入，offset 被并发的 append 改掉时重试；
+/// retention 大于 0 时顺带删掉超出保留数量的那一条消息
+pub fn append(
+    store: &impl Storage,
+    msg: &mut CommandResponse,
+    retention: u64,
+) -> Result<u64, KvError> {
+    let table = log_table(&msg.topic);
+    loop {
+        let last = store.get(OFFSET_TABLE, &msg.topic)?;
+        let (op, offset) = match &last {
+            Some(v) => (CompareOp::Equal, i64::try_from(v)? as u64 + 1),
+            None => (CompareOp::NotExists, 1),
+        };
+        msg.offset = offset;
+        msg.timestamp_ms = now_ms();
+        let buf: Value = Value {
+            value: Some(value::Value::Binary(msg.encode_to_vec().into())),
+        };
+        let compares = [Compare::new(OFFSET_TABLE, &msg.topic, op, last)];
+        let mut ops = vec![
+            TxnOp::Set {
+                table: OFFSET_TABLE.into(),
+                key: msg.topic.clone(),
+                value: (offset as i64).into(),
+            },
+            TxnOp::Set {
+                table: table.clone(),
+                key: offset_key(offset),
+                value: buf,
+            },
+        ];
+        if retention > 0 && offset > retention {
+            ops.push(TxnOp::Del {
+                table: table.clone(),
+                key: offset_key(offset - retention),
+            });
+        }
+        if store.txn(&compares, &ops, &[])?.0 {
+            return Ok(offset);
+        }
+    }
+}
+
+/// 从 from_offset 开始按顺序读最多 limit 条消息
+pub fn read(
+    store: &impl Storage,
+    topic: &str,
+    from_offset: u64,
+    limit: usize,
+) -> Result<Vec<CommandResponse>, KvError> {
+    // scan 返回大于 cursor 的 key
+    let cursor = offset_key(from_offset.saturating_sub(1));
+    let (pairs, _) = store.scan(&log_table(topic), "", &cursor, limit)?;
+    pairs
+        .into_iter()
+        .map(|pair| match pair.value.and_then(|v| v.value) {
+            Some(value::Value::Binary(buf)) => Ok(CommandResponse::decode(buf)?),
+            v => Err(KvError::ConvertError(format!("{:?}", v), "CommandResponse")),
+        })
+        .collect()
+}
+
+/// 二分查找第一条写入时间不早于 timestamp_ms 的消息的 offset，
+/// 所有消息都更早时返回最后一个 offset + 1
+pub fn seek(store: &impl Storage, topic: &str, timestamp_ms: u64) -> Result<u64, KvError> {
+    let last = match store.get(OFFSET_TABLE, topic)? {
+        Some(v) => i64::try_from(&v)? as u64,
+        None => 0,
+    };
+    let (mut lo, mut hi) = (1, last + 1);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        match read(store, topic, mid, 1)?.first() {
+            Some(msg) if msg.timestamp_ms < timestamp_ms => lo = msg.offset + 1,
+            _ => hi = mid,
+        }
+    }
+    Ok(lo)
+}
+
+/// 先写 log 再实时推送，写 log 失败时不推送
+pub fn publish(
+    param: Publish,
+    store: &impl Storage,
+    retention: u64,
+    bus: Arc<MsgBus>,
+) -> StreamingResponse {
+    let mut msg: CommandResponse = param.data.into();
+    msg.topic = param.topic;
+    let resp = match append(store, &mut msg, retention) {
+        Ok(offset) => {
+            bus.publish(msg.topic.clone(), Arc::new(msg));
+            Value::from(offset as i64).into()
+        }
+        Err(e) => e.into(),
+    };
+    Box::pin(stream::once(async { Arc::new(resp) }))
+}
+
+struct Replay<Store: Storage> {
+    inner: Arc<ServiceInner<Store>>,
+    topic: String,
+    live: SubscriptionReceiver,
+    // 第一条消息是 subscription id
+    id_sent: bool,
+    buffer: VecDeque<CommandResponse>,
+    next_offset: u64,
+    replaying: bool,
+}
+
+/// 先订阅实时消息，再从 log 里 replay，最后切换到实时推送；
+/// replay 期间到达的实时消息先留在订阅的队列里，切换时按 offset 去重；
+/// 实时推送中发现 offset 不连续（消息被丢弃，或者并发 publish 的顺序交错）时回到 log 里补上
+pub fn subscribe<Store>(
+    param: Subscribe,
+    inner: Arc<ServiceInner<Store>>,
+    bus: Arc<MsgBus>,
+) -> StreamingResponse
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    let options = param.options.unwrap_or_default();
+    let from = match options.from_offset {
+        0 => seek(&inner.store, &param.topic, options.from_timestamp_ms),
+        n => Ok(n),
+    };
+    let next_offset = match from {
+        Ok(n) => n,
+        Err(e) => return Box::pin(stream::once(async { Arc::new(e.into()) })),
+    };
+    let live = bus.subscribe(param.topic.clone(), options);
+    let state = Replay {
+        inner,
+        topic: param.topic,
+        live,
+        id_sent: false,
+        buffer: VecDeque::new(),
+        next_offset,
+        replaying: true,
+    };
+    Box::pin(stream::unfold(state, |mut st| async move {
+        if !st.id_sent {
+            st.id_sent = true;
+            let id = st.live.recv().await?;
+            return Some((id, st));
+        }
+        loop {
+            while st.replaying {
+                if let Some(msg) = st.buffer.pop_front() {
+                    st.next_offset = msg.offset + 1;
+                    return Some((Arc::new(msg), st));
+                }
+                match read(
+                    &st.inner.store,
+                    &st.topic,
+                    st.next_offset,
+                    DEFAULT_SCAN_LIMIT,
+                ) {
+                    Ok(page) if page.is_empty() => st.replaying = false,
+                    Ok(page) => st.buffer.extend(page),
+                    Err(e) => {
+                        st.replaying = false;
+                        return Some((Arc::new(e.into()), st));
+                    }
+                }
+            }
+            let msg = st.live.recv().await?;
+            match msg.offset {
+                // 不是 log 里的消息（比如订阅被断开时的错误），直接转发
+                0 => return Some((msg, st)),
+                n if n < st.next_offset => {}
+                n if n == st.next_offset => {
+                    st.next_offset = n + 1;
+                    return Some((msg, st));
+                }
+                // 中间缺了消息，log 里一定已经有了，回到 replay
+                _ => st.replaying = true,
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        MemTable, OverflowPolicy, RequestContext, Service, SledDb, SubscribeOptions,
+        assert_res_error, assert_res_ok,
+    };
+    use futures::StreamExt;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    fn build<Store: Storage + Send + Sync + 'static>(store: Store) -> Service<Store> {
+        ServiceInner::new(store)
+            .add_durable_topic("orders.*")
+            .build()
+    }
+
+    async fn publish<Store: Storage + Send + Sync + 'static>(
+        service: &Service<Store>,
+        topic: &str,
+        v: i64,
+    ) -> i64 {
+        let mut res = service.process_request(
+            CommandRequest::new_publish(topic, vec![v.into()]),
+            &RequestContext::default(),
+        );
+        (&res.next().await.unwrap().values[0]).try_into().unwrap()
+    }
+
+    async fn next_value(res: &mut StreamingResponse) -> (u64, i64) {
+        let msg = tokio::time::timeout(Duration::from_secs(1), res.next())
+            .await
+            .unwrap()
+            .unwrap();
+        (msg.offset, (&msg.values[0]).try_into().unwrap())
+    }
+
+    #[tokio::test]
+    async fn durable_topic_should_replay_from_offset_then_go_live() {
+        let service = build(MemTable::new());
+        for i in 1..=3 {
+            assert_eq!(publish(&service, "orders.eu", i * 10).await, i);
+        }
+
+        let options = SubscribeOptions::default().from_offset(2);
+        let cmd = CommandRequest::new_subscribe_with_options("orders.eu", options);
+        let mut res = service.process_request(cmd, &RequestContext::default());
+        next_value(&mut res).await;
+        assert_eq!(next_value(&mut res).await, (2, 20));
+        assert_eq!(next_value(&mut res).await, (3, 30));
+
+        publish(&service, "orders.eu", 40).await;
+        assert_eq!(next_value(&mut res).await, (4, 40));
+    }
+
+    #[tokio::test]
+    async fn durable_topic_should_replay_from_timestamp() {
+        let service = build(MemTable::new());
+        publish(&service, "orders.eu", 10).await;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let ts = now_ms();
+        publish(&service, "orders.eu", 20).await;
+        publish(&service, "orders.eu", 30).await;
+
+        let options = SubscribeOptions::default().from_timestamp_ms(ts);
+        let cmd = CommandRequest::new_subscribe_with_options("orders.eu", options);
+        let mut res = service.process_request(cmd, &RequestContext::default());
+        next_value(&mut res).await;
+        assert_eq!(next_value(&mut res).await, (2, 20));
+        assert_eq!(next_value(&mut res).await, (3, 30));
+    }
+
+    #[tokio::test]
+    async fn durable_log_should_survive_restart() {
+        let dir = tempdir().unwrap();
+        {
+            let service = build(SledDb::new(dir.path()));
+            publish(&service, "orders.eu", 10).await;
+            publish(&service, "orders.eu", 20).await;
+        }
+        // sled 的后台线程可能还没有释放文件锁
+        let mut store = SledDb::try_new(dir.path());
+        for _ in 0..100 {
+            if store.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            store = SledDb::try_new(dir.path());
+        }
+        let service = build(store.unwrap());
+        assert_eq!(publish(&service, "orders.eu", 30).await, 3);
+
+        let options = SubscribeOptions::default().from_offset(1);
+        let cmd = CommandRequest::new_subscribe_with_options("orders.eu", options);
+        let mut res = service.process_request(cmd, &RequestContext::default());
+        next_value(&mut res).await;
+        for i in 1..=3 {
+            assert_eq!(next_value(&mut res).await, (i, i as i64 * 10));
+        }
+    }
+
+    #[tokio::test]
+    async fn live_gap_should_be_filled_from_log() {
+        let service = build(MemTable::new());
+        publish(&service, "orders.eu", 10).await;
+        // 队列只有一个位置，来不及读的实时消息会被丢掉
+        let options = SubscribeOptions::new(OverflowPolicy::DropNewest, 1, 0).from_offset(1);
+        let cmd = CommandRequest::new_subscribe_with_options("orders.eu", options);
+        let mut res = service.process_request(cmd, &RequestContext::default());
+        next_value(&mut res).await;
+        assert_eq!(next_value(&mut res).await, (1, 10));
+        // replay 结束，切换到实时推送
+        let pending = tokio::time::timeout(Duration::from_millis(20), res.next()).await;
+        assert!(pending.is_err());
+
+        for i in 2..=4 {
+            publish(&service, "orders.eu", i * 10).await;
+        }
+        assert_eq!(next_value(&mut res).await, (2, 20));
+        // 3 和 4 在实时推送里被丢掉了，收到 5 的时候从 log 里补上
+        publish(&service, "orders.eu", 50).await;
+        for i in 3..=5 {
+            assert_eq!(next_value(&mut res).await, (i, i as i64 * 10));
+        }
+    }
+
+    #[tokio::test]
+    async fn durable_log_should_keep_retention_messages() {
+        let service: Service = ServiceInner::new(MemTable::new())
+            .add_durable_topic("orders.*")
+            .with_durable_retention(2)
+            .build();
+        for i in 1..=4 {
+            publish(&service, "orders.eu", i * 10).await;
+        }
+        let offsets: Vec<_> = read(&service.inner.store, "orders.eu", 1, 10)
+            .unwrap()
+            .into_iter()
+            .map(|msg| msg.offset)
+            .collect();
+        assert_eq!(offsets, vec![3, 4]);
+    }
+
+    #[tokio::test]
+    async fn durable_tables_should_reject_direct_writes() {
+        let service = build(MemTable::new());
+        publish(&service, "orders.eu", 10).await;
+        let txn = CommandRequest::new_txn(
+            vec![],
+            vec![CommandRequest::new_hdel(OFFSET_TABLE, "orders.eu")],
+            vec![],
+        );
+        for cmd in [
+            CommandRequest::new_hset(log_table("orders.eu"), offset_key(1), "x".into()),
+            CommandRequest::new_hincrby(OFFSET_TABLE, "orders.eu", 1),
+            txn,
+        ] {
+            let mut res = service.process_request(cmd, &RequestContext::default());
+            assert_res_error(
+                Arc::unwrap_or_clone(res.next().await.unwrap()),
+                403,
+                "reserved",
+            );
+        }
+        // 读不受影响
+        let mut res = service.process_request(
+            CommandRequest::new_hget(OFFSET_TABLE, "orders.eu"),
+            &RequestContext::default(),
+        );
+        assert_res_ok(&res.next().await.unwrap(), &[1.into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn non_durable_topic_should_not_keep_log() {
+        let service = build(MemTable::new());
+        let mut res = service.process_request(
+            CommandRequest::new_publish("users", vec![]),
+            &RequestContext::default(),
+        );
+        assert_res_ok(&res.next().await.unwrap(), &[], &[]);
+        assert!(
+            read(&service.inner.store, "users", 1, 10)
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
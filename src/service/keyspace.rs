@@ -0,0 +1,411 @@
+use std::sync::{Arc, Mutex};
+
+use crate::{
+    CommandResponse, Compare, KvError, Kvpair, MsgBus, PubSub, SetCondition, Storage, TxnOp,
+    TxnResult, Value, storage::KeyLocks,
+};
+
+const KEYSPACE_PREFIX: &str = "__keyspace@";
+
+/// 事件的类型，放在推送消息的 message 里
+pub const EVENT_SET: &str = "set";
+pub const EVENT_DEL: &str = "del";
+pub const EVENT_EXPIRE: &str = "expire";
+pub const EVENT_PERSIST: &str = "persist";
+pub const EVENT_EXPIRED: &str = "expired";
+
+/// 整个 table 的变更事件发布到 `__keyspace@{table}`，
+/// 单个 key 的变更事件发布到 `__keyspace@{table}:{key}`
+/// table 里的 `\` 和 `:` 会被转义，第一个没有转义的 `:` 之后才是 key，
+/// 所以 table `a:b` 和 table `a` 下的 key `b` 不会用到同一个 topic
+pub fn keyspace_topic(table: &str, key: Option<&str>) -> String {
+    let table = table.replace('\\', "\\\\").replace(':', "\\:");
+    match key {
+        Some(key) => format!("{}{}:{}", KEYSPACE_PREFIX, table, key),
+        None => format!("{}{}", KEYSPACE_PREFIX, table),
+    }
+}
+
+/// keyspace 的 topic 只能由服务端发布，客户端 publish 时返回错误
+pub fn check_publish(topic: &str) -> Result<(), KvError> {
+    if topic.starts_with(KEYSPACE_PREFIX) {
+        return Err(KvError::PermissionDenied(format!(
+            "topic {} is reserved for keyspace events",
+            topic
+        )));
+    }
+    Ok(())
+}
+
+/// 包一层 Storage，写操作成功之后把变更事件发布到 MsgBus
+/// 事件的 message 是事件类型（EVENT_*），values 是 [旧的值, 新的值]（不存在时为空 Value），
+/// pairs 是 [key => 新的值]
+/// 写入和推送都在 key 的锁里完成，同一个 key 的事件按写入的顺序到达订阅者
+pub struct KeyspaceNotifier<'a, S> {
+    store: &'a S,
+    bus: &'a Arc<MsgBus>,
+    locks: &'a KeyLocks,
+}
+
+impl<'a, S: Storage> KeyspaceNotifier<'a, S> {
+    pub(crate) fn new(store: &'a S, bus: &'a Arc<MsgBus>, locks: &'a KeyLocks) -> Self {
+        Self { store, bus, locks }
+    }
+
+    // 值的变化：set 或者 del，值没变时不推送
+    fn notify(&self, table: &str, key: &str, old: Option<&Value>, new: Option<&Value>) {
+        if old == new {
+            return;
+        }
+        let event = if new.is_some() { EVENT_SET } else { EVENT_DEL };
+        self.publish(table, key, event, old, new);
+    }
+
+    // 只改了过期时间，值没变：values 里新旧两个值相同
+    fn notify_unchanged(&self, table: &str, key: &str, event: &str) {
+        // 读值失败不影响已经成功的写操作，只是不推送
+        if let Ok(value) = self.store.get(table, key) {
+            self.publish(table, key, event, value.as_ref(), value.as_ref());
+        }
+    }
+
+    fn publish(
+        &self,
+        table: &str,
+        key: &str,
+        event: &str,
+        old: Option<&Value>,
+        new: Option<&Value>,
+    ) {
+        let topics = [
+            keyspace_topic(table, None),
+            keyspace_topic(table, Some(key)),
+        ];
+        let new = new.cloned().unwrap_or_default();
+        let msg = Arc::new(CommandResponse {
+            status: 200,
+            message: event.into(),
+            values: vec![old.cloned().unwrap_or_default(), new.clone()],
+            pairs: vec![Kvpair::new(key, new)],
+            ..Default::default()
+        });
+        for topic in topics {
+            // 没有人 watch 的时候不用额外 spawn publish 任务
+            if self.bus.has_subscribers(&topic) {
+                self.bus.clone().publish(topic, msg.clone());
+            }
+        }
+    }
+}
+
+impl<S: Storage> Storage for KeyspaceNotifier<'_, S> {
+    fn get(&self, table_name: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.store.get(table_name, key)
+    }
+
+    fn set(&self, table_name: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let _guard = self.locks.write(table_name, &key);
+        let old = self.store.set(table_name, key.clone(), value.clone())?;
+        self.notify(table_name, &key, old.as_ref(), Some(&value));
+        Ok(old)
+    }
+
+    fn del(&self, table_name: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let _guard = self.locks.write(table_name, key);
+        let old = self.store.del(table_name, key)?;
+        self.notify(table_name, key, old.as_ref(), None);
+        Ok(old)
+    }
+
+    fn set_if(
+        &self,
+        table_name: &str,
+        key: String,
+        value: Value,
+        condition: SetCondition,
+        expected: Option<&Value>,
+        ttl_ms: u64,
+    ) -> Result<Option<Value>, KvError> {
+        let _guard = self.locks.write(table_name, &key);
+        let old = self.store.set_if(
+            table_name,
+            key.clone(),
+            value.clone(),
+            condition,
+            expected,
+            ttl_ms,
+        )?;
+        self.notify(table_name, &key, old.as_ref(), Some(&value));
+        Ok(old)
+    }
+
+    fn contains(&self, table_name: &str, key: &str) -> Result<bool, KvError> {
+        self.store.contains(table_name, key)
+    }
+
+    fn get_all(&self, table_name: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.store.get_all(table_name)
+    }
+
+    fn get_iter(
+        &self,
+        table_name: &str,
+    ) -> Result<Box<dyn Iterator<Item = Result<Kvpair, KvError>>>, KvError> {
+        self.store.get_iter(table_name)
+    }
+
+    fn scan(
+        &self,
+        table_name: &str,
+        prefix: &str,
+        cursor: &str,
+        limit: usize,
+    ) -> Result<(Vec<Kvpair>, Option<String>), KvError> {
+        self.store.scan(table_name, prefix, cursor, limit)
+    }
+
+    fn expire(&self, table_name: &str, key: &str, ttl_ms: u64) -> Result<bool, KvError> {
+        let _guard = self.locks.write(table_name, key);
+        let ok = self.store.expire(table_name, key, ttl_ms)?;
+        if ok {
+            self.notify_unchanged(table_name, key, EVENT_EXPIRE);
+        }
+        Ok(ok)
+    }
+
+    fn ttl(&self, table_name: &str, key: &str) -> Result<Option<u64>, KvError> {
+        self.store.ttl(table_name, key)
+    }
+
+    fn persist(&self, table_name: &str, key: &str) -> Result<bool, KvError> {
+        let _guard = self.locks.write(table_name, key);
+        let ok = self.store.persist(table_name, key)?;
+        if ok {
+            self.notify_unchanged(table_name, key, EVENT_PERSIST);
+        }
+        Ok(ok)
+    }
+
+    fn reap_expired(&self) -> Result<Vec<(String, Kvpair)>, KvError> {
+        // 事先不知道会清理掉哪些 key，只能拿住所有的锁
+        let _guards = self.locks.write_every();
+        let reaped = self.store.reap_expired()?;
+        for (table_name, pair) in &reaped {
+            self.publish(
+                table_name,
+                &pair.key,
+                EVENT_EXPIRED,
+                pair.value.as_ref(),
+                None,
+            );
+        }
+        Ok(reaped)
+    }
+
+    fn update(
+        &self,
+        table_name: &str,
+        key: &str,
+        f: &dyn Fn(Option<&Value>) -> Result<Value, KvError>,
+    ) -> Result<Value, KvError> {
+        // f 可能被调用多次，最后一次调用时看到的才是真正被替换掉的旧值
+        let _guard = self.locks.write(table_name, key);
+        let old = Mutex::new(None);
+        let new = self.store.update(table_name, key, &|v| {
+            *old.lock().unwrap() = v.cloned();
+            f(v)
+        })?;
+        let old = old.into_inner().unwrap();
+        self.notify(table_name, key, old.as_ref(), Some(&new));
+        Ok(new)
+    }
+
+    fn txn(
+        &self,
+        compares: &[Compare],
+        success: &[TxnOp],
+        failure: &[TxnOp],
+    ) -> Result<(bool, Vec<TxnResult>), KvError> {
+        let keys = success.iter().chain(failure).map(|op| op.table_key());
+        let _guards = self.locks.write_all(keys);
+        let (succeeded, results) = self.store.txn(compares, success, failure)?;
+        let ops = if succeeded { success } else { failure };
+        for (op, result) in ops.iter().zip(&results) {
+            let (table_name, key) = op.table_key();
+            let old = result.prev.as_ref();
+            match op {
+                TxnOp::Get { .. } => {}
+                TxnOp::Set { value, .. } => self.notify(table_name, key, old, Some(value)),
+                TxnOp::Del { .. } => self.notify(table_name, key, old, None),
+                TxnOp::Incr { .. } | TxnOp::IncrFloat { .. } => {
+                    self.notify(table_name, key, old, Some(&result.value))
+                }
+            }
+        }
+        Ok((succeeded, results))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::subscription::SubscriptionReceiver;
+    use crate::{MemTable, OverflowPolicy, SubscribeOptions};
+    use std::time::Duration;
+
+    async fn next_event(rx: &mut SubscriptionReceiver) -> Arc<CommandResponse> {
+        tokio::time::timeout(Duration::from_secs(1), rx.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn writes_should_notify_table_and_key_watchers() {
+        let bus = Arc::new(MsgBus::default());
+        let store = MemTable::new();
+        let locks = KeyLocks::default();
+        let notifier = KeyspaceNotifier::new(&store, &bus, &locks);
+        let mut table_rx = bus
+            .clone()
+            .subscribe(keyspace_topic("t1", None), Default::default());
+        let mut key_rx = bus
+            .clone()
+            .subscribe(keyspace_topic("t1", Some("k1")), Default::default());
+        next_event(&mut table_rx).await;
+        next_event(&mut key_rx).await;
+
+        notifier.set("t1", "k1".into(), "v1".into()).unwrap();
+        let event = next_event(&mut key_rx).await;
+        assert_eq!(event.topic, "__keyspace@t1:k1");
+        assert_eq!(event.values, vec![Value::default(), "v1".into()]);
+        let event = next_event(&mut table_rx).await;
+        assert_eq!(event.topic, "__keyspace@t1");
+        assert_eq!(event.pairs, vec![Kvpair::new("k1", "v1".into())]);
+
+        notifier.incr("t1", "k2", 5).unwrap();
+        let event = next_event(&mut table_rx).await;
+        assert_eq!(event.values, vec![Value::default(), 5.into()]);
+
+        notifier.del("t1", "k1").unwrap();
+        let event = next_event(&mut key_rx).await;
+        assert_eq!(event.values, vec!["v1".into(), Value::default()]);
+    }
+
+    #[tokio::test]
+    async fn ttl_changes_should_notify_watchers() {
+        let bus = Arc::new(MsgBus::default());
+        let store = MemTable::new();
+        let locks = KeyLocks::default();
+        let notifier = KeyspaceNotifier::new(&store, &bus, &locks);
+        let mut rx = bus
+            .clone()
+            .subscribe(keyspace_topic("t1", Some("k1")), Default::default());
+        next_event(&mut rx).await;
+
+        notifier.set("t1", "k1".into(), "v1".into()).unwrap();
+        assert_eq!(next_event(&mut rx).await.message, EVENT_SET);
+
+        assert!(notifier.expire("t1", "k1", 60_000).unwrap());
+        let event = next_event(&mut rx).await;
+        assert_eq!(event.message, EVENT_EXPIRE);
+        assert_eq!(event.values, vec!["v1".into(), "v1".into()]);
+
+        assert!(notifier.persist("t1", "k1").unwrap());
+        assert_eq!(next_event(&mut rx).await.message, EVENT_PERSIST);
+
+        // reaper 清理掉的 key 推送 expired 事件
+        notifier.expire("t1", "k1", 0).unwrap();
+        next_event(&mut rx).await;
+        assert_eq!(notifier.reap_expired().unwrap().len(), 1);
+        let event = next_event(&mut rx).await;
+        assert_eq!(event.message, EVENT_EXPIRED);
+        assert_eq!(event.values, vec!["v1".into(), Value::default()]);
+    }
+
+    #[test]
+    fn keyspace_topics_should_not_collide() {
+        assert_eq!(keyspace_topic("t1", Some("k1")), "__keyspace@t1:k1");
+        assert_eq!(keyspace_topic("a:b", None), "__keyspace@a\\:b");
+        let topics = [
+            keyspace_topic("a", Some("b:c")),
+            keyspace_topic("a:b", Some("c")),
+            keyspace_topic("a:b:c", None),
+            keyspace_topic("a", Some("b")),
+            keyspace_topic("a:b", None),
+            keyspace_topic("a\\", Some("b")),
+            keyspace_topic("a\\:b", None),
+        ];
+        let unique: std::collections::HashSet<_> = topics.iter().collect();
+        assert_eq!(unique.len(), topics.len());
+    }
+
+    #[tokio::test]
+    async fn events_of_one_key_should_arrive_in_write_order() {
+        let bus = Arc::new(MsgBus::default());
+        let store = MemTable::new();
+        let locks = KeyLocks::default();
+        let options = SubscribeOptions::new(OverflowPolicy::DropNewest, 1024, 0);
+        let mut rx = bus
+            .clone()
+            .subscribe(keyspace_topic("t1", Some("n")), options);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let notifier = KeyspaceNotifier::new(&store, &bus, &locks);
+                    for _ in 0..100 {
+                        notifier.incr("t1", "n", 1).unwrap();
+                    }
+                });
+            }
+        });
+        next_event(&mut rx).await;
+        let event = next_event(&mut rx).await;
+        assert_eq!(event.values, vec![Value::default(), 1.into()]);
+        for i in 1..400 {
+            let event = next_event(&mut rx).await;
+            assert_eq!(event.values, vec![i.into(), (i + 1).into()]);
+        }
+    }
+
+    #[test]
+    fn publish_to_keyspace_topic_should_be_rejected() {
+        assert!(check_publish("lobby").is_ok());
+        assert!(matches!(
+            check_publish(&keyspace_topic("t1", None)),
+            Err(KvError::PermissionDenied(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn txn_should_notify_written_keys() {
+        let bus = Arc::new(MsgBus::default());
+        let store = MemTable::new();
+        store.set("t1", "n".into(), 1.into()).unwrap();
+        let locks = KeyLocks::default();
+        let notifier = KeyspaceNotifier::new(&store, &bus, &locks);
+        let mut rx = bus
+            .clone()
+            .subscribe(keyspace_topic("t1", Some("n")), Default::default());
+        next_event(&mut rx).await;
+
+        let ops = [TxnOp::Incr {
+            table: "t1".into(),
+            key: "n".into(),
+            delta: 2,
+        }];
+        notifier.txn(&[], &ops, &[]).unwrap();
+        let event = next_event(&mut rx).await;
+        assert_eq!(event.values, vec![1.into(), 3.into()]);
+
+        // 旧值来自 storage，而不是用 delta 反推：浮点数加减不会有误差
+        let ops = [TxnOp::IncrFloat {
+            table: "t1".into(),
+            key: "n".into(),
+            delta: 0.1,
+        }];
+        notifier.txn(&[], &ops, &[]).unwrap();
+        let event = next_event(&mut rx).await;
+        assert_eq!(event.values, vec![3.into(), 3.1.into()]);
+    }
+}
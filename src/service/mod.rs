@@ -1,8 +1,28 @@
 mod cmd_impl;
-mod topic;
+pub mod context;
+pub mod durable;
+pub mod keyspace;
+pub mod pattern;
+pub mod subscription;
+pub mod topic;
+pub mod topic_service;
 
-use crate::{CommandRequest, CommandResponse, KvError, MemTable, RequestData, Storage};
-use std::sync::Arc;
+use crate::{
+    CommandRequest, CommandResponse, KvError, MemTable, Punsubscribe, RequestData, Storage,
+    Unsubscribe, Unwatch, storage::KeyLocks,
+};
+use context::{RequestContext, SubscriptionIds};
+use futures::{StreamExt, stream};
+use keyspace::{KeyspaceNotifier, check_publish};
+use pattern::PatternTrie;
+use std::{sync::Arc, time::Duration};
+use tokio::task::JoinHandle;
+use topic::{MsgBus, TopicStats};
+use topic_service::{StreamingResponse, TopicService};
+use tracing::{debug, warn};
+
+/// req hook 返回错误时请求不会被执行，错误直接作为 response 返回
+pub type ReqHook = fn(&CommandRequest, &RequestContext) -> Result<(), KvError>;
 
 pub trait CmdService {
     fn execute(self, store: &impl Storage) -> CommandResponse;
@@ -13,6 +33,7 @@ where
     Store: Storage,
 {
     inner: Arc<ServiceInner<Store>>,
+    broadcaster: Arc<MsgBus>,
 }
 
 impl<Store> Clone for Service<Store>
@@ -22,6 +43,7 @@ where
     fn clone(&self) -> Self {
         Self {
             inner: Arc::clone(&self.inner),
+            broadcaster: Arc::clone(&self.broadcaster),
         }
     }
 }
@@ -32,8 +54,14 @@ where
     Store: Storage,
 {
     store: Store,
-    req_hooks: Vec<fn(&CommandRequest)>,
+    req_hooks: Vec<ReqHook>,
     resp_hooks: Vec<fn(&mut CommandResponse)>,
+    // 匹配上的 topic 会把消息写进 store 里的 log，支持 replay
+    durable_topics: PatternTrie,
+    // 每个 durable topic 最多保留的消息数，0 表示不限制
+    durable_retention: u64,
+    // 同一个 key 的写入和 keyspace 事件的推送在同一把锁里完成
+    keyspace_locks: KeyLocks,
 }
 
 impl<Store> From<ServiceInner<Store>> for Service<Store>
@@ -43,6 +71,7 @@ where
     fn from(inner: ServiceInner<Store>) -> Self {
         Self {
             inner: Arc::new(inner),
+            broadcaster: Default::default(),
         }
     }
 }
@@ -53,12 +82,15 @@ where
 {
     pub fn new(store: Store) -> Self {
         Self {
-            store: store,
+            store,
             req_hooks: vec![],
             resp_hooks: vec![],
+            durable_topics: PatternTrie::default(),
+            durable_retention: durable::DEFAULT_DURABLE_RETENTION,
+            keyspace_locks: KeyLocks::default(),
         }
     }
-    pub fn add_req_hook(mut self, f: fn(&CommandRequest)) -> Self {
+    pub fn add_req_hook(mut self, f: ReqHook) -> Self {
         self.req_hooks.push(f);
         self
     }
@@ -67,6 +99,23 @@ where
         self.resp_hooks.push(f);
         self
     }
+    /// 把匹配 pattern 的 topic 设置为 durable，pattern 的语法和 Psubscribe 相同
+    pub fn add_durable_topic(mut self, pattern: impl Into<String>) -> Self {
+        // 只关心有没有匹配上，id 没有意义
+        self.durable_topics.insert(&pattern.into(), 0);
+        self
+    }
+
+    /// 每个 durable topic 最多保留的消息数，更早的消息在 publish 时被删掉；0 表示不限制
+    pub fn with_durable_retention(mut self, max_messages: u64) -> Self {
+        self.durable_retention = max_messages;
+        self
+    }
+
+    fn is_durable(&self, topic: &str) -> bool {
+        !self.durable_topics.is_empty() && !self.durable_topics.matches(topic).is_empty()
+    }
+
     pub fn build(self) -> Service<Store> {
         self.into()
     }
@@ -101,62 +150,162 @@ where
 {
     // deprecated => replaced by builder flow
     pub fn new(store: Store) -> Self {
-        Self {
-            inner: Arc::new(ServiceInner::new(store)),
-        }
+        ServiceInner::new(store).into()
     }
-    pub fn process_request(&self, cmd_req: CommandRequest) -> CommandResponse {
-        self.inner.req_hooks.exec_all(&cmd_req);
-        let mut resp = exec_cmd(cmd_req, &self.inner.store);
-        // if resp == CommandResponse::default() {
-        //     exec_stream_cmd(cmd_req, store)
-        // } else {
-        //     self.inner.resp_hooks.exec_all(&mut resp);
-        //     resp
-        // }
-        // todo => update exec_cmd output format
-        //      => update exec_stream_cmd output format
-        // resp_rx
+}
+
+impl<Store> Service<Store>
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    // 普通命令的 stream 只有一个 response，subscribe 的 stream 持续到取消订阅
+    // ctx 里带着调用方的身份，交给 req hook 做鉴权
+    pub fn process_request(
+        &self,
+        cmd_req: CommandRequest,
+        ctx: &RequestContext,
+    ) -> StreamingResponse {
+        if let Err(e) = self
+            .inner
+            .req_hooks
+            .iter()
+            .try_for_each(|f| f(&cmd_req, ctx))
+            .and_then(|_| durable::check_write(&cmd_req))
+        {
+            let mut resp = e.into();
+            self.inner.resp_hooks.exec_all(&mut resp);
+            return Box::pin(stream::once(async { Arc::new(resp) }));
+        }
+        if is_stream_cmd(&cmd_req) {
+            let resps = self.exec_stream_cmd(cmd_req, ctx);
+            if self.inner.resp_hooks.is_empty() {
+                return resps;
+            }
+            // stream 里的每个 response 也都要经过 resp hook，包括订阅推送的消息
+            let inner = Arc::clone(&self.inner);
+            return Box::pin(resps.map(move |resp| {
+                let mut resp = Arc::unwrap_or_clone(resp);
+                inner.resp_hooks.exec_all(&mut resp);
+                Arc::new(resp)
+            }));
+        }
+        // 写操作经过 KeyspaceNotifier，把变更推送给 watch 的订阅者
+        let store = KeyspaceNotifier::new(
+            &self.inner.store,
+            &self.broadcaster,
+            &self.inner.keyspace_locks,
+        );
+        let mut resp = exec_cmd(cmd_req, &store);
         self.inner.resp_hooks.exec_all(&mut resp);
-        resp
-    }
-}
-
-// todo topic_stream
-//  server
-//  let resp_rx = service.process_request(cmd);
-//  tokio::spawn(async move {
-//      while let Some(resp) = resp_rx.next() {
-//          self.stream.send(resp).await.unwrap();
-//      }
-//  });
-//  client
-//  let cmd = ...;
-//  let client = ClientStream::new(stream);
-//  let resp_rx = client.exec(cmd);
-//  oneshot or other stuff?
-//  ...
-
-// impl<Store: Storage> Service<Store> {
-//     pub fn execute(&self, cmd: CommandRequest) -> StreamingResponse {
-//         debug!("Got request: {:?}", cmd);
-//         self.inner.on_received.notify(&cmd);
-//         let mut res = dispatch(cmd.clone(), &self.inner.store);
-
-//         if res == CommandResponse::default() {
-//             dispatch_stream(cmd, Arc::clone(&self.broadcaster))
-//         } else {
-//             debug!("Executed response: {:?}", res);
-//             self.inner.on_executed.notify(&res);
-//             self.inner.on_before_send.notify(&mut res);
-//             if !self.inner.on_before_send.is_empty() {
-//                 debug!("Modified response: {:?}", res);
-//             }
-
-//             Box::pin(stream::once(async { Arc::new(res) }))
-//         }
-//     }
-// }
+        Box::pin(stream::once(async { Arc::new(resp) }))
+    }
+
+    /// 当前所有被订阅的 topic 和模式
+    pub fn topic_stats(&self) -> Vec<TopicStats> {
+        self.broadcaster.topic_stats()
+    }
+
+    // durable topic 的 publish / replay 需要访问 store，其它的只和 MsgBus 有关；
+    // 订阅记在 ctx 里，只有创建订阅的连接才能取消它
+    fn exec_stream_cmd(&self, cmd_req: CommandRequest, ctx: &RequestContext) -> StreamingResponse {
+        let bus = Arc::clone(&self.broadcaster);
+        let owned = &ctx.subscriptions;
+        let is_subscribe = matches!(
+            cmd_req.request_data,
+            Some(RequestData::Subscribe(_))
+                | Some(RequestData::Psubscribe(_))
+                | Some(RequestData::Watch(_))
+        );
+        let resps: StreamingResponse = match cmd_req.request_data {
+            Some(RequestData::Unsubscribe(Unsubscribe { id, .. }))
+            | Some(RequestData::Punsubscribe(Punsubscribe { id, .. }))
+            | Some(RequestData::Unwatch(Unwatch { id, .. }))
+                if !owned.contains(id) =>
+            {
+                let e = KvError::NotFound(format!("subscription {}", id));
+                Box::pin(stream::once(async { Arc::new(e.into()) }))
+            }
+            Some(RequestData::Publish(param)) if let Err(e) = check_publish(&param.topic) => {
+                Box::pin(stream::once(async { Arc::new(e.into()) }))
+            }
+            Some(RequestData::Publish(param)) if self.inner.is_durable(&param.topic) => {
+                durable::publish(param, &self.inner.store, self.inner.durable_retention, bus)
+            }
+            Some(RequestData::Subscribe(param))
+                if param
+                    .options
+                    .as_ref()
+                    .is_some_and(|o| o.from_offset > 0 || o.from_timestamp_ms > 0) =>
+            {
+                durable::subscribe(param, Arc::clone(&self.inner), bus)
+            }
+            request_data => exec_stream_cmd(
+                CommandRequest {
+                    request_data,
+                    ..Default::default()
+                },
+                bus,
+            ),
+        };
+        if is_subscribe {
+            track_subscription(resps, owned.clone())
+        } else {
+            resps
+        }
+    }
+
+    /// 启动后台任务，每隔 interval 清理一次过期的 key
+    /// 任务只持有 service 的弱引用，service 全部 drop 之后自动退出
+    pub fn spawn_reaper(&self, interval: Duration) -> JoinHandle<()> {
+        let inner = Arc::downgrade(&self.inner);
+        let bus = Arc::downgrade(&self.broadcaster);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let (Some(inner), Some(bus)) = (inner.upgrade(), bus.upgrade()) else {
+                    break;
+                };
+                // 经过 KeyspaceNotifier，清理掉的 key 会推送 expired 事件
+                let store = KeyspaceNotifier::new(&inner.store, &bus, &inner.keyspace_locks);
+                match store.reap_expired() {
+                    Ok(reaped) if reaped.is_empty() => {}
+                    Ok(reaped) => debug!("reaped {} expired keys", reaped.len()),
+                    Err(e) => warn!("failed to reap expired keys: {:?}", e),
+                }
+            }
+        })
+    }
+}
+
+// 第一个 response 里是 subscription id，记到连接的 ctx 里；stream 结束或被 drop 时去掉
+fn track_subscription(resps: StreamingResponse, owned: SubscriptionIds) -> StreamingResponse {
+    struct Tracked {
+        owned: SubscriptionIds,
+        id: Option<u32>,
+    }
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            if let Some(id) = self.id {
+                self.owned.remove(id);
+            }
+        }
+    }
+    let tracked = Tracked { owned, id: None };
+    Box::pin(stream::unfold(
+        (resps, tracked),
+        |(mut resps, mut tracked)| async move {
+            let resp = resps.next().await?;
+            if tracked.id.is_none()
+                && let Ok(id) = i64::try_from(resp.as_ref())
+            {
+                tracked.id = Some(id as u32);
+                tracked.owned.insert(id as u32);
+            }
+            Some((resp, (resps, tracked)))
+        },
+    ))
+}
 
 // operate on DB & gen response
 pub fn exec_cmd(cmd_req: CommandRequest, store: &impl Storage) -> CommandResponse {
@@ -164,10 +313,70 @@ pub fn exec_cmd(cmd_req: CommandRequest, store: &impl Storage) -> CommandRespons
         Some(RequestData::Hget(param)) => param.execute(store),
         Some(RequestData::Hset(param)) => param.execute(store),
         Some(RequestData::Hdel(param)) => param.execute(store),
+        Some(RequestData::Hexpire(param)) => param.execute(store),
+        Some(RequestData::Httl(param)) => param.execute(store),
+        Some(RequestData::Hpersist(param)) => param.execute(store),
+        Some(RequestData::Hincrby(param)) => param.execute(store),
+        Some(RequestData::Hincrbyfloat(param)) => param.execute(store),
+        Some(RequestData::Txn(param)) => param.execute(store),
+        Some(RequestData::Hgetall(param)) => param.execute(store),
+        Some(RequestData::Hscan(param)) => param.execute(store),
+        Some(RequestData::Hmget(param)) => param.execute(store),
+        Some(RequestData::Hmset(param)) => param.execute(store),
+        Some(RequestData::Hmdel(param)) => param.execute(store),
+        Some(RequestData::Hmexist(param)) => param.execute(store),
+        Some(RequestData::Ping(param)) => param.execute(store),
+        Some(RequestData::Subscribe(_))
+        | Some(RequestData::Unsubscribe(_))
+        | Some(RequestData::Publish(_))
+        | Some(RequestData::Psubscribe(_))
+        | Some(RequestData::Punsubscribe(_))
+        | Some(RequestData::Watch(_))
+        | Some(RequestData::Unwatch(_))
+        | Some(RequestData::Topics(_)) => {
+            KvError::InvalidCommand("Pub/sub command needs a stream".into()).into()
+        }
+        // 握手由 ServerStream 处理，不会交给 service
+        Some(RequestData::Hello(_)) => {
+            KvError::InvalidCommand("Hello is only allowed as the first request".into()).into()
+        }
         None => KvError::InvalidCommand("Request has no data".into()).into(),
     }
 }
 
+pub fn is_stream_cmd(cmd_req: &CommandRequest) -> bool {
+    matches!(
+        cmd_req.request_data,
+        Some(RequestData::Subscribe(_))
+            | Some(RequestData::Unsubscribe(_))
+            | Some(RequestData::Publish(_))
+            | Some(RequestData::Psubscribe(_))
+            | Some(RequestData::Punsubscribe(_))
+            | Some(RequestData::Watch(_))
+            | Some(RequestData::Unwatch(_))
+            | Some(RequestData::Topics(_))
+    )
+}
+
+// operate on MsgBus & gen response stream
+pub fn exec_stream_cmd(cmd_req: CommandRequest, topic: Arc<MsgBus>) -> StreamingResponse {
+    match cmd_req.request_data {
+        Some(RequestData::Subscribe(param)) => param.execute(topic),
+        Some(RequestData::Unsubscribe(param)) => param.execute(topic),
+        Some(RequestData::Publish(param)) => param.execute(topic),
+        Some(RequestData::Psubscribe(param)) => param.execute(topic),
+        Some(RequestData::Punsubscribe(param)) => param.execute(topic),
+        Some(RequestData::Watch(param)) => param.execute(topic),
+        Some(RequestData::Unwatch(param)) => param.execute(topic),
+        Some(RequestData::Topics(param)) => param.execute(topic),
+        // is_stream_cmd 之外的命令不应该走到这里，返回错误而不是 panic
+        v => {
+            let resp: CommandResponse = KvError::InvalidCommand(format!("{:?}", v)).into();
+            Box::pin(stream::once(async { Arc::new(resp) }))
+        }
+    }
+}
+
 // 测试成功返回的结果
 #[cfg(test)]
 use crate::{Kvpair, Value};
@@ -201,3 +410,196 @@ pub fn assert_res_error(res: CommandResponse, code: u32, msg: &str) {
     assert_eq!(res.values, &[]);
     assert_eq!(res.pairs, &[]);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use context::PeerIdentity;
+    use futures::StreamExt;
+    use std::convert::TryInto;
+
+    #[tokio::test]
+    async fn process_request_should_return_single_response() {
+        let service: Service = ServiceInner::new(MemTable::new()).build();
+        let mut res = service.process_request(
+            CommandRequest::new_hset("t1", "k1", "v1".into()),
+            &RequestContext::default(),
+        );
+        assert_res_ok(&res.next().await.unwrap(), &[Value::default()], &[]);
+        assert!(res.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn req_hook_should_reject_request_by_context() {
+        fn need_peer(_: &CommandRequest, ctx: &RequestContext) -> Result<(), KvError> {
+            match ctx.peer {
+                Some(_) => Ok(()),
+                None => Err(KvError::PermissionDenied("anonymous".into())),
+            }
+        }
+        let service: Service = ServiceInner::new(MemTable::new())
+            .add_req_hook(need_peer)
+            .build();
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let mut res = service.process_request(cmd.clone(), &RequestContext::default());
+        assert_res_error(
+            Arc::unwrap_or_clone(res.next().await.unwrap()),
+            403,
+            "anonymous",
+        );
+        assert_eq!(service.inner.store.get("t1", "k1").unwrap(), None);
+
+        let ctx = RequestContext::new(Some(PeerIdentity::default()));
+        let mut res = service.process_request(cmd, &ctx);
+        assert_res_ok(&res.next().await.unwrap(), &[Value::default()], &[]);
+    }
+
+    #[tokio::test]
+    async fn resp_hook_should_apply_to_stream_responses() {
+        let service: Service = ServiceInner::new(MemTable::new())
+            .add_resp_hook(|res| res.message = "hooked".into())
+            .build();
+        let ctx = RequestContext::default();
+        let mut sub = service.process_request(CommandRequest::new_subscribe("lobby"), &ctx);
+        assert_eq!(sub.next().await.unwrap().message, "hooked");
+
+        let cmd = CommandRequest::new_publish("lobby", vec!["hello".into()]);
+        let mut res = service.process_request(cmd, &ctx);
+        assert_eq!(res.next().await.unwrap().message, "hooked");
+        let msg = sub.next().await.unwrap();
+        assert_eq!(msg.message, "hooked");
+        assert_eq!(msg.values, vec!["hello".into()]);
+    }
+
+    #[tokio::test]
+    async fn exec_stream_cmd_should_reject_non_stream_command() {
+        let bus = Arc::new(MsgBus::default());
+        let mut res = exec_stream_cmd(CommandRequest::new_hget("t1", "k1"), bus);
+        let resp = Arc::unwrap_or_clone(res.next().await.unwrap());
+        assert_eq!(resp.status, 400);
+        assert!(res.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn process_request_should_stream_subscriptions() {
+        let service: Service = ServiceInner::new(MemTable::new()).build();
+        let ctx = RequestContext::default();
+        let mut sub = service.process_request(CommandRequest::new_subscribe("lobby"), &ctx);
+        let id: i64 = sub.next().await.unwrap().as_ref().try_into().unwrap();
+
+        let v: Value = "hello".into();
+        let mut res = service.process_request(
+            CommandRequest::new_publish("lobby", vec![v.clone()]),
+            &RequestContext::default(),
+        );
+        assert_res_ok(&res.next().await.unwrap(), &[], &[]);
+        assert_res_ok(&sub.next().await.unwrap(), &[v], &[]);
+
+        let mut res =
+            service.process_request(CommandRequest::new_unsubscribe("lobby", id as _), &ctx);
+        assert_res_ok(&res.next().await.unwrap(), &[], &[]);
+        assert!(sub.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn only_owner_should_cancel_subscriptions() {
+        let service: Service = ServiceInner::new(MemTable::new()).build();
+        let owner = RequestContext::default();
+        let other = RequestContext::default();
+        let mut sub = service.process_request(CommandRequest::new_subscribe("lobby"), &owner);
+        let id: i64 = sub.next().await.unwrap().as_ref().try_into().unwrap();
+        let mut psub = service.process_request(CommandRequest::new_psubscribe("lob*"), &owner);
+        let pid: i64 = psub.next().await.unwrap().as_ref().try_into().unwrap();
+        let mut watch = service.process_request(CommandRequest::new_watch("t1", ""), &owner);
+        let wid: i64 = watch.next().await.unwrap().as_ref().try_into().unwrap();
+
+        let cancels = [
+            CommandRequest::new_unsubscribe("lobby", id as _),
+            CommandRequest::new_punsubscribe("lob*", pid as _),
+            CommandRequest::new_unwatch("t1", "", wid as _),
+        ];
+        // 其它连接拿到 id 也取消不了
+        for cmd in &cancels {
+            let mut res = service.process_request(cmd.clone(), &other);
+            assert_eq!(res.next().await.unwrap().status, 404);
+        }
+        assert_eq!(service.topic_stats().len(), 3);
+
+        for cmd in cancels {
+            let mut res = service.process_request(cmd, &owner);
+            assert_res_ok(&res.next().await.unwrap(), &[], &[]);
+        }
+        assert!(sub.next().await.is_none());
+        assert!(psub.next().await.is_none());
+        assert!(watch.next().await.is_none());
+        assert!(!owner.subscriptions.contains(id as _));
+    }
+
+    #[tokio::test]
+    async fn watch_should_receive_write_events() {
+        let service: Service = ServiceInner::new(MemTable::new()).build();
+        let ctx = RequestContext::default();
+        let mut watch = service.process_request(CommandRequest::new_watch("t1", ""), &ctx);
+        let id: i64 = watch.next().await.unwrap().as_ref().try_into().unwrap();
+
+        let mut res = service.process_request(
+            CommandRequest::new_hset("t1", "k1", "v1".into()),
+            &RequestContext::default(),
+        );
+        res.next().await.unwrap();
+        let event = watch.next().await.unwrap();
+        assert_eq!(event.topic, "__keyspace@t1");
+        assert_eq!(event.message, keyspace::EVENT_SET);
+        assert_eq!(event.values, vec![Value::default(), "v1".into()]);
+        assert_eq!(event.pairs, vec![Kvpair::new("k1", "v1".into())]);
+
+        let mut res = service.process_request(
+            CommandRequest::new_hdel("t1", "k1"),
+            &RequestContext::default(),
+        );
+        res.next().await.unwrap();
+        let event = watch.next().await.unwrap();
+        assert_eq!(event.message, keyspace::EVENT_DEL);
+        assert_eq!(event.values, vec!["v1".into(), Value::default()]);
+        assert_eq!(event.pairs, vec![Kvpair::new("k1", Value::default())]);
+
+        let mut res = service.process_request(CommandRequest::new_unwatch("t1", "", id as _), &ctx);
+        assert_res_ok(&res.next().await.unwrap(), &[], &[]);
+        assert!(watch.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn publish_to_keyspace_topic_should_be_rejected() {
+        let service: Service = ServiceInner::new(MemTable::new()).build();
+        let mut res = service.process_request(
+            CommandRequest::new_publish("__keyspace@t1", vec!["fake".into()]),
+            &RequestContext::default(),
+        );
+        assert_res_error(
+            Arc::unwrap_or_clone(res.next().await.unwrap()),
+            403,
+            "reserved",
+        );
+    }
+
+    #[tokio::test]
+    async fn reaper_should_remove_expired_keys() {
+        let service: Service = ServiceInner::new(MemTable::new()).build();
+        let cmd = CommandRequest::new_hset_with_ttl("t1", "k1", "v1".into(), 10);
+        service
+            .process_request(cmd, &RequestContext::default())
+            .next()
+            .await
+            .unwrap();
+        let handle = service.spawn_reaper(Duration::from_millis(5));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // 已经被后台任务清理掉了
+        assert!(service.inner.store.reap_expired().unwrap().is_empty());
+
+        drop(service);
+        tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::{HashMap, HashSet};
+
+const SEPARATOR: char = '.';
+// 匹配一个分段
+const SINGLE_WILDCARD: &str = "*";
+// 匹配零个或多个分段
+const MULTI_WILDCARD: &str = "**";
+
+/// 按 topic 分段组织的模式订阅树
+/// publish 时只沿着字面量 / "*" / "**" 三个分支往下走，和订阅的模式总数无关
+#[derive(Debug, Default)]
+pub struct PatternTrie {
+    children: HashMap<String, PatternTrie>,
+    sub_ids: HashSet<u32>,
+}
+
+impl PatternTrie {
+    pub fn insert(&mut self, pattern: &str, sub_id: u32) {
+        let mut node = self;
+        for seg in pattern.split(SEPARATOR) {
+            node = node.children.entry(seg.to_string()).or_default();
+        }
+        node.sub_ids.insert(sub_id);
+    }
+
+    /// 删除成功返回 true，同时清理掉空的分支
+    pub fn remove(&mut self, pattern: &str, sub_id: u32) -> bool {
+        let segs: Vec<&str> = pattern.split(SEPARATOR).collect();
+        self.remove_segs(&segs, sub_id)
+    }
+
+    fn remove_segs(&mut self, segs: &[&str], sub_id: u32) -> bool {
+        let Some((first, rest)) = segs.split_first() else {
+            return self.sub_ids.remove(&sub_id);
+        };
+        let Some(child) = self.children.get_mut(*first) else {
+            return false;
+        };
+        let removed = child.remove_segs(rest, sub_id);
+        if child.is_empty() {
+            self.children.remove(*first);
+        }
+        removed
+    }
+
+    /// 不知道模式时按 id 删除，需要遍历整棵树
+    pub fn remove_id(&mut self, sub_id: u32) -> bool {
+        let mut removed = self.sub_ids.remove(&sub_id);
+        self.children.retain(|_, child| {
+            removed |= child.remove_id(sub_id);
+            !child.is_empty()
+        });
+        removed
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.children.is_empty() && self.sub_ids.is_empty()
+    }
+
+    /// 返回所有模式能匹配上 topic 的 subscription id
+    pub fn matches(&self, topic: &str) -> HashSet<u32> {
+        let segs: Vec<&str> = topic.split(SEPARATOR).collect();
+        let mut ids = HashSet::new();
+        self.collect(&segs, &mut ids);
+        ids
+    }
+
+    fn collect(&self, segs: &[&str], ids: &mut HashSet<u32>) {
+        // "**" 可以吃掉 0..=segs.len() 个分段
+        if let Some(multi) = self.children.get(MULTI_WILDCARD) {
+            for i in 0..=segs.len() {
+                multi.collect(&segs[i..], ids);
+            }
+        }
+        let Some((first, rest)) = segs.split_first() else {
+            ids.extend(self.sub_ids.iter().copied());
+            return;
+        };
+        if let Some(child) = self.children.get(*first) {
+            child.collect(rest, ids);
+        }
+        if *first != SINGLE_WILDCARD
+            && let Some(child) = self.children.get(SINGLE_WILDCARD)
+        {
+            child.collect(rest, ids);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(ids: HashSet<u32>) -> Vec<u32> {
+        let mut ids: Vec<_> = ids.into_iter().collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn pattern_trie_should_match_wildcards() {
+        let mut trie = PatternTrie::default();
+        trie.insert("orders.eu.*", 1);
+        trie.insert("orders.*.123", 2);
+        trie.insert("orders.**", 3);
+        trie.insert("orders.eu.123", 4);
+        trie.insert("**.123", 5);
+
+        assert_eq!(sorted(trie.matches("orders.eu.123")), vec![1, 2, 3, 4, 5]);
+        assert_eq!(sorted(trie.matches("orders.eu.456")), vec![1, 3]);
+        assert_eq!(sorted(trie.matches("orders.us.123")), vec![2, 3, 5]);
+        assert_eq!(sorted(trie.matches("orders")), vec![3]);
+        assert_eq!(sorted(trie.matches("orders.eu")), vec![3]);
+        assert!(trie.matches("users.eu.1").is_empty());
+    }
+
+    #[test]
+    fn pattern_trie_remove_should_prune_empty_branches() {
+        let mut trie = PatternTrie::default();
+        trie.insert("orders.eu.*", 1);
+        trie.insert("orders.eu.*", 2);
+
+        assert!(trie.remove("orders.eu.*", 1));
+        assert!(!trie.remove("orders.eu.*", 1));
+        assert!(!trie.remove("orders.us.*", 2));
+        assert_eq!(sorted(trie.matches("orders.eu.1")), vec![2]);
+
+        assert!(trie.remove("orders.eu.*", 2));
+        assert!(trie.is_empty());
+
+        trie.insert("orders.**", 3);
+        assert!(trie.remove_id(3));
+        assert!(trie.is_empty());
+    }
+}
@@ -0,0 +1,376 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        Arc, Mutex, Weak,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+use tokio::{sync::Notify, time::Instant};
+
+use crate::{CommandResponse, KvError, MsgBus, OverflowPolicy, SubscribeOptions};
+
+/// 每个订阅默认的队列长度
+pub const BROADCAST_CAPACITY: usize = 128;
+/// 每个订阅最大的队列长度，客户端要求更大的值时按这个值处理
+pub const MAX_BROADCAST_CAPACITY: usize = 65_536;
+/// BLOCK 策略下默认最多等待的时间
+pub const DEFAULT_BLOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// 订阅的是一个 topic 还是一个模式
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SubscriptionTopic {
+    Topic(String),
+    Pattern(String),
+}
+
+/// 投递一条消息的结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    Delivered,
+    /// 消息被丢弃了（DROP_NEWEST，BLOCK 超时或者等待的消息太多）
+    Dropped,
+    /// BLOCK 策略下队列已满，消息进入等待队列，调用方需要调用 drain 等待队列有空位
+    Full,
+    /// BLOCK 策略下前面已经有消息在等待，这条消息排在它们后面，由已有的 drain 投递
+    Queued,
+    /// 订阅已经关闭，需要从 MsgBus 里删掉
+    Closed,
+}
+
+/// 单个订阅的统计信息
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubscriptionStats {
+    pub queued: usize,
+    pub dropped: u64,
+}
+
+/// 每个订阅一个有界队列，publish 同步写入，订阅者通过 SubscriptionReceiver 读取
+pub struct Subscription {
+    id: u32,
+    topic: SubscriptionTopic,
+    queue: Mutex<VecDeque<Arc<CommandResponse>>>,
+    // BLOCK 策略下等待队列空位的消息和各自的 deadline，按 publish 的顺序排列
+    // 同时需要两把锁时，先锁 queue 再锁 backlog
+    backlog: Mutex<VecDeque<(Instant, Arc<CommandResponse>)>>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    block_timeout: Duration,
+    closed: AtomicBool,
+    dropped: AtomicU64,
+    // 队列里有新消息，或者订阅被关闭
+    readable: Notify,
+    // 队列里有空位，或者订阅被关闭
+    writable: Notify,
+}
+
+impl Subscription {
+    pub fn new(id: u32, topic: SubscriptionTopic, options: &SubscribeOptions) -> Arc<Self> {
+        let capacity = match options.capacity {
+            0 => BROADCAST_CAPACITY,
+            n => (n as usize).min(MAX_BROADCAST_CAPACITY),
+        };
+        let block_timeout = match options.block_timeout_ms {
+            0 => DEFAULT_BLOCK_TIMEOUT,
+            n => Duration::from_millis(n),
+        };
+        Arc::new(Self {
+            id,
+            topic,
+            queue: Mutex::new(VecDeque::new()),
+            backlog: Mutex::new(VecDeque::new()),
+            capacity,
+            policy: options.policy(),
+            block_timeout,
+            closed: AtomicBool::new(false),
+            dropped: AtomicU64::new(0),
+            readable: Notify::new(),
+            writable: Notify::new(),
+        })
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn topic(&self) -> &SubscriptionTopic {
+        &self.topic
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    pub fn stats(&self) -> SubscriptionStats {
+        SubscriptionStats {
+            queued: self.queue.lock().unwrap().len(),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    /// 不等待地投递一条消息，队列满时按 policy 处理
+    pub fn try_push(&self, msg: Arc<CommandResponse>) -> Delivery {
+        if self.is_closed() {
+            return Delivery::Closed;
+        }
+        let mut queue = self.queue.lock().unwrap();
+        // BLOCK 策略下前面还有消息在等待时也要排队，保证同一个订阅者收到的消息和 publish 的顺序一致
+        let mut backlog = self.backlog.lock().unwrap();
+        if queue.len() >= self.capacity || !backlog.is_empty() {
+            match self.policy {
+                OverflowPolicy::Block => {
+                    if backlog.len() >= self.capacity {
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                        return Delivery::Dropped;
+                    }
+                    backlog.push_back((Instant::now() + self.block_timeout, msg));
+                    return match backlog.len() {
+                        1 => Delivery::Full,
+                        _ => Delivery::Queued,
+                    };
+                }
+                OverflowPolicy::DropNewest => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    return Delivery::Dropped;
+                }
+                OverflowPolicy::DropOldest => {
+                    queue.pop_front();
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                OverflowPolicy::Disconnect => {
+                    // 丢掉积压的消息，只留下一个错误通知订阅者
+                    self.dropped
+                        .fetch_add(queue.len() as u64 + 1, Ordering::Relaxed);
+                    queue.clear();
+                    queue.push_back(Arc::new(KvError::SlowSubscriber(self.id).into()));
+                    drop(queue);
+                    self.close();
+                    return Delivery::Closed;
+                }
+            }
+        }
+        queue.push_back(msg);
+        drop(queue);
+        self.readable.notify_one();
+        Delivery::Delivered
+    }
+
+    /// try_push 返回 Full 之后调用：按顺序把等待队列里的消息搬进队列，
+    /// 每条消息最多等到自己的 deadline，超时的丢弃；等待队列清空之后返回
+    pub async fn drain(&self) -> Delivery {
+        loop {
+            // 先注册等待，再检查队列，避免错过 recv 发出的通知
+            let writable = self.writable.notified();
+            tokio::pin!(writable);
+            writable.as_mut().enable();
+            let deadline = {
+                let mut queue = self.queue.lock().unwrap();
+                let mut backlog = self.backlog.lock().unwrap();
+                if self.is_closed() {
+                    backlog.clear();
+                    return Delivery::Closed;
+                }
+                let moved = queue.len();
+                while queue.len() < self.capacity {
+                    match backlog.pop_front() {
+                        Some((_, msg)) => queue.push_back(msg),
+                        None => break,
+                    }
+                }
+                if queue.len() > moved {
+                    self.readable.notify_one();
+                }
+                let now = Instant::now();
+                while backlog
+                    .front()
+                    .is_some_and(|(deadline, _)| *deadline <= now)
+                {
+                    backlog.pop_front();
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                // 在锁里确认等待队列已经空了，之后的 try_push 会重新返回 Full
+                match backlog.front() {
+                    Some((deadline, _)) => *deadline,
+                    None => return Delivery::Delivered,
+                }
+            };
+            let _ = tokio::time::timeout_at(deadline, writable).await;
+        }
+    }
+
+    /// 关闭订阅，订阅者读完队列里剩下的消息之后结束
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.readable.notify_one();
+        self.writable.notify_waiters();
+    }
+}
+
+/// 订阅者一端，drop 的时候关闭订阅并立刻从 MsgBus 里删掉
+pub struct SubscriptionReceiver {
+    sub: Arc<Subscription>,
+    bus: Weak<MsgBus>,
+}
+
+impl SubscriptionReceiver {
+    pub fn new(sub: Arc<Subscription>, bus: Weak<MsgBus>) -> Self {
+        Self { sub, bus }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.sub.id
+    }
+
+    pub fn stats(&self) -> SubscriptionStats {
+        self.sub.stats()
+    }
+
+    /// 订阅关闭并且队列读完之后返回 None
+    pub async fn recv(&mut self) -> Option<Arc<CommandResponse>> {
+        loop {
+            let msg = self.sub.queue.lock().unwrap().pop_front();
+            if let Some(msg) = msg {
+                self.sub.writable.notify_waiters();
+                return Some(msg);
+            }
+            if self.sub.is_closed() {
+                return None;
+            }
+            // 只有一个读者，notify_one 在没有等待者时会保留一个 permit，不会丢通知
+            self.sub.readable.notified().await;
+        }
+    }
+}
+
+impl Drop for SubscriptionReceiver {
+    fn drop(&mut self) {
+        self.sub.close();
+        if let Some(bus) = self.bus.upgrade() {
+            bus.remove_subscriber(&self.sub);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Value;
+
+    fn msg(v: i64) -> Arc<CommandResponse> {
+        Arc::new(Value::from(v).into())
+    }
+
+    fn new_sub(policy: OverflowPolicy, block_timeout_ms: u64) -> Arc<Subscription> {
+        let options = SubscribeOptions::new(policy, 2, block_timeout_ms);
+        Subscription::new(1, SubscriptionTopic::Topic("t".into()), &options)
+    }
+
+    async fn recv_all(rx: &mut SubscriptionReceiver) -> Vec<i64> {
+        let mut values = vec![];
+        while rx.stats().queued > 0 {
+            let msg = rx.recv().await.unwrap();
+            values.push((&msg.values[0]).try_into().unwrap());
+        }
+        values
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_should_keep_latest_messages() {
+        let sub = new_sub(OverflowPolicy::DropOldest, 0);
+        let mut rx = SubscriptionReceiver::new(sub.clone(), Weak::new());
+        for i in 1..=3 {
+            assert_eq!(sub.try_push(msg(i)), Delivery::Delivered);
+        }
+        assert_eq!(sub.stats().dropped, 1);
+        assert_eq!(recv_all(&mut rx).await, vec![2, 3]);
+    }
+
+    #[tokio::test]
+    async fn drop_newest_should_keep_earliest_messages() {
+        let sub = new_sub(OverflowPolicy::DropNewest, 0);
+        let mut rx = SubscriptionReceiver::new(sub.clone(), Weak::new());
+        sub.try_push(msg(1));
+        sub.try_push(msg(2));
+        assert_eq!(sub.try_push(msg(3)), Delivery::Dropped);
+        assert_eq!(rx.stats().dropped, 1);
+        assert_eq!(recv_all(&mut rx).await, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn disconnect_should_close_with_error() {
+        let sub = new_sub(OverflowPolicy::Disconnect, 0);
+        let mut rx = SubscriptionReceiver::new(sub.clone(), Weak::new());
+        sub.try_push(msg(1));
+        sub.try_push(msg(2));
+        assert_eq!(sub.try_push(msg(3)), Delivery::Closed);
+        assert_eq!(sub.try_push(msg(4)), Delivery::Closed);
+        assert_eq!(rx.recv().await.unwrap().status, 429);
+        assert!(rx.recv().await.is_none());
+        assert_eq!(sub.stats().dropped, 3);
+    }
+
+    #[tokio::test]
+    async fn block_should_wait_for_space_until_timeout() {
+        let sub = new_sub(OverflowPolicy::Block, 50);
+        let mut rx = SubscriptionReceiver::new(sub.clone(), Weak::new());
+        sub.try_push(msg(1));
+        sub.try_push(msg(2));
+        assert_eq!(sub.try_push(msg(3)), Delivery::Full);
+
+        // 没有人读，超时之后丢弃
+        assert_eq!(sub.drain().await, Delivery::Delivered);
+        assert_eq!(sub.stats().dropped, 1);
+        assert_eq!(sub.stats().queued, 2);
+
+        // 有人读就能写进去
+        assert_eq!(sub.try_push(msg(4)), Delivery::Full);
+        let drainer = {
+            let sub = sub.clone();
+            tokio::spawn(async move { sub.drain().await })
+        };
+        assert_eq!(rx.recv().await.unwrap().values[0], 1.into());
+        assert_eq!(drainer.await.unwrap(), Delivery::Delivered);
+        assert_eq!(recv_all(&mut rx).await, vec![2, 4]);
+    }
+
+    #[tokio::test]
+    async fn block_should_keep_publish_order() {
+        let sub = new_sub(OverflowPolicy::Block, 1000);
+        let mut rx = SubscriptionReceiver::new(sub.clone(), Weak::new());
+        sub.try_push(msg(1));
+        sub.try_push(msg(2));
+        assert_eq!(sub.try_push(msg(3)), Delivery::Full);
+        // 队列腾出空位之后，后来的消息也不能插到等待的消息前面
+        assert_eq!(rx.recv().await.unwrap().values[0], 1.into());
+        assert_eq!(sub.try_push(msg(4)), Delivery::Queued);
+        let drainer = {
+            let sub = sub.clone();
+            tokio::spawn(async move { sub.drain().await })
+        };
+        let mut values = vec![];
+        for _ in 0..3 {
+            let msg = rx.recv().await.unwrap();
+            values.push(i64::try_from(&msg.values[0]).unwrap());
+        }
+        assert_eq!(values, vec![2, 3, 4]);
+        assert_eq!(drainer.await.unwrap(), Delivery::Delivered);
+        assert_eq!(sub.stats().dropped, 0);
+        // 等待队列清空之后恢复直接写入
+        assert_eq!(sub.try_push(msg(5)), Delivery::Delivered);
+    }
+
+    #[test]
+    fn capacity_should_be_clamped() {
+        let options = SubscribeOptions::new(OverflowPolicy::Block, u32::MAX, 0);
+        let sub = Subscription::new(1, SubscriptionTopic::Topic("t".into()), &options);
+        assert_eq!(sub.capacity, MAX_BROADCAST_CAPACITY);
+    }
+
+    #[tokio::test]
+    async fn dropping_receiver_should_close_subscription() {
+        let sub = new_sub(OverflowPolicy::Block, 0);
+        let rx = SubscriptionReceiver::new(sub.clone(), Weak::new());
+        drop(rx);
+        assert_eq!(sub.try_push(msg(1)), Delivery::Closed);
+    }
+}
@@ -1,28 +1,46 @@
+use crate::{
+    CommandResponse, KvError, SubscribeOptions, Value,
+    service::{
+        pattern::PatternTrie,
+        subscription::{
+            Delivery, Subscription, SubscriptionReceiver, SubscriptionStats, SubscriptionTopic,
+        },
+    },
+};
 use dashmap::{DashMap, DashSet};
+use futures::future::join_all;
 use std::{
-    process::Command,
+    collections::BTreeMap,
     sync::{
-        Arc,
+        Arc, RwLock,
         atomic::{AtomicU32, Ordering},
     },
 };
-use tokio::sync::mpsc;
-use tracing::{debug, info, warn};
-
-use crate::{CommandResponse, KvError, Value};
+use tracing::debug;
 
-const BROADCAST_CAPACITY: usize = 128;
+/// 一个 topic（或者模式）当前的订阅情况
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicStats {
+    pub topic: SubscriptionTopic,
+    pub subscribers: usize,
+    /// 所有订阅者丢弃的消息数之和
+    pub dropped: u64,
+}
 
 #[derive(Default)]
 pub struct MsgBus {
     name_2_sub_ids: DashMap<String, DashSet<u32>>,
-    sub_id_2_tx: DashMap<u32, mpsc::Sender<Arc<CommandResponse>>>,
+    sub_id_2_sub: DashMap<u32, Arc<Subscription>>,
+    patterns: RwLock<PatternTrie>,
 }
 
 pub trait PubSub: Send + Sync + 'static {
-    fn subscribe(self, name: String) -> mpsc::Receiver<Arc<CommandResponse>>;
+    fn subscribe(self, name: String, options: SubscribeOptions) -> SubscriptionReceiver;
     fn unsubscribe(self, name: String, id: u32) -> Result<u32, KvError>;
+    fn psubscribe(self, pattern: String, options: SubscribeOptions) -> SubscriptionReceiver;
+    fn punsubscribe(self, pattern: String, id: u32) -> Result<u32, KvError>;
     fn publish(self, name: String, msg: Arc<CommandResponse>);
+    fn topics(self) -> Vec<TopicStats>;
 }
 
 static NEXT_ID: AtomicU32 = AtomicU32::new(1);
@@ -32,24 +50,14 @@ fn get_next_subscription_id() -> u32 {
 }
 
 impl PubSub for Arc<MsgBus> {
-    fn subscribe(self, name: String) -> mpsc::Receiver<Arc<CommandResponse>> {
+    fn subscribe(self, name: String, options: SubscribeOptions) -> SubscriptionReceiver {
         let sub_id = {
-            let entry = self.name_2_sub_ids.entry(name).or_default();
+            let entry = self.name_2_sub_ids.entry(name.clone()).or_default();
             let sub_id = get_next_subscription_id();
             entry.value().insert(sub_id);
             sub_id
         };
-        let (tx, rx) = mpsc::channel(BROADCAST_CAPACITY);
-        let v: Value = (sub_id as i64).into();
-        let tx_1 = tx.clone();
-        tokio::spawn(async move {
-            if let Err(e) = tx_1.send(Arc::new(v.into())).await {
-                println!("failed to send sub_id: {}, error: {}", sub_id, e);
-            }
-        });
-        self.sub_id_2_tx.insert(sub_id, tx);
-        println!("sub_id {} added", sub_id);
-        rx
+        self.add_subscriber(sub_id, SubscriptionTopic::Topic(name), &options)
     }
     fn unsubscribe(self, name: String, sub_id: u32) -> Result<u32, KvError> {
         match self.remove_subscription(name, sub_id) {
@@ -57,51 +65,151 @@ impl PubSub for Arc<MsgBus> {
             None => Err(KvError::NotFound(format!("subscription {}", sub_id))),
         }
     }
+    fn psubscribe(self, pattern: String, options: SubscribeOptions) -> SubscriptionReceiver {
+        let sub_id = get_next_subscription_id();
+        self.patterns.write().unwrap().insert(&pattern, sub_id);
+        self.add_subscriber(sub_id, SubscriptionTopic::Pattern(pattern), &options)
+    }
+    fn punsubscribe(self, pattern: String, sub_id: u32) -> Result<u32, KvError> {
+        match self.remove_pattern_subscription(&pattern, sub_id) {
+            Some(sub_id) => Ok(sub_id),
+            None => Err(KvError::NotFound(format!("subscription {}", sub_id))),
+        }
+    }
     fn publish(self, name: String, msg: Arc<CommandResponse>) {
+        // 推送的消息带上实际发布的 topic，模式订阅者才能区分来源
+        let msg = if msg.topic == name {
+            msg
+        } else {
+            let mut msg = Arc::unwrap_or_clone(msg);
+            msg.topic = name.clone();
+            Arc::new(msg)
+        };
+        let mut sub_ids: Vec<u32> = match self.name_2_sub_ids.get(&name) {
+            Some(ids_lock) => ids_lock.value().iter().map(|id| *id).collect(),
+            None => vec![],
+        };
+        sub_ids.extend(self.patterns.read().unwrap().matches(&name));
+
+        // 每个订阅都是同步写入自己的队列，慢的订阅者不会拖住其它订阅者
+        let mut blocked = vec![];
+        for sub_id in sub_ids {
+            let Some(sub) = self.sub_id_2_sub.get(&sub_id).map(|s| s.clone()) else {
+                continue;
+            };
+            match sub.try_push(msg.clone()) {
+                Delivery::Full => blocked.push(sub),
+                Delivery::Closed => self.remove_subscriber(&sub),
+                Delivery::Delivered | Delivery::Queued | Delivery::Dropped => {}
+            }
+        }
+        if blocked.is_empty() {
+            return;
+        }
+        // BLOCK 策略下队列满的订阅者并发等待，每条消息各自最多等 block_timeout；
+        // 等待期间后面 publish 的消息排在等待队列里，由同一个任务按顺序投递
         tokio::spawn(async move {
-            let mut to_remove_ids = vec![];
-            if let Some(ids_lock) = self.name_2_sub_ids.get(&name) {
-                println!("ref_lock.key(): {:?}", ids_lock.key());
-                let sub_ids = ids_lock.value().clone();
-                drop(ids_lock);
-                for sub_id in sub_ids.into_iter() {
-                    if let Some(tx_lk) = self.sub_id_2_tx.get(&sub_id) {
-                        if let Err(e) = tx_lk.send(msg.clone()).await {
-                            println!("publish to {} failed! error: {:?}", sub_id, e);
-                            to_remove_ids.push(sub_id);
-                        }
-                    }
+            let results = join_all(blocked.iter().map(|sub| sub.drain())).await;
+            for (sub, res) in blocked.iter().zip(results) {
+                if res == Delivery::Closed {
+                    self.remove_subscriber(sub);
                 }
             }
-            for sub_id in to_remove_ids {
-                self.remove_subscription(name.clone(), sub_id);
-            }
         });
     }
+    fn topics(self) -> Vec<TopicStats> {
+        self.topic_stats()
+    }
 }
 
 impl MsgBus {
-    pub fn remove_subscription(&self, name: String, sub_id: u32) -> Option<u32> {
-        if let Some(sub_ids) = self.name_2_sub_ids.get_mut(&name) {
-            sub_ids.remove(&sub_id);
-            if sub_ids.is_empty() {
-                println!("channel {:?} is deleted!", &name);
-                drop(sub_ids);
-                self.name_2_sub_ids.remove(&name);
+    /// topic 上有没有订阅者（包括能匹配上的模式订阅）
+    pub fn has_subscribers(&self, name: &str) -> bool {
+        self.name_2_sub_ids.contains_key(name)
+            || !self.patterns.read().unwrap().matches(name).is_empty()
+    }
+
+    /// 订阅的队列长度和丢弃的消息数
+    pub fn subscription_stats(&self, sub_id: u32) -> Option<SubscriptionStats> {
+        self.sub_id_2_sub.get(&sub_id).map(|sub| sub.stats())
+    }
+
+    // 创建订阅的队列，并立刻把 subscription id 作为第一条消息放进去
+    fn add_subscriber(
+        self: &Arc<Self>,
+        sub_id: u32,
+        topic: SubscriptionTopic,
+        options: &SubscribeOptions,
+    ) -> SubscriptionReceiver {
+        let sub = Subscription::new(sub_id, topic, options);
+        let v: Value = (sub_id as i64).into();
+        sub.try_push(Arc::new(v.into()));
+        self.sub_id_2_sub.insert(sub_id, sub.clone());
+        debug!("sub_id {} added", sub_id);
+        SubscriptionReceiver::new(sub, Arc::downgrade(self))
+    }
+
+    /// 当前所有的 topic 和模式，以及各自的订阅者数量；topic 在前，模式在后，各自按名字排序
+    pub fn topic_stats(&self) -> Vec<TopicStats> {
+        let mut topics: BTreeMap<SubscriptionTopic, TopicStats> = BTreeMap::new();
+        for sub in self.sub_id_2_sub.iter().filter(|sub| !sub.is_closed()) {
+            let stats = topics
+                .entry(sub.topic().clone())
+                .or_insert_with(|| TopicStats {
+                    topic: sub.topic().clone(),
+                    subscribers: 0,
+                    dropped: 0,
+                });
+            stats.subscribers += 1;
+            stats.dropped += sub.stats().dropped;
+        }
+        topics.into_values().collect()
+    }
+
+    // 订阅被关闭（订阅者断开或者太慢）之后，从对应的索引里删掉
+    pub(crate) fn remove_subscriber(&self, sub: &Subscription) {
+        match sub.topic() {
+            SubscriptionTopic::Topic(name) => {
+                self.remove_subscription(name.clone(), sub.id());
+            }
+            SubscriptionTopic::Pattern(pattern) => {
+                self.remove_pattern_subscription(pattern, sub.id());
             }
         }
-        println!("subscription {:?} is removed!", sub_id);
-        self.sub_id_2_tx.remove(&sub_id).map(|(id, _)| id)
+    }
+
+    pub fn remove_pattern_subscription(&self, pattern: &str, sub_id: u32) -> Option<u32> {
+        if !self.patterns.write().unwrap().remove(pattern, sub_id) {
+            return None;
+        }
+        self.sub_id_2_sub.remove(&sub_id).map(|(id, sub)| {
+            sub.close();
+            id
+        })
+    }
+
+    pub fn remove_subscription(&self, name: String, sub_id: u32) -> Option<u32> {
+        let sub_ids = self.name_2_sub_ids.get_mut(&name)?;
+        sub_ids.remove(&sub_id)?;
+        if sub_ids.is_empty() {
+            debug!("channel {:?} is deleted!", &name);
+            drop(sub_ids);
+            self.name_2_sub_ids
+                .remove_if(&name, |_, ids| ids.is_empty());
+        }
+        debug!("subscription {:?} is removed!", sub_id);
+        self.sub_id_2_sub.remove(&sub_id).map(|(id, sub)| {
+            sub.close();
+            id
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::convert::TryInto;
+    use std::{convert::TryInto, time::Duration};
 
-    use tokio::sync::mpsc::Receiver;
-
-    use crate::assert_res_ok;
+    use crate::{OverflowPolicy, assert_res_ok};
 
     use super::*;
 
@@ -111,8 +219,8 @@ mod tests {
         let lobby = "lobby".to_string();
 
         // subscribe
-        let mut stream1 = b.clone().subscribe(lobby.clone());
-        let mut stream2 = b.clone().subscribe(lobby.clone());
+        let mut stream1 = b.clone().subscribe(lobby.clone(), Default::default());
+        let mut stream2 = b.clone().subscribe(lobby.clone(), Default::default());
 
         // publish
         let v: Value = "hello".into();
@@ -128,7 +236,7 @@ mod tests {
         let res2 = stream2.recv().await.unwrap();
 
         assert_eq!(res1, res2);
-        assert_res_ok(&res1, &[v.clone()], &[]);
+        assert_res_ok(&res1, &[v], &[]);
 
         // 如果 subscriber 取消订阅，则收不到新数据
         let result = b.clone().unsubscribe(lobby.clone(), id1 as _).unwrap();
@@ -140,10 +248,127 @@ mod tests {
 
         assert!(stream1.recv().await.is_none());
         let res2 = stream2.recv().await.unwrap();
-        assert_res_ok(&res2, &[v.clone()], &[]);
+        assert_res_ok(&res2, &[v], &[]);
+    }
+
+    #[tokio::test]
+    async fn pattern_subscription_should_receive_concrete_topic() {
+        let b = Arc::new(MsgBus::default());
+        let mut stream1 = b
+            .clone()
+            .psubscribe("orders.eu.*".into(), Default::default());
+        let mut stream2 = b
+            .clone()
+            .subscribe("orders.eu.123".into(), Default::default());
+        let id1 = get_id(&mut stream1).await;
+        get_id(&mut stream2).await;
+
+        let v: Value = "created".into();
+        b.clone()
+            .publish("orders.eu.123".into(), Arc::new(v.clone().into()));
+        let res1 = stream1.recv().await.unwrap();
+        let res2 = stream2.recv().await.unwrap();
+        assert_eq!(res1.topic, "orders.eu.123");
+        assert_eq!(res1, res2);
+
+        // 不匹配的 topic 收不到
+        b.clone()
+            .publish("orders.us.123".into(), Arc::new(v.clone().into()));
+        b.clone()
+            .publish("orders.eu.456".into(), Arc::new(v.into()));
+        let res1 = stream1.recv().await.unwrap();
+        assert_eq!(res1.topic, "orders.eu.456");
+
+        assert!(b.clone().punsubscribe("orders.us.*".into(), id1).is_err());
+        assert_eq!(
+            b.clone().punsubscribe("orders.eu.*".into(), id1).unwrap(),
+            id1
+        );
+        assert!(stream1.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn slow_subscriber_should_not_block_others() {
+        let b = Arc::new(MsgBus::default());
+        let lobby = "lobby".to_string();
+        // 慢的订阅者队列只有 2 个位置，而且一直不读
+        let options = SubscribeOptions::new(OverflowPolicy::Block, 2, 10_000);
+        let slow = b.clone().subscribe(lobby.clone(), options);
+        let options = SubscribeOptions::new(OverflowPolicy::DropOldest, 2, 0);
+        let mut lossy = b.clone().subscribe(lobby.clone(), options);
+        let mut fast = b.clone().subscribe(lobby.clone(), Default::default());
+        get_id(&mut lossy).await;
+        get_id(&mut fast).await;
+
+        for i in 0..10 {
+            b.clone()
+                .publish(lobby.clone(), Arc::new(Value::from(i).into()));
+        }
+        for i in 0..10 {
+            let res = tokio::time::timeout(Duration::from_secs(1), fast.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_res_ok(&res, &[i.into()], &[]);
+        }
+        assert_eq!(lossy.stats().dropped, 8);
+        assert_res_ok(&lossy.recv().await.unwrap(), &[8.into()], &[]);
+        assert_eq!(b.subscription_stats(slow.id()).unwrap().queued, 2);
+    }
+
+    #[tokio::test]
+    async fn disconnected_subscriber_should_be_removed() {
+        let b = Arc::new(MsgBus::default());
+        let lobby = "lobby".to_string();
+        let options = SubscribeOptions::new(OverflowPolicy::Disconnect, 1, 0);
+        let mut rx = b.clone().subscribe(lobby.clone(), options);
+
+        // 队列里还有 subscription id 没有读，这条消息会触发断开
+        b.clone()
+            .publish(lobby.clone(), Arc::new(Value::from(1).into()));
+        assert!(b.subscription_stats(rx.id()).is_none());
+        assert!(!b.has_subscribers(&lobby));
+        assert_eq!(rx.recv().await.unwrap().status, 429);
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn dropped_receiver_should_be_removed_right_away() {
+        let b = Arc::new(MsgBus::default());
+        let rx1 = b.clone().subscribe("lobby".into(), Default::default());
+        let rx2 = b.clone().subscribe("lobby".into(), Default::default());
+        let rx3 = b.clone().psubscribe("orders.*".into(), Default::default());
+        let topic = SubscriptionTopic::Topic("lobby".into());
+        let pattern = SubscriptionTopic::Pattern("orders.*".into());
+        assert_eq!(
+            b.topic_stats(),
+            vec![
+                TopicStats {
+                    topic: topic.clone(),
+                    subscribers: 2,
+                    dropped: 0
+                },
+                TopicStats {
+                    topic: pattern,
+                    subscribers: 1,
+                    dropped: 0
+                },
+            ]
+        );
+
+        drop(rx1);
+        drop(rx3);
+        assert_eq!(b.topic_stats()[0].topic, topic);
+        assert_eq!(b.topic_stats()[0].subscribers, 1);
+        assert!(!b.has_subscribers("orders.eu"));
+        assert!(b.subscription_stats(rx2.id()).is_some());
+
+        drop(rx2);
+        assert!(b.topic_stats().is_empty());
+        assert!(!b.has_subscribers("lobby"));
     }
 
-    pub async fn get_id(res: &mut Receiver<Arc<CommandResponse>>) -> u32 {
+    pub async fn get_id(res: &mut SubscriptionReceiver) -> u32 {
         let id: i64 = res.recv().await.unwrap().as_ref().try_into().unwrap();
         id as u32
     }
@@ -312,7 +537,7 @@ mod tests {
 // //         let res2 = stream2.recv().await.unwrap();
 // //
 // //         assert_eq!(res1, res2);
-// //         assert_res_ok(&res1, &[v.clone()], &[]);
+// //         assert_res_ok(&res1, &[v], &[]);
 // //
 // //         // 如果 subscriber 取消订阅，则收不到新数据
 // //         let result = b.clone().unsubscribe(lobby.clone(), id1 as _).unwrap();
@@ -324,7 +549,7 @@ mod tests {
 // //
 // //         assert!(stream1.recv().await.is_none());
 // //         let res2 = stream2.recv().await.unwrap();
-// //         assert_res_ok(&res2, &[v.clone()], &[]);
+// //         assert_res_ok(&res2, &[v], &[]);
 // //     }
 // //
 // //     pub async fn get_id(res: &mut Receiver<Arc<CommandResponse>>) -> u32 {
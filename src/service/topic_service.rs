@@ -0,0 +1,238 @@
+use futures::{Stream, stream};
+use std::{pin::Pin, sync::Arc};
+
+use crate::{
+    CommandResponse, Kvpair, Psubscribe, PubSub, Publish, Punsubscribe, Subscribe, Topics,
+    Unsubscribe, Unwatch, Watch,
+    service::{
+        keyspace::{check_publish, keyspace_topic},
+        subscription::SubscriptionTopic,
+    },
+};
+
+pub type StreamingResponse = Pin<Box<dyn Stream<Item = Arc<CommandResponse>> + Send>>;
+
+pub trait TopicService {
+    fn execute(self, topic: impl PubSub) -> StreamingResponse;
+}
+
+impl TopicService for Subscribe {
+    fn execute(self, topic: impl PubSub) -> StreamingResponse {
+        let rx = topic.subscribe(self.topic, self.options.unwrap_or_default());
+        // 订阅的 stream 一直持续到 subscription 被关闭，stream 被 drop 时订阅也随之关闭
+        Box::pin(stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|msg| (msg, rx))
+        }))
+    }
+}
+
+impl TopicService for Unsubscribe {
+    fn execute(self, topic: impl PubSub) -> StreamingResponse {
+        let resp = match topic.unsubscribe(self.topic, self.id) {
+            Ok(_) => CommandResponse::ok(),
+            Err(e) => e.into(),
+        };
+        Box::pin(stream::once(async move { Arc::new(resp) }))
+    }
+}
+
+impl TopicService for Psubscribe {
+    fn execute(self, topic: impl PubSub) -> StreamingResponse {
+        let rx = topic.psubscribe(self.pattern, self.options.unwrap_or_default());
+        Box::pin(stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|msg| (msg, rx))
+        }))
+    }
+}
+
+impl TopicService for Punsubscribe {
+    fn execute(self, topic: impl PubSub) -> StreamingResponse {
+        let resp = match topic.punsubscribe(self.pattern, self.id) {
+            Ok(_) => CommandResponse::ok(),
+            Err(e) => e.into(),
+        };
+        Box::pin(stream::once(async move { Arc::new(resp) }))
+    }
+}
+
+impl TopicService for Watch {
+    fn execute(self, topic: impl PubSub) -> StreamingResponse {
+        Subscribe {
+            topic: keyspace_topic(
+                &self.table,
+                Some(self.key.as_str()).filter(|k| !k.is_empty()),
+            ),
+            options: self.options,
+        }
+        .execute(topic)
+    }
+}
+
+impl TopicService for Unwatch {
+    fn execute(self, topic: impl PubSub) -> StreamingResponse {
+        Unsubscribe {
+            topic: keyspace_topic(
+                &self.table,
+                Some(self.key.as_str()).filter(|k| !k.is_empty()),
+            ),
+            id: self.id,
+        }
+        .execute(topic)
+    }
+}
+
+// topic 和模式可能同名，加上前缀区分
+const TOPIC_KEY_PREFIX: &str = "topic:";
+const PATTERN_KEY_PREFIX: &str = "pattern:";
+
+impl TopicService for Topics {
+    fn execute(self, topic: impl PubSub) -> StreamingResponse {
+        let stats = topic.topics();
+        let mut pairs = Vec::with_capacity(stats.len());
+        let mut values = Vec::with_capacity(stats.len());
+        for stats in stats {
+            let key = match stats.topic {
+                SubscriptionTopic::Topic(name) => format!("{}{}", TOPIC_KEY_PREFIX, name),
+                SubscriptionTopic::Pattern(name) => format!("{}{}", PATTERN_KEY_PREFIX, name),
+            };
+            pairs.push(Kvpair::new(key, (stats.subscribers as i64).into()));
+            values.push((stats.dropped as i64).into());
+        }
+        let resp = CommandResponse {
+            status: 200,
+            values,
+            pairs,
+            ..Default::default()
+        };
+        Box::pin(stream::once(async { Arc::new(resp) }))
+    }
+}
+
+impl TopicService for Publish {
+    fn execute(self, topic: impl PubSub) -> StreamingResponse {
+        let resp = match check_publish(&self.topic) {
+            Ok(()) => {
+                topic.publish(self.topic, Arc::new(self.data.into()));
+                CommandResponse::ok()
+            }
+            Err(e) => e.into(),
+        };
+        Box::pin(stream::once(async { Arc::new(resp) }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::topic::MsgBus;
+    use crate::{
+        CommandRequest, OverflowPolicy, RequestData, SubscribeOptions, Value, assert_res_error,
+        assert_res_ok,
+    };
+    use futures::StreamExt;
+    use std::{convert::TryInto, time::Duration};
+
+    fn dispatch(cmd: CommandRequest, topic: &Arc<MsgBus>) -> StreamingResponse {
+        match cmd.request_data.unwrap() {
+            RequestData::Subscribe(param) => param.execute(topic.clone()),
+            RequestData::Unsubscribe(param) => param.execute(topic.clone()),
+            RequestData::Publish(param) => param.execute(topic.clone()),
+            RequestData::Psubscribe(param) => param.execute(topic.clone()),
+            RequestData::Punsubscribe(param) => param.execute(topic.clone()),
+            RequestData::Topics(param) => param.execute(topic.clone()),
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribe_and_publish_should_work() {
+        let topic = Arc::new(MsgBus::default());
+        let mut res1 = dispatch(CommandRequest::new_subscribe("lobby"), &topic);
+        let mut res2 = dispatch(CommandRequest::new_subscribe("lobby"), &topic);
+        let id1 = get_id(&mut res1).await;
+        let id2 = get_id(&mut res2).await;
+        assert_ne!(id1, id2);
+
+        let v: Value = "hello".into();
+        let mut res = dispatch(
+            CommandRequest::new_publish("lobby", vec![v.clone()]),
+            &topic,
+        );
+        assert_res_ok(&res.next().await.unwrap(), &[], &[]);
+
+        for res in [&mut res1, &mut res2] {
+            let msg = res.next().await.unwrap();
+            assert_res_ok(&msg, std::slice::from_ref(&v), &[]);
+        }
+    }
+
+    #[tokio::test]
+    async fn unsubscribe_should_end_the_stream() {
+        let topic = Arc::new(MsgBus::default());
+        let mut res = dispatch(CommandRequest::new_subscribe("lobby"), &topic);
+        let id = get_id(&mut res).await;
+
+        let mut unsub = dispatch(CommandRequest::new_unsubscribe("lobby", id), &topic);
+        assert_res_ok(&unsub.next().await.unwrap(), &[], &[]);
+        let next = tokio::time::timeout(Duration::from_secs(1), res.next()).await;
+        assert!(next.unwrap().is_none());
+
+        let mut unsub = dispatch(CommandRequest::new_unsubscribe("lobby", id), &topic);
+        let resp = unsub.next().await.unwrap();
+        assert_res_error(Arc::unwrap_or_clone(resp), 404, "Not found");
+    }
+
+    #[tokio::test]
+    async fn psubscribe_should_receive_matching_topics() {
+        let topic = Arc::new(MsgBus::default());
+        let mut res = dispatch(CommandRequest::new_psubscribe("orders.eu.*"), &topic);
+        let id = get_id(&mut res).await;
+
+        let v: Value = "created".into();
+        for name in ["orders.us.1", "orders.eu.1"] {
+            let mut pubs = dispatch(CommandRequest::new_publish(name, vec![v.clone()]), &topic);
+            assert_res_ok(&pubs.next().await.unwrap(), &[], &[]);
+        }
+        let msg = res.next().await.unwrap();
+        assert_eq!(msg.topic, "orders.eu.1");
+        assert_res_ok(&msg, std::slice::from_ref(&v), &[]);
+
+        let mut unsub = dispatch(CommandRequest::new_punsubscribe("orders.eu.*", id), &topic);
+        assert_res_ok(&unsub.next().await.unwrap(), &[], &[]);
+        assert!(res.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn topics_should_separate_topics_and_patterns() {
+        let topic = Arc::new(MsgBus::default());
+        // 队列只有一个位置，放着还没有读的 subscription id，后面的消息都会被丢弃
+        let options = SubscribeOptions::new(OverflowPolicy::DropNewest, 1, 0);
+        let _sub = dispatch(
+            CommandRequest::new_subscribe_with_options("lobby", options),
+            &topic,
+        );
+        let mut psub = dispatch(CommandRequest::new_psubscribe("lobby"), &topic);
+        get_id(&mut psub).await;
+        for _ in 0..2 {
+            let mut pubs = dispatch(CommandRequest::new_publish("lobby", vec![]), &topic);
+            pubs.next().await.unwrap();
+        }
+
+        // topic 在前，模式在后；values 和 pairs 一一对应
+        let mut res = dispatch(CommandRequest::new_topics(), &topic);
+        let resp = res.next().await.unwrap();
+        assert_eq!(
+            resp.pairs,
+            vec![
+                Kvpair::new("topic:lobby", 1.into()),
+                Kvpair::new("pattern:lobby", 1.into()),
+            ]
+        );
+        assert_eq!(resp.values, vec![2.into(), 0.into()]);
+    }
+
+    async fn get_id(res: &mut StreamingResponse) -> u32 {
+        let id: i64 = res.next().await.unwrap().as_ref().try_into().unwrap();
+        id as u32
+    }
+}
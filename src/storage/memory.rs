@@ -1,11 +1,53 @@
-use crate::{KvError, Storage, Value};
+use crate::storage::{
+    KeyLocks, TxnOp, TxnResult, add_float, add_int, check_condition, compare_holds, now_ms,
+};
+use crate::{Compare, KvError, Kvpair, SetCondition, Storage, StorageIter, Value, value};
 use anyhow::Result;
-use dashmap::{DashMap, mapref::one::Ref};
+use bytes::Bytes;
+use dashmap::{
+    DashMap,
+    mapref::{entry::Entry as MapEntry, one::Ref},
+};
+use std::collections::{BTreeSet, BinaryHeap, HashMap};
+use std::sync::Mutex;
 
 #[derive(Default)]
 pub struct MemTable {
     // table_2_kv, kv => k_2_v
-    tables: DashMap<String, DashMap<String, Value>>,
+    tables: DashMap<String, DashMap<String, Entry>>,
+    // 过期时间索引 (expire_at, table, key)，后台清理时按过期时间顺序取出
+    expiry: Mutex<BTreeSet<(u64, String, String)>>,
+    // 单 key 操作和事务之间互斥用的锁
+    locks: KeyLocks,
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    value: Value,
+    // 过期的时间点（unix 毫秒），None 表示永不过期
+    expire_at: Option<u64>,
+}
+
+impl Entry {
+    fn new(value: Value) -> Self {
+        Self {
+            value: detach(value),
+            expire_at: None,
+        }
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expire_at.is_some_and(|t| t <= now)
+    }
+}
+
+// 从连接上解出来的 Value::Binary 引用着整块读缓冲，存起来之前拷贝一份，
+// 否则一个很小的 value 会让整块缓冲一直释放不了
+fn detach(mut value: Value) -> Value {
+    if let Some(value::Value::Binary(b)) = &mut value.value {
+        *b = Bytes::copy_from_slice(b);
+    }
+    value
 }
 
 impl MemTable {
@@ -13,7 +55,7 @@ impl MemTable {
         Self::default()
     }
 
-    fn get_or_create_table(&self, name: &str) -> Ref<String, DashMap<String, Value>> {
+    fn get_or_create_table(&self, name: &str) -> Ref<'_, String, DashMap<String, Entry>> {
         match self.tables.get(name) {
             Some(table) => table,
             None => {
@@ -22,31 +64,346 @@ impl MemTable {
             }
         }
     }
+
+    fn index_expiry(&self, table_name: &str, key: &str, old: Option<u64>, new: Option<u64>) {
+        if old == new {
+            return;
+        }
+        let mut expiry = self.expiry.lock().unwrap();
+        if let Some(t) = old {
+            expiry.remove(&(t, table_name.to_string(), key.to_string()));
+        }
+        if let Some(t) = new {
+            expiry.insert((t, table_name.to_string(), key.to_string()));
+        }
+    }
+
+    // 读出 key 当前没有过期的值，调用方需要持有 key 的锁
+    fn read_live(&self, table_name: &str, key: &str, now: u64) -> Option<Value> {
+        let table_entry = self.get_or_create_table(table_name);
+        table_entry
+            .get(key)
+            .filter(|e| !e.is_expired(now))
+            .map(|e| e.value.clone())
+    }
+
+    // 把 key 的旧 entry 换出来之后调用：清理它的过期索引，已经过期的值视为不存在
+    fn live_value(&self, table_name: &str, key: &str, old: Option<Entry>) -> Option<Value> {
+        let old = old?;
+        self.index_expiry(table_name, key, old.expire_at, None);
+        if old.is_expired(now_ms()) {
+            None
+        } else {
+            Some(old.value)
+        }
+    }
 }
 
 impl Storage for MemTable {
     fn get(&self, table_name: &str, key: &str) -> Result<Option<Value>, KvError> {
         // get or create table
         // get value from table
-        let table_entry = self.get_or_create_table(&table_name);
-        let option_key_entry = table_entry.get(key);
-        let option_value = option_key_entry.map(|key_entry| {
-            let value = key_entry.value();
-            value.clone()
-        });
+        let _guard = self.locks.read(table_name, key);
+        let table_entry = self.get_or_create_table(table_name);
+        let now = now_ms();
+        let option_value = table_entry
+            .get(key)
+            .filter(|key_entry| !key_entry.is_expired(now))
+            .map(|key_entry| key_entry.value.clone());
         Ok(option_value)
     }
 
     fn set(&self, table_name: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let _guard = self.locks.write(table_name, &key);
         let table_entry = self.get_or_create_table(table_name);
-        let option_res = table_entry.insert(key, value);
-        Ok(option_res)
+        let old = table_entry.insert(key.clone(), Entry::new(value));
+        Ok(self.live_value(table_name, &key, old))
     }
 
     fn del(&self, table_name: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let _guard = self.locks.write(table_name, key);
+        let table_entry = self.get_or_create_table(table_name);
+        let old = table_entry.remove(key).map(|(_, v)| v);
+        Ok(self.live_value(table_name, key, old))
+    }
+
+    fn set_if(
+        &self,
+        table_name: &str,
+        key: String,
+        value: Value,
+        condition: SetCondition,
+        expected: Option<&Value>,
+        ttl_ms: u64,
+    ) -> Result<Option<Value>, KvError> {
+        let _guard = self.locks.write(table_name, &key);
         let table_entry = self.get_or_create_table(table_name);
-        let option_prev_val = table_entry.remove(key).map(|(_, v)| v);
-        Ok(option_prev_val)
+        let now = now_ms();
+        let expire_at = (ttl_ms > 0).then(|| now.saturating_add(ttl_ms));
+        let entry = Entry {
+            value: detach(value),
+            expire_at,
+        };
+        // 检查和写入都在 entry 持有的 shard 写锁里完成
+        let old = match table_entry.entry(key.clone()) {
+            MapEntry::Occupied(mut e) => {
+                let current = Some(&e.get().value).filter(|_| !e.get().is_expired(now));
+                check_condition(&key, condition, current, expected)?;
+                Some(e.insert(entry))
+            }
+            MapEntry::Vacant(e) => {
+                check_condition(&key, condition, None, expected)?;
+                e.insert(entry);
+                None
+            }
+        };
+        let old = self.live_value(table_name, &key, old);
+        self.index_expiry(table_name, &key, None, expire_at);
+        Ok(old)
+    }
+
+    fn contains(&self, table_name: &str, key: &str) -> Result<bool, KvError> {
+        let _guard = self.locks.read(table_name, key);
+        let table_entry = self.get_or_create_table(table_name);
+        let now = now_ms();
+        Ok(table_entry.get(key).is_some_and(|e| !e.is_expired(now)))
+    }
+
+    fn get_all(&self, table_name: &str) -> Result<Vec<Kvpair>, KvError> {
+        let table_entry = self.get_or_create_table(table_name);
+        let now = now_ms();
+        let pairs = table_entry
+            .iter()
+            .filter(|entry| !entry.is_expired(now))
+            .map(|entry| Kvpair::new(entry.key(), entry.value.clone()))
+            .collect();
+        Ok(pairs)
+    }
+
+    fn get_iter(
+        &self,
+        table_name: &str,
+    ) -> Result<Box<dyn Iterator<Item = Result<Kvpair, KvError>>>, KvError> {
+        // clone 出 table 的 snapshot，避免迭代期间一直持有 DashMap 的锁
+        let table = self.get_or_create_table(table_name).clone();
+        let now = now_ms();
+        let data = table
+            .into_iter()
+            .filter(move |(_, e)| !e.is_expired(now))
+            .map(|(k, e)| (k, e.value));
+        let iter = StorageIter::new(data).map(Ok);
+        Ok(Box::new(iter))
+    }
+
+    fn scan(
+        &self,
+        table_name: &str,
+        prefix: &str,
+        cursor: &str,
+        limit: usize,
+    ) -> Result<(Vec<Kvpair>, Option<String>), KvError> {
+        let table_entry = self.get_or_create_table(table_name);
+        let now = now_ms();
+        // DashMap 是无序的，用一个大小为 limit + 1 的大顶堆选出最小的几个 key，
+        // 多取的那一个用来判断后面是否还有数据
+        let mut heap = BinaryHeap::with_capacity(limit + 1);
+        for entry in table_entry.iter() {
+            let key = entry.key();
+            if !key.starts_with(prefix) || key.as_str() <= cursor || entry.is_expired(now) {
+                continue;
+            }
+            if heap.len() <= limit {
+                heap.push(key.clone());
+            } else if heap.peek().is_some_and(|max| key < max) {
+                heap.pop();
+                heap.push(key.clone());
+            }
+        }
+        let mut keys = heap.into_sorted_vec();
+        let has_more = keys.len() > limit;
+        keys.truncate(limit);
+        let pairs: Vec<Kvpair> = keys
+            .into_iter()
+            .filter_map(|key| {
+                let value = table_entry.get(&key)?.value.clone();
+                Some(Kvpair::new(key, value))
+            })
+            .collect();
+        let next = match pairs.last() {
+            Some(pair) if has_more => Some(pair.key.clone()),
+            _ => None,
+        };
+        Ok((pairs, next))
+    }
+
+    fn expire(&self, table_name: &str, key: &str, ttl_ms: u64) -> Result<bool, KvError> {
+        let _guard = self.locks.write(table_name, key);
+        let table_entry = self.get_or_create_table(table_name);
+        let now = now_ms();
+        let (old, new) = match table_entry.get_mut(key) {
+            Some(mut e) if !e.is_expired(now) => {
+                let new = Some(now.saturating_add(ttl_ms));
+                (std::mem::replace(&mut e.expire_at, new), new)
+            }
+            _ => return Ok(false),
+        };
+        self.index_expiry(table_name, key, old, new);
+        Ok(true)
+    }
+
+    fn ttl(&self, table_name: &str, key: &str) -> Result<Option<u64>, KvError> {
+        let _guard = self.locks.read(table_name, key);
+        let table_entry = self.get_or_create_table(table_name);
+        let now = now_ms();
+        let ttl = table_entry
+            .get(key)
+            .filter(|e| !e.is_expired(now))
+            .and_then(|e| e.expire_at)
+            .map(|t| t - now);
+        Ok(ttl)
+    }
+
+    fn persist(&self, table_name: &str, key: &str) -> Result<bool, KvError> {
+        let _guard = self.locks.write(table_name, key);
+        let table_entry = self.get_or_create_table(table_name);
+        let now = now_ms();
+        let old = match table_entry.get_mut(key) {
+            Some(mut e) if !e.is_expired(now) => e.expire_at.take(),
+            _ => None,
+        };
+        self.index_expiry(table_name, key, old, None);
+        Ok(old.is_some())
+    }
+
+    fn reap_expired(&self) -> Result<Vec<(String, Kvpair)>, KvError> {
+        let now = now_ms();
+        // 先把到期的索引摘出来再释放锁，删除 key 时不持有索引的锁
+        let due = {
+            let mut expiry = self.expiry.lock().unwrap();
+            let rest = expiry.split_off(&(now + 1, String::new(), String::new()));
+            std::mem::replace(&mut *expiry, rest)
+        };
+        let mut reaped = vec![];
+        for (expire_at, table_name, key) in due {
+            let _guard = self.locks.write(&table_name, &key);
+            let Some(table_entry) = self.tables.get(&table_name) else {
+                continue;
+            };
+            // 索引可能已经过时（key 被重新 set 或者改了过期时间），只删除过期时间一致的 entry
+            if let Some((key, e)) =
+                table_entry.remove_if(&key, |_, e| e.expire_at == Some(expire_at))
+            {
+                drop(table_entry);
+                reaped.push((table_name, Kvpair::new(key, e.value)));
+            }
+        }
+        Ok(reaped)
+    }
+
+    fn update(
+        &self,
+        table_name: &str,
+        key: &str,
+        f: &dyn Fn(Option<&Value>) -> Result<Value, KvError>,
+    ) -> Result<Value, KvError> {
+        let _guard = self.locks.write(table_name, key);
+        let table_entry = self.get_or_create_table(table_name);
+        let now = now_ms();
+        // entry 持有 key 所在 shard 的写锁，读旧值和写新值之间不会被其它写入打断
+        let mut stale = None;
+        let value = match table_entry.entry(key.to_string()) {
+            MapEntry::Occupied(mut e) if e.get().is_expired(now) => {
+                let value = f(None)?;
+                stale = e.insert(Entry::new(value.clone())).expire_at;
+                value
+            }
+            MapEntry::Occupied(mut e) => {
+                let value = f(Some(&e.get().value))?;
+                e.get_mut().value = detach(value.clone());
+                value
+            }
+            MapEntry::Vacant(e) => {
+                let value = f(None)?;
+                e.insert(Entry::new(value.clone()));
+                value
+            }
+        };
+        self.index_expiry(table_name, key, stale, None);
+        Ok(value)
+    }
+
+    fn txn(
+        &self,
+        compares: &[Compare],
+        success: &[TxnOp],
+        failure: &[TxnOp],
+    ) -> Result<(bool, Vec<TxnResult>), KvError> {
+        // 锁住所有涉及到的 key，事务执行期间其它读写都要等待
+        let keys = compares
+            .iter()
+            .map(|c| (c.table.as_str(), c.key.as_str()))
+            .chain(success.iter().chain(failure).map(|op| op.table_key()));
+        let _guards = self.locks.write_all(keys);
+        let now = now_ms();
+
+        let succeeded = compares
+            .iter()
+            .all(|c| compare_holds(c, self.read_live(&c.table, &c.key, now).as_ref()));
+        let ops = if succeeded { success } else { failure };
+
+        // 先在 overlay 上算出所有的结果，中途出错直接返回，不会留下部分修改
+        // overlay: (table, key) => (新的值, 是否清除过期时间)
+        let mut overlay: HashMap<(&str, &str), (Option<Value>, bool)> = HashMap::new();
+        let mut results = Vec::with_capacity(ops.len());
+        for op in ops {
+            let (table_name, key) = op.table_key();
+            let (current, reset) = match overlay.get(&(table_name, key)) {
+                Some((v, reset)) => (v.clone(), *reset),
+                None => (self.read_live(table_name, key, now), false),
+            };
+            let value = match op {
+                TxnOp::Get { .. } => current.clone().unwrap_or_default(),
+                TxnOp::Set { value, .. } => {
+                    overlay.insert((table_name, key), (Some(value.clone()), true));
+                    current.clone().unwrap_or_default()
+                }
+                TxnOp::Del { .. } => {
+                    overlay.insert((table_name, key), (None, true));
+                    current.clone().unwrap_or_default()
+                }
+                TxnOp::Incr { delta, .. } => {
+                    let value = add_int(current.as_ref(), *delta)?;
+                    let reset = reset || current.is_none();
+                    overlay.insert((table_name, key), (Some(value.clone()), reset));
+                    value
+                }
+                TxnOp::IncrFloat { delta, .. } => {
+                    let value = add_float(current.as_ref(), *delta)?;
+                    let reset = reset || current.is_none();
+                    overlay.insert((table_name, key), (Some(value.clone()), reset));
+                    value
+                }
+            };
+            results.push(TxnResult::new(value, current));
+        }
+
+        for ((table_name, key), (value, reset)) in overlay {
+            let table_entry = self.get_or_create_table(table_name);
+            let old = match value {
+                Some(value) if reset => table_entry.insert(key.to_string(), Entry::new(value)),
+                // 没有清除过期时间说明 key 本来就存在而且没有过期，只替换值
+                Some(value) => {
+                    if let Some(mut e) = table_entry.get_mut(key) {
+                        e.value = detach(value);
+                    }
+                    None
+                }
+                None => table_entry.remove(key).map(|(_, v)| v),
+            };
+            drop(table_entry);
+            self.live_value(table_name, key, old);
+        }
+        Ok((succeeded, results))
     }
 }
 
@@ -61,6 +418,38 @@ mod tests {
         assert!(store.tables.contains_key("t1"));
         // println!("memtable test done!");
     }
+
+    #[test]
+    fn stored_binary_values_should_not_share_buffers() {
+        let buf = Bytes::from(vec![42u8; 4096]);
+        let range = buf.as_ptr_range();
+        let value = || Value::from(buf.slice(..16));
+        let in_buf = |v: Option<Value>| match v.and_then(|v| v.value) {
+            Some(value::Value::Binary(b)) => range.contains(&b.as_ptr()),
+            v => panic!("expect binary value, got {:?}", v),
+        };
+
+        let store = MemTable::new();
+        store.set("t1", "k1".into(), value()).unwrap();
+        assert!(!in_buf(store.get("t1", "k1").unwrap()));
+
+        store
+            .set_if("t1", "k2".into(), value(), SetCondition::Always, None, 0)
+            .unwrap();
+        assert!(!in_buf(store.get("t1", "k2").unwrap()));
+
+        store.update("t1", "k1", &|_| Ok(value())).unwrap();
+        assert!(!in_buf(store.get("t1", "k1").unwrap()));
+
+        let set = |key: &str| TxnOp::Set {
+            table: "t1".into(),
+            key: key.into(),
+            value: value(),
+        };
+        store.txn(&[], &[set("k2"), set("k3")], &[]).unwrap();
+        assert!(!in_buf(store.get("t1", "k2").unwrap()));
+        assert!(!in_buf(store.get("t1", "k3").unwrap()));
+    }
 }
 
 // use crate::{KvError, Kvpair, Storage, StorageIter, Value};
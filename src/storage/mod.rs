@@ -1,13 +1,267 @@
 pub mod memory;
 pub mod sled;
 
-use crate::{KvError, Value};
+use crate::{Compare, CompareOp, KvError, Kvpair, SetCondition, Value};
 use anyhow::Result;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Hscan 没有指定 limit 时每页返回的数量
+pub const DEFAULT_SCAN_LIMIT: usize = 100;
+/// Hscan 每页最多返回的数量，避免 response 超过 frame 限制
+pub const MAX_SCAN_LIMIT: usize = 10_000;
 
 pub trait Storage {
     fn get(&self, table_name: &str, key: &str) -> Result<Option<Value>, KvError>;
     fn set(&self, table_name: &str, key: String, value: Value) -> Result<Option<Value>, KvError>;
     fn del(&self, table_name: &str, key: &str) -> Result<Option<Value>, KvError>;
+    /// 原子地检查 condition 并写入，返回旧的值；条件不满足时返回 KvError::PreconditionFailed
+    /// ttl_ms 大于 0 时在同一次写入里设置过期时间，为 0 时清掉原来的过期时间
+    fn set_if(
+        &self,
+        table_name: &str,
+        key: String,
+        value: Value,
+        condition: SetCondition,
+        expected: Option<&Value>,
+        ttl_ms: u64,
+    ) -> Result<Option<Value>, KvError>;
+    fn contains(&self, table_name: &str, key: &str) -> Result<bool, KvError>;
+    /// 遍历 table，返回所有 kv pair
+    fn get_all(&self, table_name: &str) -> Result<Vec<Kvpair>, KvError>;
+    /// 遍历 table，返回 kv pair 的 Iterator；遍历过程中读出错时返回 Err
+    fn get_iter(
+        &self,
+        table_name: &str,
+    ) -> Result<Box<dyn Iterator<Item = Result<Kvpair, KvError>>>, KvError>;
+    /// 按 key 的字典序返回 table 中以 prefix 开头、且大于 cursor 的最多 limit 个 kv pair，
+    /// 如果后面还有数据，同时返回下一页的 cursor
+    fn scan(
+        &self,
+        table_name: &str,
+        prefix: &str,
+        cursor: &str,
+        limit: usize,
+    ) -> Result<(Vec<Kvpair>, Option<String>), KvError>;
+    /// 给 key 设置 ttl_ms 毫秒之后过期，key 不存在时返回 false
+    fn expire(&self, table_name: &str, key: &str, ttl_ms: u64) -> Result<bool, KvError>;
+    /// 返回 key 剩余的存活时间（毫秒），key 不存在或者没有过期时间时返回 None
+    fn ttl(&self, table_name: &str, key: &str) -> Result<Option<u64>, KvError>;
+    /// 去掉 key 的过期时间，原来设置过过期时间时返回 true
+    fn persist(&self, table_name: &str, key: &str) -> Result<bool, KvError>;
+    /// 根据过期时间索引清理已经过期的 key，返回清理掉的 (table, key => 过期前的值)
+    fn reap_expired(&self) -> Result<Vec<(String, Kvpair)>, KvError>;
+    /// 原子地给 key 的整数值加上 delta，key 不存在时从 0 开始，返回新的值
+    fn incr(&self, table_name: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        let v = self.update(table_name, key, &|old| add_int(old, delta))?;
+        i64::try_from(&v)
+    }
+    /// 原子地给 key 的数值加上浮点数 delta，key 不存在时从 0 开始，返回新的值
+    fn incr_float(&self, table_name: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        let v = self.update(table_name, key, &|old| add_float(old, delta))?;
+        f64::try_from(&v)
+    }
+    /// 原子地用 f 根据旧值计算出新值并写入，返回新的值；f 返回错误时不做修改
+    /// f 可能被调用多次，不能有副作用
+    fn update(
+        &self,
+        table_name: &str,
+        key: &str,
+        f: &dyn Fn(Option<&Value>) -> Result<Value, KvError>,
+    ) -> Result<Value, KvError>;
+    /// 原子地执行一个事务：compares 全部成立时执行 success，否则执行 failure
+    /// 返回是否执行了 success，以及每个操作的结果；任何一个操作出错时不做任何修改
+    fn txn(
+        &self,
+        compares: &[Compare],
+        success: &[TxnOp],
+        failure: &[TxnOp],
+    ) -> Result<(bool, Vec<TxnResult>), KvError>;
+}
+
+const LOCK_STRIPES: usize = 64;
+
+/// 按 (table, key) 的 hash 分段的读写锁
+/// 单 key 的操作只拿一把锁；事务按 stripe 的下标从小到大依次加锁，
+/// 所有人加锁的顺序一致，所以跨多个 table 的事务之间也不会死锁
+pub(crate) struct KeyLocks(Vec<RwLock<()>>);
+
+impl Default for KeyLocks {
+    fn default() -> Self {
+        Self((0..LOCK_STRIPES).map(|_| RwLock::new(())).collect())
+    }
+}
+
+impl KeyLocks {
+    fn stripe(table_name: &str, key: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        (table_name, key).hash(&mut hasher);
+        hasher.finish() as usize % LOCK_STRIPES
+    }
+
+    pub(crate) fn read(&self, table_name: &str, key: &str) -> RwLockReadGuard<'_, ()> {
+        self.0[Self::stripe(table_name, key)].read().unwrap()
+    }
+
+    pub(crate) fn write(&self, table_name: &str, key: &str) -> RwLockWriteGuard<'_, ()> {
+        self.0[Self::stripe(table_name, key)].write().unwrap()
+    }
+
+    pub(crate) fn write_all<'a>(
+        &self,
+        keys: impl Iterator<Item = (&'a str, &'a str)>,
+    ) -> Vec<RwLockWriteGuard<'_, ()>> {
+        let mut stripes: Vec<_> = keys.map(|(t, k)| Self::stripe(t, k)).collect();
+        stripes.sort_unstable();
+        stripes.dedup();
+        stripes
+            .into_iter()
+            .map(|i| self.0[i].write().unwrap())
+            .collect()
+    }
+
+    /// 按顺序拿到所有的锁
+    pub(crate) fn write_every(&self) -> Vec<RwLockWriteGuard<'_, ()>> {
+        self.0.iter().map(|l| l.write().unwrap()).collect()
+    }
+}
+
+/// 事务里一个操作的结果
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxnResult {
+    /// 返回给调用方的值，含义见 TxnOp
+    pub value: Value,
+    /// 执行这个操作之前 key 的值，不存在（或者已经过期）时为 None
+    pub prev: Option<Value>,
+}
+
+impl TxnResult {
+    fn new(value: Value, prev: Option<Value>) -> Self {
+        Self { value, prev }
+    }
+}
+
+/// 事务里支持的操作
+#[derive(Debug, Clone, PartialEq)]
+pub enum TxnOp {
+    /// 返回 key 的值，不存在时返回空 Value
+    Get { table: String, key: String },
+    /// 写入 key，返回之前的值
+    Set {
+        table: String,
+        key: String,
+        value: Value,
+    },
+    /// 删除 key，返回之前的值
+    Del { table: String, key: String },
+    /// 给 key 的整数值加上 delta，返回新的值
+    Incr {
+        table: String,
+        key: String,
+        delta: i64,
+    },
+    /// 给 key 的数值加上浮点数 delta，返回新的值
+    IncrFloat {
+        table: String,
+        key: String,
+        delta: f64,
+    },
+}
+
+impl TxnOp {
+    pub fn table_key(&self) -> (&str, &str) {
+        match self {
+            TxnOp::Get { table, key }
+            | TxnOp::Set { table, key, .. }
+            | TxnOp::Del { table, key }
+            | TxnOp::Incr { table, key, .. }
+            | TxnOp::IncrFloat { table, key, .. } => (table, key),
+        }
+    }
+}
+
+/// 检查 compare 对 key 当前的值是否成立
+pub(crate) fn compare_holds(compare: &Compare, current: Option<&Value>) -> bool {
+    match compare.op() {
+        CompareOp::Equal => current == compare.value.as_ref(),
+        CompareOp::NotEqual => current != compare.value.as_ref(),
+        CompareOp::Exists => current.is_some(),
+        CompareOp::NotExists => current.is_none(),
+    }
+}
+
+pub(crate) fn add_int(old: Option<&Value>, delta: i64) -> Result<Value, KvError> {
+    let old = match old {
+        Some(v) => i64::try_from(v)?,
+        None => 0,
+    };
+    let new = old.checked_add(delta).ok_or_else(|| {
+        KvError::InvalidCommand(format!("increment {} by {} overflows", old, delta))
+    })?;
+    Ok(new.into())
+}
+
+pub(crate) fn add_float(old: Option<&Value>, delta: f64) -> Result<Value, KvError> {
+    let old = match old {
+        Some(v) => f64::try_from(v)?,
+        None => 0.0,
+    };
+    Ok((old + delta).into())
+}
+
+/// 检查 key 当前的值是否满足写入条件
+pub(crate) fn check_condition(
+    key: &str,
+    condition: SetCondition,
+    current: Option<&Value>,
+    expected: Option<&Value>,
+) -> Result<(), KvError> {
+    let ok = match condition {
+        SetCondition::Always => true,
+        SetCondition::IfAbsent => current.is_none(),
+        SetCondition::IfPresent => current.is_some(),
+        SetCondition::IfEqual => current == expected,
+    };
+    if ok {
+        Ok(())
+    } else {
+        Err(KvError::PreconditionFailed(format!(
+            "key {} does not satisfy {:?}",
+            key, condition
+        )))
+    }
+}
+
+/// 当前的 unix 时间戳（毫秒）
+pub(crate) fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// 把任意能转成 Kvpair 的 Iterator 包装成 Storage 的 Iterator
+pub struct StorageIter<T> {
+    data: T,
+}
+
+impl<T> StorageIter<T> {
+    pub fn new(data: T) -> Self {
+        Self { data }
+    }
+}
+
+impl<T> Iterator for StorageIter<T>
+where
+    T: Iterator,
+    T::Item: Into<Kvpair>,
+{
+    type Item = Kvpair;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.data.next().map(|v| v.into())
+    }
 }
 
 #[cfg(test)]
@@ -30,6 +284,149 @@ mod tests {
         test_simple(store);
     }
 
+    #[test]
+    fn memtable_get_all_should_work() {
+        let store = MemTable::new();
+        test_get_all(store);
+    }
+
+    #[test]
+    fn sleddb_get_all_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_get_all(store);
+    }
+
+    #[test]
+    fn memtable_scan_should_work() {
+        let store = MemTable::new();
+        test_scan(store);
+    }
+
+    #[test]
+    fn sleddb_scan_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_scan(store);
+    }
+
+    #[test]
+    fn memtable_ttl_should_work() {
+        let store = MemTable::new();
+        test_ttl(store);
+    }
+
+    #[test]
+    fn sleddb_ttl_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_ttl(store);
+    }
+
+    #[test]
+    fn memtable_reap_expired_should_work() {
+        let store = MemTable::new();
+        test_reap_expired(store);
+    }
+
+    #[test]
+    fn sleddb_reap_expired_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_reap_expired(store);
+    }
+
+    #[test]
+    fn memtable_incr_should_work() {
+        let store = MemTable::new();
+        test_incr(store);
+    }
+
+    #[test]
+    fn sleddb_incr_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_incr(store);
+    }
+
+    #[test]
+    fn memtable_concurrent_incr_should_work() {
+        let store = MemTable::new();
+        test_concurrent_incr(store);
+    }
+
+    #[test]
+    fn sleddb_concurrent_incr_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_concurrent_incr(store);
+    }
+
+    #[test]
+    fn memtable_set_if_should_work() {
+        let store = MemTable::new();
+        test_set_if(store);
+    }
+
+    #[test]
+    fn sleddb_set_if_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_set_if(store);
+    }
+
+    #[test]
+    fn memtable_concurrent_set_if_should_work() {
+        let store = MemTable::new();
+        test_concurrent_set_if(store);
+    }
+
+    #[test]
+    fn sleddb_concurrent_set_if_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_concurrent_set_if(store);
+    }
+
+    #[test]
+    fn memtable_txn_should_work() {
+        let store = MemTable::new();
+        test_txn(store);
+    }
+
+    #[test]
+    fn sleddb_txn_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_txn(store);
+    }
+
+    #[test]
+    fn memtable_concurrent_txn_should_work() {
+        let store = MemTable::new();
+        test_concurrent_txn(store);
+    }
+
+    #[test]
+    fn sleddb_concurrent_txn_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_concurrent_txn(store);
+    }
+
+    #[test]
+    fn memtable_iter_should_work() {
+        let store = MemTable::new();
+        test_get_iter(store);
+    }
+
+    #[test]
+    fn sleddb_iter_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_get_iter(store);
+    }
+
     fn test_simple(store: impl Storage) {
         // set
         let v = store.set("t1", "hello".to_string(), "world".into());
@@ -43,6 +440,11 @@ mod tests {
         let v = store.get("t1", "hello").unwrap();
         assert_eq!(v, Some("world1".into()));
 
+        // contains
+        assert!(store.contains("t1", "hello").unwrap());
+        assert!(!store.contains("t1", "hello1").unwrap());
+        assert!(!store.contains("t2", "hello").unwrap());
+
         assert_eq!(None, store.get("t1", "hello1").unwrap());
         // assert_eq!(Ok(None), store.get("t2", "hello1"));
         assert!(store.get("t2", "hello1").unwrap().is_none());
@@ -54,4 +456,404 @@ mod tests {
         assert_eq!(None, store.del("t1", "hello1").unwrap());
         assert_eq!(None, store.del("t2", "hello").unwrap());
     }
+
+    fn test_get_all(store: impl Storage) {
+        store.set("t2", "k1".into(), "v1".into()).unwrap();
+        store.set("t2", "k2".into(), "v2".into()).unwrap();
+        // 其它 table 里的数据不应该被返回
+        store.set("t22", "k3".into(), "v3".into()).unwrap();
+        let mut data = store.get_all("t2").unwrap();
+        data.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(
+            data,
+            vec![
+                Kvpair::new("k1", "v1".into()),
+                Kvpair::new("k2", "v2".into())
+            ]
+        );
+        assert!(store.get_all("t3").unwrap().is_empty());
+    }
+
+    fn test_get_iter(store: impl Storage) {
+        store.set("t2", "k1".into(), "v1".into()).unwrap();
+        store.set("t2", "k2".into(), "v2".into()).unwrap();
+        store.set("t22", "k3".into(), "v3".into()).unwrap();
+        let mut data: Vec<_> = store
+            .get_iter("t2")
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        data.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(
+            data,
+            vec![
+                Kvpair::new("k1", "v1".into()),
+                Kvpair::new("k2", "v2".into())
+            ]
+        );
+    }
+
+    fn test_scan(store: impl Storage) {
+        for i in 0..7 {
+            store.set("t3", format!("user:{}", i), i.into()).unwrap();
+        }
+        store.set("t3", "admin:1".into(), "a1".into()).unwrap();
+        store.set("t33", "user:9".into(), 9.into()).unwrap();
+
+        let mut cursor = String::new();
+        let mut pages = vec![];
+        loop {
+            let (pairs, next) = store.scan("t3", "user:", &cursor, 3).unwrap();
+            pages.push(pairs);
+            match next {
+                Some(next) => cursor = next,
+                None => break,
+            }
+        }
+        assert_eq!(pages.len(), 3);
+        assert_eq!(
+            pages.iter().map(|p| p.len()).collect::<Vec<_>>(),
+            vec![3, 3, 1]
+        );
+        let keys: Vec<_> = pages.into_iter().flatten().map(|p| p.key).collect();
+        let expected: Vec<_> = (0..7).map(|i| format!("user:{}", i)).collect();
+        assert_eq!(keys, expected);
+
+        // 正好取完时不应该再返回 cursor
+        let (pairs, next) = store.scan("t3", "", "", 8).unwrap();
+        assert_eq!(pairs.len(), 8);
+        assert_eq!(pairs[0], Kvpair::new("admin:1", "a1".into()));
+        assert!(next.is_none());
+
+        let (pairs, next) = store.scan("t3", "guest:", "", 3).unwrap();
+        assert!(pairs.is_empty());
+        assert!(next.is_none());
+    }
+
+    fn test_ttl(store: impl Storage) {
+        assert!(!store.expire("t4", "k1", 1000).unwrap());
+        store.set("t4", "k1".into(), "v1".into()).unwrap();
+        assert_eq!(store.ttl("t4", "k1").unwrap(), None);
+
+        assert!(store.expire("t4", "k1", 10_000).unwrap());
+        let ttl = store.ttl("t4", "k1").unwrap().unwrap();
+        assert!(ttl > 9_000 && ttl <= 10_000);
+        assert!(store.persist("t4", "k1").unwrap());
+        assert!(!store.persist("t4", "k1").unwrap());
+        assert_eq!(store.ttl("t4", "k1").unwrap(), None);
+
+        // 重新 set 会清除过期时间
+        store.expire("t4", "k1", 10_000).unwrap();
+        store.set("t4", "k1".into(), "v2".into()).unwrap();
+        assert_eq!(store.ttl("t4", "k1").unwrap(), None);
+
+        // ttl 特别大时不会溢出，相当于永不过期
+        assert!(store.expire("t4", "k1", u64::MAX).unwrap());
+        assert_eq!(store.get("t4", "k1").unwrap(), Some("v2".into()));
+        assert!(store.ttl("t4", "k1").unwrap().unwrap() > 10_000);
+        assert!(store.reap_expired().unwrap().is_empty());
+
+        // 过期的 key 不能再被读到
+        store.expire("t4", "k1", 0).unwrap();
+        assert_eq!(store.get("t4", "k1").unwrap(), None);
+        assert!(!store.contains("t4", "k1").unwrap());
+        assert!(store.get_all("t4").unwrap().is_empty());
+        assert_eq!(store.get_iter("t4").unwrap().count(), 0);
+        assert!(store.scan("t4", "", "", 10).unwrap().0.is_empty());
+        assert!(!store.expire("t4", "k1", 1000).unwrap());
+        assert!(!store.persist("t4", "k1").unwrap());
+        assert_eq!(store.set("t4", "k1".into(), "v3".into()).unwrap(), None);
+        assert_eq!(store.get("t4", "k1").unwrap(), Some("v3".into()));
+    }
+
+    fn test_reap_expired(store: impl Storage) {
+        store.set("t5", "k1".into(), "v1".into()).unwrap();
+        store.set("t5", "k2".into(), "v2".into()).unwrap();
+        store.set("t5", "k3".into(), "v3".into()).unwrap();
+        store.expire("t5", "k1", 0).unwrap();
+        store.expire("t5", "k2", 60_000).unwrap();
+        // 过期之后又被重新 set 的 key 不应该被清理
+        store.expire("t5", "k3", 0).unwrap();
+        store.set("t5", "k3".into(), "v4".into()).unwrap();
+
+        assert_eq!(
+            store.reap_expired().unwrap(),
+            vec![("t5".to_string(), Kvpair::new("k1", "v1".into()))]
+        );
+        assert!(store.reap_expired().unwrap().is_empty());
+        let mut keys: Vec<_> = store
+            .get_iter("t5")
+            .unwrap()
+            .map(|p| p.unwrap().key)
+            .collect();
+        keys.sort();
+        assert_eq!(keys, vec!["k2", "k3"]);
+    }
+
+    fn test_incr(store: impl Storage) {
+        assert_eq!(store.incr("t6", "k1", 5).unwrap(), 5);
+        assert_eq!(store.incr("t6", "k1", -2).unwrap(), 3);
+        assert_eq!(store.get("t6", "k1").unwrap(), Some(3.into()));
+
+        assert_eq!(store.incr_float("t6", "k1", 0.5).unwrap(), 3.5);
+        assert_eq!(store.incr_float("t6", "k2", 1.5).unwrap(), 1.5);
+        assert_eq!(store.get("t6", "k2").unwrap(), Some(1.5.into()));
+        // 浮点数不能再用整数的方式加
+        assert!(matches!(
+            store.incr("t6", "k2", 1),
+            Err(KvError::ConvertError(_, _))
+        ));
+
+        store.set("t6", "k3".into(), "hello".into()).unwrap();
+        assert!(matches!(
+            store.incr("t6", "k3", 1),
+            Err(KvError::ConvertError(_, _))
+        ));
+        assert_eq!(store.get("t6", "k3").unwrap(), Some("hello".into()));
+
+        store.set("t6", "k4".into(), i64::MAX.into()).unwrap();
+        assert!(store.incr("t6", "k4", 1).is_err());
+
+        // 已经过期的 key 从 0 开始，而且不再带有过期时间
+        store.set("t6", "k5".into(), 10.into()).unwrap();
+        store.expire("t6", "k5", 0).unwrap();
+        assert_eq!(store.incr("t6", "k5", 1).unwrap(), 1);
+        assert_eq!(store.ttl("t6", "k5").unwrap(), None);
+        assert_eq!(store.get("t6", "k5").unwrap(), Some(1.into()));
+        // 过期索引也跟着清理了，reaper 不会把新的值删掉
+        assert!(store.reap_expired().unwrap().is_empty());
+        assert_eq!(store.get("t6", "k5").unwrap(), Some(1.into()));
+
+        // 没有过期的 key 保留原来的过期时间
+        store.expire("t6", "k5", 10_000).unwrap();
+        assert_eq!(store.incr("t6", "k5", 1).unwrap(), 2);
+        assert!(store.ttl("t6", "k5").unwrap().is_some());
+    }
+
+    fn test_concurrent_incr(store: impl Storage + Sync) {
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        store.incr("t7", "counter", 1).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(store.get("t7", "counter").unwrap(), Some(800.into()));
+    }
+
+    fn test_set_if(store: impl Storage) {
+        let set_if = |v: &str, condition, expected: Option<Value>| {
+            store.set_if("t8", "k1".into(), v.into(), condition, expected.as_ref(), 0)
+        };
+        let is_failed =
+            |r: Result<Option<Value>, KvError>| matches!(r, Err(KvError::PreconditionFailed(_)));
+
+        assert!(is_failed(set_if("v1", SetCondition::IfPresent, None)));
+        assert!(is_failed(set_if(
+            "v1",
+            SetCondition::IfEqual,
+            Some("v0".into())
+        )));
+        assert_eq!(set_if("v1", SetCondition::IfAbsent, None).unwrap(), None);
+        assert!(is_failed(set_if("v2", SetCondition::IfAbsent, None)));
+        assert_eq!(
+            set_if("v2", SetCondition::IfPresent, None).unwrap(),
+            Some("v1".into())
+        );
+        assert!(is_failed(set_if(
+            "v3",
+            SetCondition::IfEqual,
+            Some("v1".into())
+        )));
+        assert_eq!(
+            set_if("v3", SetCondition::IfEqual, Some("v2".into())).unwrap(),
+            Some("v2".into())
+        );
+        assert_eq!(store.get("t8", "k1").unwrap(), Some("v3".into()));
+
+        // 已经过期的 key 视为不存在
+        store.expire("t8", "k1", 0).unwrap();
+        assert!(is_failed(set_if("v4", SetCondition::IfPresent, None)));
+        assert_eq!(set_if("v4", SetCondition::IfAbsent, None).unwrap(), None);
+        assert_eq!(store.ttl("t8", "k1").unwrap(), None);
+
+        // 带 ttl 的写入同时设置过期时间，不带 ttl 的写入会清掉它
+        let r = store.set_if(
+            "t8",
+            "k1".into(),
+            "v5".into(),
+            SetCondition::Always,
+            None,
+            10_000,
+        );
+        assert_eq!(r.unwrap(), Some("v4".into()));
+        let ttl = store.ttl("t8", "k1").unwrap().unwrap();
+        assert!(ttl > 9_000 && ttl <= 10_000);
+        set_if("v6", SetCondition::IfPresent, None).unwrap();
+        assert_eq!(store.ttl("t8", "k1").unwrap(), None);
+        // 条件不满足时不会留下过期时间
+        let r = store.set_if(
+            "t8",
+            "k1".into(),
+            "v7".into(),
+            SetCondition::IfAbsent,
+            None,
+            10,
+        );
+        assert!(is_failed(r));
+        assert_eq!(store.ttl("t8", "k1").unwrap(), None);
+        assert!(store.reap_expired().unwrap().is_empty());
+    }
+
+    fn test_concurrent_set_if(store: impl Storage + Sync) {
+        // 多个线程同时抢同一个 key，只能有一个成功
+        let winners = std::sync::atomic::AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for i in 0..8 {
+                let store = &store;
+                let winners = &winners;
+                s.spawn(move || {
+                    let r = store.set_if(
+                        "t9",
+                        "leader".into(),
+                        i.into(),
+                        SetCondition::IfAbsent,
+                        None,
+                        0,
+                    );
+                    if r.is_ok() {
+                        winners.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    }
+                });
+            }
+        });
+        assert_eq!(winners.into_inner(), 1);
+    }
+
+    fn set_op(table: &str, key: &str, value: Value) -> TxnOp {
+        TxnOp::Set {
+            table: table.into(),
+            key: key.into(),
+            value,
+        }
+    }
+
+    fn incr_op(table: &str, key: &str, delta: i64) -> TxnOp {
+        TxnOp::Incr {
+            table: table.into(),
+            key: key.into(),
+            delta,
+        }
+    }
+
+    fn values(results: &[TxnResult]) -> Vec<Value> {
+        results.iter().map(|r| r.value.clone()).collect()
+    }
+
+    fn test_txn(store: impl Storage) {
+        store.set("acct", "a".into(), 100.into()).unwrap();
+        store.set("acct", "b".into(), 0.into()).unwrap();
+
+        // 条件成立，跨两个 table 执行 success
+        let compares = [Compare::new(
+            "acct",
+            "a",
+            CompareOp::Equal,
+            Some(100.into()),
+        )];
+        let success = [
+            incr_op("acct", "a", -30),
+            incr_op("acct", "b", 30),
+            set_op("log", "last", "a->b".into()),
+        ];
+        let failure = [set_op("log", "last", "failed".into())];
+        let (ok, results) = store.txn(&compares, &success, &failure).unwrap();
+        assert!(ok);
+        assert_eq!(
+            values(&results),
+            vec![70.into(), 30.into(), Value::default()]
+        );
+        // prev 是每个操作之前的值
+        let prev: Vec<_> = results.into_iter().map(|r| r.prev).collect();
+        assert_eq!(prev, vec![Some(100.into()), Some(0.into()), None]);
+        assert_eq!(store.get("log", "last").unwrap(), Some("a->b".into()));
+
+        // 条件不成立，执行 failure
+        let (ok, results) = store.txn(&compares, &success, &failure).unwrap();
+        assert!(!ok);
+        assert_eq!(values(&results), vec!["a->b".into()]);
+        assert_eq!(store.get("acct", "a").unwrap(), Some(70.into()));
+        assert_eq!(store.get("log", "last").unwrap(), Some("failed".into()));
+
+        // 事务里后面的操作能看到前面的修改
+        let ops = [
+            set_op("t10", "k1", 1.into()),
+            incr_op("t10", "k1", 1),
+            TxnOp::Get {
+                table: "t10".into(),
+                key: "k1".into(),
+            },
+            TxnOp::Del {
+                table: "t10".into(),
+                key: "k1".into(),
+            },
+        ];
+        let (ok, results) = store.txn(&[], &ops, &[]).unwrap();
+        assert!(ok);
+        assert_eq!(
+            values(&results),
+            vec![Value::default(), 2.into(), 2.into(), 2.into()]
+        );
+        assert_eq!(results[1].prev, Some(1.into()));
+        assert_eq!(store.get("t10", "k1").unwrap(), None);
+
+        // 中途出错时所有的修改都不生效
+        store.set("t10", "s".into(), "hello".into()).unwrap();
+        let ops = [set_op("t10", "k2", 1.into()), incr_op("t10", "s", 1)];
+        assert!(matches!(
+            store.txn(&[], &ops, &[]),
+            Err(KvError::ConvertError(_, _))
+        ));
+        assert_eq!(store.get("t10", "k2").unwrap(), None);
+
+        // 过期的 key 视为不存在
+        store.expire("acct", "b", 0).unwrap();
+        let compares = [Compare::new("acct", "b", CompareOp::NotExists, None)];
+        let (ok, results) = store
+            .txn(&compares, &[incr_op("acct", "b", 1)], &[])
+            .unwrap();
+        assert!(ok);
+        assert_eq!(results, vec![TxnResult::new(1.into(), None)]);
+        assert_eq!(store.ttl("acct", "b").unwrap(), None);
+    }
+
+    fn test_concurrent_txn(store: impl Storage + Sync) {
+        store.set("bank", "a".into(), 1000.into()).unwrap();
+        store.set("other", "b".into(), 1000.into()).unwrap();
+        // 两个方向相反的转账并发执行，总额保持不变，也不会死锁
+        std::thread::scope(|s| {
+            for i in 0..8 {
+                let store = &store;
+                s.spawn(move || {
+                    let (from, to) = if i % 2 == 0 {
+                        (("bank", "a"), ("other", "b"))
+                    } else {
+                        (("other", "b"), ("bank", "a"))
+                    };
+                    for _ in 0..50 {
+                        let ops = [incr_op(from.0, from.1, -1), incr_op(to.0, to.1, 1)];
+                        store.txn(&[], &ops, &[]).unwrap();
+                    }
+                });
+            }
+        });
+        let a = store.get("bank", "a").unwrap().unwrap();
+        let b = store.get("other", "b").unwrap().unwrap();
+        assert_eq!(
+            i64::try_from(&a).unwrap() + i64::try_from(&b).unwrap(),
+            2000
+        );
+    }
 }
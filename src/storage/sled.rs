@@ -1,24 +1,73 @@
-use crate::{KvError, Storage, Value};
-use sled::Db;
-use std::{convert::TryInto, path::Path, str};
+use crate::storage::{
+    TxnOp, TxnResult, add_float, add_int, check_condition, compare_holds, now_ms,
+};
+use crate::{Compare, KvError, Kvpair, SetCondition, Storage, Value};
+use sled::transaction::{
+    ConflictableTransactionError, TransactionError, Transactional, TransactionalTree,
+};
+use sled::{Db, IVec, Tree};
+use std::{convert::TryInto, ops::Bound, path::Path, str};
 
-pub struct SledDb(Db);
+pub struct SledDb {
+    db: Db,
+    // full_key => 过期时间（unix 毫秒，big endian）
+    ttl: Tree,
+    // 过期时间 + full_key => []，后台清理时按过期时间顺序遍历
+    expiry: Tree,
+}
+
+type TxResult<T> = Result<T, ConflictableTransactionError<KvError>>;
 
 impl SledDb {
     pub fn new(path: impl AsRef<Path>) -> Self {
-        Self(sled::open(path).unwrap())
+        Self::try_new(path).unwrap()
+    }
+    /// 打开失败（比如文件锁还被占用）时返回错误，而不是 panic
+    pub fn try_new(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        let db = sled::open(path)?;
+        let ttl = db.open_tree("__ttl")?;
+        let expiry = db.open_tree("__expiry")?;
+        Ok(Self { db, ttl, expiry })
     }
     fn get_full_key(table_name: &str, key: &str) -> String {
         format!("{}:{}", table_name, key)
     }
+    fn get_table_prefix(table_name: &str) -> String {
+        format!("{}:", table_name)
+    }
+    fn get_expiry_key(expire_at: u64, full_key: &str) -> Vec<u8> {
+        let mut k = Vec::with_capacity(8 + full_key.len());
+        k.extend_from_slice(&expire_at.to_be_bytes());
+        k.extend_from_slice(full_key.as_bytes());
+        k
+    }
+    fn is_expired(&self, full_key: &[u8], now: u64) -> Result<bool, KvError> {
+        Ok(self
+            .ttl
+            .get(full_key)?
+            .is_some_and(|t| decode_expire_at(&t) <= now))
+    }
+    fn transaction<T>(
+        &self,
+        f: impl Fn(&TransactionalTree, &TransactionalTree, &TransactionalTree) -> TxResult<T>,
+    ) -> Result<T, KvError> {
+        (&*self.db, &self.ttl, &self.expiry)
+            .transaction(|(db, ttl, expiry)| f(db, ttl, expiry))
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => e.into(),
+            })
+    }
 }
 
 impl Storage for SledDb {
     fn get(&self, table_name: &str, key: &str) -> anyhow::Result<Option<Value>, KvError> {
         let name = SledDb::get_full_key(table_name, key);
-        let res = self.0.get(name)?.map(|ivec| ivec.as_ref().try_into());
-        let flipped = flip(res);
-        flipped
+        let res = self.db.get(&name)?.map(|ivec| ivec.as_ref().try_into());
+        match flip(res)? {
+            Some(_) if self.is_expired(name.as_bytes(), now_ms())? => Ok(None),
+            v => Ok(v),
+        }
     }
     fn set(
         &self,
@@ -28,20 +77,368 @@ impl Storage for SledDb {
     ) -> anyhow::Result<Option<Value>, KvError> {
         let full_key = SledDb::get_full_key(table_name, &key);
         let data: Vec<u8> = value.try_into()?;
-        let res = self
-            .0
-            .insert(full_key, data)?
-            .map(|ivec| ivec.as_ref().try_into());
-        let flipped = flip(res);
-        flipped
+        let now = now_ms();
+        self.transaction(|db, ttl, expiry| {
+            let old = db.insert(full_key.as_bytes(), data.as_slice())?;
+            live_value(&full_key, old, ttl, expiry, now)
+        })
     }
     fn del(&self, table_name: &str, key: &str) -> anyhow::Result<Option<Value>, KvError> {
+        let full_key = SledDb::get_full_key(table_name, key);
+        let now = now_ms();
+        self.transaction(|db, ttl, expiry| {
+            let old = db.remove(full_key.as_bytes())?;
+            live_value(&full_key, old, ttl, expiry, now)
+        })
+    }
+    fn set_if(
+        &self,
+        table_name: &str,
+        key: String,
+        value: Value,
+        condition: SetCondition,
+        expected: Option<&Value>,
+        ttl_ms: u64,
+    ) -> anyhow::Result<Option<Value>, KvError> {
         let full_key = SledDb::get_full_key(table_name, &key);
-        let res = self
-            .0
-            .remove(full_key)?
-            .map(|ivec| ivec.as_ref().try_into());
-        flip(res)
+        let data: Vec<u8> = value.try_into()?;
+        let now = now_ms();
+        self.transaction(|db, ttl, expiry| {
+            let expired = ttl
+                .get(full_key.as_bytes())?
+                .is_some_and(|t| decode_expire_at(&t) <= now);
+            let current = match db.get(full_key.as_bytes())? {
+                Some(v) if !expired => Some(decode_value(&v)?),
+                _ => None,
+            };
+            check_condition(&key, condition, current.as_ref(), expected)
+                .map_err(ConflictableTransactionError::Abort)?;
+            let old = db.insert(full_key.as_bytes(), data.as_slice())?;
+            let old = live_value(&full_key, old, ttl, expiry, now)?;
+            if ttl_ms > 0 {
+                let expire_at = now.saturating_add(ttl_ms);
+                ttl.insert(full_key.as_bytes(), &expire_at.to_be_bytes())?;
+                expiry.insert(SledDb::get_expiry_key(expire_at, &full_key), &[])?;
+            }
+            Ok(old)
+        })
+    }
+    fn contains(&self, table_name: &str, key: &str) -> anyhow::Result<bool, KvError> {
+        let full_key = SledDb::get_full_key(table_name, key);
+        Ok(self.db.contains_key(&full_key)? && !self.is_expired(full_key.as_bytes(), now_ms())?)
+    }
+    fn get_all(&self, table_name: &str) -> anyhow::Result<Vec<Kvpair>, KvError> {
+        let prefix = SledDb::get_table_prefix(table_name);
+        let now = now_ms();
+        let mut pairs = vec![];
+        for item in self.db.scan_prefix(&prefix) {
+            let (k, v) = item?;
+            if !self.is_expired(&k, now)? {
+                pairs.push(to_kvpair(prefix.len(), (k, v))?);
+            }
+        }
+        Ok(pairs)
+    }
+    fn get_iter(
+        &self,
+        table_name: &str,
+    ) -> anyhow::Result<Box<dyn Iterator<Item = Result<Kvpair, KvError>>>, KvError> {
+        // sled 的 prefix scan 是惰性的，不会把整个 table 读进内存
+        let prefix = SledDb::get_table_prefix(table_name);
+        let ttl = self.ttl.clone();
+        let now = now_ms();
+        let iter = self.db.scan_prefix(&prefix).filter_map(move |item| {
+            let (k, v) = match item {
+                Ok(kv) => kv,
+                Err(e) => return Some(Err(e.into())),
+            };
+            match ttl.get(&k) {
+                Ok(Some(t)) if decode_expire_at(&t) <= now => None,
+                Ok(_) => Some(to_kvpair(prefix.len(), (k, v))),
+                Err(e) => Some(Err(e.into())),
+            }
+        });
+        Ok(Box::new(iter))
+    }
+    fn scan(
+        &self,
+        table_name: &str,
+        prefix: &str,
+        cursor: &str,
+        limit: usize,
+    ) -> anyhow::Result<(Vec<Kvpair>, Option<String>), KvError> {
+        let table_prefix = SledDb::get_table_prefix(table_name);
+        let full_prefix = SledDb::get_full_key(table_name, prefix);
+        let now = now_ms();
+        // sled 的 key 本身有序，直接从 cursor 之后开始做 range 遍历
+        let start = if cursor.is_empty() || cursor < prefix {
+            Bound::Included(full_prefix.clone())
+        } else {
+            Bound::Excluded(SledDb::get_full_key(table_name, cursor))
+        };
+        let mut pairs = Vec::with_capacity(limit);
+        let mut has_more = false;
+        for item in self.db.range::<String, _>((start, Bound::Unbounded)) {
+            let (k, v) = item?;
+            if !k.starts_with(full_prefix.as_bytes()) {
+                break;
+            }
+            if self.is_expired(&k, now)? {
+                continue;
+            }
+            if pairs.len() == limit {
+                has_more = true;
+                break;
+            }
+            pairs.push(to_kvpair(table_prefix.len(), (k, v))?);
+        }
+        let next = match pairs.last() {
+            Some(pair) if has_more => Some(pair.key.clone()),
+            _ => None,
+        };
+        Ok((pairs, next))
+    }
+    fn expire(&self, table_name: &str, key: &str, ttl_ms: u64) -> anyhow::Result<bool, KvError> {
+        let full_key = SledDb::get_full_key(table_name, key);
+        let now = now_ms();
+        let expire_at = now.saturating_add(ttl_ms);
+        self.transaction(|db, ttl, expiry| {
+            if db.get(full_key.as_bytes())?.is_none() {
+                return Ok(false);
+            }
+            if let Some(old) = ttl.get(full_key.as_bytes())? {
+                let old = decode_expire_at(&old);
+                if old <= now {
+                    return Ok(false);
+                }
+                expiry.remove(SledDb::get_expiry_key(old, &full_key))?;
+            }
+            ttl.insert(full_key.as_bytes(), &expire_at.to_be_bytes())?;
+            expiry.insert(SledDb::get_expiry_key(expire_at, &full_key), &[])?;
+            Ok(true)
+        })
+    }
+    fn ttl(&self, table_name: &str, key: &str) -> anyhow::Result<Option<u64>, KvError> {
+        let full_key = SledDb::get_full_key(table_name, key);
+        let now = now_ms();
+        let ttl = self
+            .ttl
+            .get(&full_key)?
+            .map(|t| decode_expire_at(&t))
+            .filter(|t| *t > now)
+            .map(|t| t - now);
+        Ok(ttl)
+    }
+    fn persist(&self, table_name: &str, key: &str) -> anyhow::Result<bool, KvError> {
+        let full_key = SledDb::get_full_key(table_name, key);
+        let now = now_ms();
+        self.transaction(|_db, ttl, expiry| match ttl.get(full_key.as_bytes())? {
+            Some(t) if decode_expire_at(&t) > now => {
+                ttl.remove(full_key.as_bytes())?;
+                expiry.remove(SledDb::get_expiry_key(decode_expire_at(&t), &full_key))?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        })
+    }
+    fn reap_expired(&self) -> anyhow::Result<Vec<(String, Kvpair)>, KvError> {
+        let now = now_ms();
+        let end = (now + 1).to_be_bytes();
+        let mut reaped = vec![];
+        // expiry 的 key 以过期时间开头，只需要遍历已经到期的那一段
+        for item in self.expiry.range(..&end[..]) {
+            let (index_key, _) = item?;
+            let expire_at = decode_expire_at(&index_key);
+            let full_key = index_key[8..].to_vec();
+            let removed = self.transaction(|db, ttl, expiry| {
+                expiry.remove(&index_key)?;
+                // 索引可能已经过时，只删除过期时间一致的 key
+                match ttl.get(&full_key)? {
+                    Some(t) if decode_expire_at(&t) == expire_at => {
+                        ttl.remove(full_key.as_slice())?;
+                        db.remove(full_key.as_slice())?
+                            .map(|v| decode_value(&v))
+                            .transpose()
+                    }
+                    _ => Ok(None),
+                }
+            })?;
+            if let Some(value) = removed {
+                reaped.push(split_full_key(&full_key, value)?);
+            }
+        }
+        Ok(reaped)
+    }
+
+    fn update(
+        &self,
+        table_name: &str,
+        key: &str,
+        f: &dyn Fn(Option<&Value>) -> Result<Value, KvError>,
+    ) -> anyhow::Result<Value, KvError> {
+        let full_key = SledDb::get_full_key(table_name, key);
+        let now = now_ms();
+        // 过期检查、清理和写入都在同一个事务里，冲突时 sled 会重试整个闭包
+        self.transaction(|db, ttl, expiry| {
+            let mut old = db.get(full_key.as_bytes())?;
+            if let Some(t) = ttl.get(full_key.as_bytes())? {
+                let expire_at = decode_expire_at(&t);
+                // 已经过期的 key 先清理掉，保证新的值不会带着旧的过期时间
+                if expire_at <= now {
+                    ttl.remove(full_key.as_bytes())?;
+                    expiry.remove(SledDb::get_expiry_key(expire_at, &full_key))?;
+                    old = None;
+                }
+            }
+            let old = old.map(|v| decode_value(&v)).transpose()?;
+            let value = f(old.as_ref()).map_err(ConflictableTransactionError::Abort)?;
+            db.insert(full_key.as_bytes(), encode_value(&value)?)?;
+            Ok(value)
+        })
+    }
+
+    fn txn(
+        &self,
+        compares: &[Compare],
+        success: &[TxnOp],
+        failure: &[TxnOp],
+    ) -> anyhow::Result<(bool, Vec<TxnResult>), KvError> {
+        let now = now_ms();
+        // sled 的事务里读能看到之前的写，Abort 时所有的修改都会被丢弃
+        self.transaction(|db, ttl, expiry| {
+            let mut succeeded = true;
+            for c in compares {
+                let full_key = SledDb::get_full_key(&c.table, &c.key);
+                if !compare_holds(c, tx_get_live(db, ttl, &full_key, now)?.as_ref()) {
+                    succeeded = false;
+                    break;
+                }
+            }
+            let ops = if succeeded { success } else { failure };
+            let mut results = Vec::with_capacity(ops.len());
+            for op in ops {
+                let (table_name, key) = op.table_key();
+                let full_key = SledDb::get_full_key(table_name, key);
+                let result = match op {
+                    TxnOp::Get { .. } => {
+                        let current = tx_get_live(db, ttl, &full_key, now)?;
+                        TxnResult::new(current.clone().unwrap_or_default(), current)
+                    }
+                    TxnOp::Set { value, .. } => {
+                        let old = db.insert(full_key.as_bytes(), encode_value(value)?)?;
+                        let old = live_value(&full_key, old, ttl, expiry, now)?;
+                        TxnResult::new(old.clone().unwrap_or_default(), old)
+                    }
+                    TxnOp::Del { .. } => {
+                        let old = db.remove(full_key.as_bytes())?;
+                        let old = live_value(&full_key, old, ttl, expiry, now)?;
+                        TxnResult::new(old.clone().unwrap_or_default(), old)
+                    }
+                    TxnOp::Incr { delta, .. } => {
+                        let current = tx_get_live(db, ttl, &full_key, now)?;
+                        let value = add_int(current.as_ref(), *delta)
+                            .map_err(ConflictableTransactionError::Abort)?;
+                        tx_update(db, ttl, expiry, &full_key, current.clone(), &value, now)?;
+                        TxnResult::new(value, current)
+                    }
+                    TxnOp::IncrFloat { delta, .. } => {
+                        let current = tx_get_live(db, ttl, &full_key, now)?;
+                        let value = add_float(current.as_ref(), *delta)
+                            .map_err(ConflictableTransactionError::Abort)?;
+                        tx_update(db, ttl, expiry, &full_key, current.clone(), &value, now)?;
+                        TxnResult::new(value, current)
+                    }
+                };
+                results.push(result);
+            }
+            Ok((succeeded, results))
+        })
+    }
+}
+
+// 在 transaction 里读出 key 当前没有过期的值
+fn tx_get_live(
+    db: &TransactionalTree,
+    ttl: &TransactionalTree,
+    full_key: &str,
+    now: u64,
+) -> TxResult<Option<Value>> {
+    let expired = ttl
+        .get(full_key.as_bytes())?
+        .is_some_and(|t| decode_expire_at(&t) <= now);
+    match db.get(full_key.as_bytes())? {
+        Some(v) if !expired => Ok(Some(decode_value(&v)?)),
+        _ => Ok(None),
+    }
+}
+
+// 在 transaction 里用新的值替换 key 当前的值；key 原来不存在（或者已经过期）时清除过期时间
+fn tx_update(
+    db: &TransactionalTree,
+    ttl: &TransactionalTree,
+    expiry: &TransactionalTree,
+    full_key: &str,
+    current: Option<Value>,
+    value: &Value,
+    now: u64,
+) -> TxResult<()> {
+    let old = db.insert(full_key.as_bytes(), encode_value(value)?)?;
+    if current.is_none() {
+        live_value(full_key, old, ttl, expiry, now)?;
+    }
+    Ok(())
+}
+
+// 在 transaction 里把 key 的旧值换出来之后调用：清理它的过期时间，已经过期的值视为不存在
+fn live_value(
+    full_key: &str,
+    old: Option<IVec>,
+    ttl: &TransactionalTree,
+    expiry: &TransactionalTree,
+    now: u64,
+) -> TxResult<Option<Value>> {
+    let mut expired = false;
+    if let Some(t) = ttl.remove(full_key.as_bytes())? {
+        let expire_at = decode_expire_at(&t);
+        expiry.remove(SledDb::get_expiry_key(expire_at, full_key))?;
+        expired = expire_at <= now;
+    }
+    match old {
+        Some(v) if !expired => Ok(Some(decode_value(&v)?)),
+        _ => Ok(None),
+    }
+}
+
+fn decode_value(data: &[u8]) -> TxResult<Value> {
+    data.try_into().map_err(ConflictableTransactionError::Abort)
+}
+
+fn encode_value(value: &Value) -> TxResult<Vec<u8>> {
+    value
+        .clone()
+        .try_into()
+        .map_err(ConflictableTransactionError::Abort)
+}
+
+fn decode_expire_at(data: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&data[..8]);
+    u64::from_be_bytes(buf)
+}
+
+// sled 里的 key 是 "table:key"，还原成 Kvpair 时去掉 table 前缀
+fn to_kvpair(prefix_len: usize, (k, v): (IVec, IVec)) -> Result<Kvpair, KvError> {
+    let key = String::from_utf8_lossy(&k[prefix_len..]).into_owned();
+    let value: Value = v.as_ref().try_into()?;
+    Ok(Kvpair::new(key, value))
+}
+
+// 把 full key 拆回 (table, key => value)；table 名里不应该有 ':'，按第一个 ':' 拆分
+fn split_full_key(full_key: &[u8], value: Value) -> Result<(String, Kvpair), KvError> {
+    let full_key = String::from_utf8_lossy(full_key);
+    match full_key.split_once(':') {
+        Some((table, key)) => Ok((table.to_string(), Kvpair::new(key, value))),
+        None => Err(KvError::Internal(format!("invalid full key {}", full_key))),
     }
 }
 